@@ -0,0 +1,182 @@
+//! Inspect and replay the dead letters of a topic.
+//!
+//! ```text
+//! dead-letters <brokers> <dead-letter-topic> list
+//! dead-letters <brokers> <dead-letter-topic> replay <topic> <partition> <offset>
+//! dead-letters <brokers> <dead-letter-topic> replay-all <journal>
+//! ```
+//!
+//! Brokers are separated by commas. Replayed messages are published to their original topic
+//! again; they stay in the dead-letter topic until it expires them. `replay-all` records each
+//! replayed message in the journal file as `topic:partition@offset` as soon as it's published,
+//! and skips the messages the journal already lists, so running it again, e.g. after it was
+//! interrupted or once new dead letters arrived, doesn't publish a message twice.
+
+use event_bus_kafka::event::dead_letter::{DeadLetter, KafkaDeadLetterQueue};
+use event_sourcing::Error;
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+
+fn main() -> Result<(), Error> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (brokers, topic, command) = match args.as_slice() {
+        [brokers, topic, command, ..] => (brokers, topic, command.as_str()),
+        _ => return Err(usage()),
+    };
+    let queue = KafkaDeadLetterQueue::new(
+        brokers.split(',').map(String::from).collect(),
+        topic.as_str(),
+    )?;
+    let dead_letters = queue.read()?;
+    match (command, &args[3..]) {
+        ("list", []) => {
+            for dead_letter in &dead_letters {
+                println!("{}", serde_json::to_string(dead_letter)?);
+            }
+        }
+        ("replay", [topic, partition, offset]) => {
+            let (partition, offset): (i32, i64) = (partition.parse()?, offset.parse()?);
+            let dead_letter = dead_letters
+                .iter()
+                .find(|dead_letter| {
+                    &dead_letter.topic == topic
+                        && dead_letter.partition == partition
+                        && dead_letter.offset == offset
+                })
+                .ok_or(Error::from("No such dead letter"))?;
+            replay(&queue, dead_letter)?;
+        }
+        ("replay-all", [journal]) => {
+            let replayed = read_journal(Path::new(journal))?;
+            let mut journal = OpenOptions::new().create(true).append(true).open(journal)?;
+            for dead_letter in not_replayed(&dead_letters, &replayed) {
+                replay(&queue, dead_letter)?;
+                writeln!(journal, "{}", journal_entry(dead_letter))?;
+                journal.sync_data()?;
+            }
+        }
+        _ => return Err(usage()),
+    }
+    Ok(())
+}
+
+fn replay(queue: &KafkaDeadLetterQueue, dead_letter: &DeadLetter) -> Result<(), Error> {
+    queue.replay(dead_letter)?;
+    println!(
+        "Replayed {}:{}@{}",
+        dead_letter.topic, dead_letter.partition, dead_letter.offset
+    );
+    Ok(())
+}
+
+/// How a replayed message is listed in the journal.
+fn journal_entry(dead_letter: &DeadLetter) -> String {
+    format!(
+        "{}:{}@{}",
+        dead_letter.topic, dead_letter.partition, dead_letter.offset
+    )
+}
+
+/// The messages listed in the journal, which is empty until something was replayed.
+fn read_journal(path: &Path) -> Result<HashSet<String>, Error> {
+    if !path.exists() {
+        return Ok(HashSet::new());
+    }
+    let mut replayed = HashSet::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            replayed.insert(line.trim().to_string());
+        }
+    }
+    Ok(replayed)
+}
+
+/// The dead letters whose message the journal doesn't list, each only once, as a message can be
+/// set aside more than once.
+fn not_replayed<'a>(
+    dead_letters: &'a [DeadLetter],
+    replayed: &HashSet<String>,
+) -> Vec<&'a DeadLetter> {
+    let mut seen = HashSet::new();
+    dead_letters
+        .iter()
+        .filter(|dead_letter| {
+            let entry = journal_entry(dead_letter);
+            !replayed.contains(&entry) && seen.insert(entry)
+        })
+        .collect()
+}
+
+fn usage() -> Error {
+    Error::from(
+        "usage: dead-letters <brokers> <dead-letter-topic> \
+         (list | replay <topic> <partition> <offset> | replay-all <journal>)",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use event_bus_kafka::event::dead_letter::DeadLetterReason;
+
+    fn dead_letter(partition: i32, offset: i64) -> DeadLetter {
+        DeadLetter {
+            topic: String::from("events"),
+            partition,
+            offset,
+            key: vec![],
+            value: vec![],
+            reason: DeadLetterReason::Undelivered,
+            error: String::from("listener failed"),
+            aggregate_id: None,
+            sequence: None,
+            failed_at: Utc::now(),
+        }
+    }
+
+    fn offsets(dead_letters: Vec<&DeadLetter>) -> Vec<(i32, i64)> {
+        dead_letters
+            .into_iter()
+            .map(|dead_letter| (dead_letter.partition, dead_letter.offset))
+            .collect()
+    }
+
+    #[test]
+    fn messages_listed_in_the_journal_are_not_replayed() {
+        let dead_letters = [dead_letter(0, 1), dead_letter(0, 2), dead_letter(1, 1)];
+        let replayed = HashSet::from([String::from("events:0@1")]);
+        assert_eq!(
+            offsets(not_replayed(&dead_letters, &replayed)),
+            vec![(0, 2), (1, 1)]
+        );
+    }
+
+    #[test]
+    fn message_set_aside_twice_is_replayed_once() {
+        let dead_letters = [dead_letter(0, 1), dead_letter(0, 1)];
+        assert_eq!(
+            offsets(not_replayed(&dead_letters, &HashSet::new())),
+            vec![(0, 1)]
+        );
+    }
+
+    #[test]
+    fn journal_lists_what_was_replayed() {
+        let path = std::env::temp_dir().join(format!(
+            "dead-letters-journal-{}",
+            Utc::now().timestamp_nanos_opt().unwrap()
+        ));
+        assert!(read_journal(&path).unwrap().is_empty());
+        std::fs::write(&path, "events:0@1\n\nevents:1@7\n").unwrap();
+        let replayed = read_journal(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            replayed,
+            HashSet::from([String::from("events:0@1"), String::from("events:1@7")])
+        );
+    }
+}
@@ -1 +1,4 @@
+pub mod dead_letter;
+pub mod error;
 pub mod listener;
+pub mod publisher;
@@ -0,0 +1,275 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use event_sourcing::event::listener::DeliveryError;
+use event_sourcing::Error;
+use kafka::consumer::{Consumer, FetchOffset};
+use kafka::producer::{Producer, Record, RequiredAcks};
+use log::warn;
+use metrics::counter;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::event::error::{ErrorHandler, FailedMessage};
+
+/// Why a message was set aside.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeadLetterReason {
+    // The message couldn't be decoded into an event.
+    Undecodable,
+    // A listener kept failing to handle the event of the message.
+    Undelivered,
+}
+
+/// A message set aside so the container can move past it, with what went wrong. The original
+/// key and value are kept as they were, so the message can be replayed to its topic.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub topic: String,
+    pub partition: i32,
+    pub offset: i64,
+    #[serde(with = "base64_bytes")]
+    pub key: Vec<u8>,
+    #[serde(with = "base64_bytes")]
+    pub value: Vec<u8>,
+    pub reason: DeadLetterReason,
+    pub error: String,
+    pub aggregate_id: Option<String>,
+    pub sequence: Option<i64>,
+    pub failed_at: DateTime<Utc>,
+}
+
+impl DeadLetter {
+    fn new(message: &FailedMessage, reason: DeadLetterReason, error: String) -> Self {
+        Self {
+            topic: message.topic.clone(),
+            partition: message.partition,
+            offset: message.offset,
+            key: message.key.clone(),
+            value: message.value.clone(),
+            reason,
+            error,
+            aggregate_id: None,
+            sequence: None,
+            failed_at: Utc::now(),
+        }
+    }
+}
+
+/// Where dead letters are kept.
+#[async_trait::async_trait]
+pub trait DeadLetterStore: Send + Sync {
+    async fn store(&self, dead_letter: &DeadLetter) -> Result<(), Error>;
+}
+
+/// Sends the messages that fail to decode, and those whose listener kept failing once retried,
+/// to a [`DeadLetterStore`], so the container skips them instead of stopping. Should the store
+/// fail, the container stops without committing the message.
+pub struct DeadLetterErrorHandler<S: DeadLetterStore> {
+    store: S,
+}
+
+impl<S: DeadLetterStore> DeadLetterErrorHandler<S> {
+    pub fn new(store: S) -> Self {
+        Self { store }
+    }
+
+    async fn store(&self, dead_letter: DeadLetter) -> Result<(), Error> {
+        warn!(
+            "Sending message {}:{}@{} to the dead letters: {}",
+            dead_letter.topic, dead_letter.partition, dead_letter.offset, dead_letter.error
+        );
+        self.store.store(&dead_letter).await?;
+        counter!(
+            "kafka_dead_letters_total",
+            "topic" => dead_letter.topic,
+            "reason" => match dead_letter.reason {
+                DeadLetterReason::Undecodable => "undecodable",
+                DeadLetterReason::Undelivered => "undelivered",
+            }
+        )
+        .increment(1);
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl<S: DeadLetterStore> ErrorHandler for DeadLetterErrorHandler<S> {
+    async fn handle(&self, message: &FailedMessage, error: &Error) -> Result<(), Error> {
+        self.store(DeadLetter::new(
+            message,
+            DeadLetterReason::Undecodable,
+            error.to_string(),
+        ))
+        .await
+    }
+
+    async fn handle_undelivered(
+        &self,
+        message: &FailedMessage,
+        error: &DeliveryError,
+    ) -> Result<(), Error> {
+        self.store(DeadLetter {
+            aggregate_id: Some(error.aggregate_id.clone()),
+            sequence: Some(error.sequence),
+            ..DeadLetter::new(
+                message,
+                DeadLetterReason::Undelivered,
+                error.error.to_string(),
+            )
+        })
+        .await
+    }
+}
+
+/// Dead letters kept as JSON messages in a topic, keyed like the original messages.
+pub struct KafkaDeadLetterQueue {
+    brokers: Vec<String>,
+    topic: String,
+    producer: Arc<Mutex<Producer>>,
+}
+
+impl KafkaDeadLetterQueue {
+    pub fn new(brokers: Vec<String>, topic: impl Into<String>) -> Result<Self, Error> {
+        let producer = Producer::from_hosts(brokers.clone())
+            .with_ack_timeout(Duration::from_secs(1))
+            .with_required_acks(RequiredAcks::All)
+            .create()?;
+        Ok(Self {
+            brokers,
+            topic: topic.into(),
+            producer: Arc::new(Mutex::new(producer)),
+        })
+    }
+
+    /// Every dead letter in the topic, oldest first in each partition.
+    pub fn read(&self) -> Result<Vec<DeadLetter>, Error> {
+        let mut consumer = Consumer::from_hosts(self.brokers.clone())
+            .with_topic(self.topic.clone())
+            .with_fallback_offset(FetchOffset::Earliest)
+            .create()?;
+        let mut dead_letters = vec![];
+        loop {
+            let message_sets = consumer.poll()?;
+            if message_sets.is_empty() {
+                return Ok(dead_letters);
+            }
+            for message_set in message_sets.iter() {
+                for message in message_set.messages() {
+                    dead_letters.push(serde_json::from_slice(message.value)?);
+                }
+                consumer.consume_messageset(message_set)?;
+            }
+        }
+    }
+
+    /// Publish the original message of a dead letter to its topic again, e.g. once the
+    /// listener that failed to handle it has been fixed.
+    pub fn replay(&self, dead_letter: &DeadLetter) -> Result<(), Error> {
+        self.send(&Record::from_key_value(
+            &dead_letter.topic,
+            dead_letter.key.as_slice(),
+            dead_letter.value.as_slice(),
+        ))
+    }
+
+    fn send(&self, record: &Record<&[u8], &[u8]>) -> Result<(), Error> {
+        self.producer
+            .lock()
+            .map_err(|_| Error::from("Kafka producer is poisoned"))?
+            .send(record)?;
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl DeadLetterStore for KafkaDeadLetterQueue {
+    async fn store(&self, dead_letter: &DeadLetter) -> Result<(), Error> {
+        let key = dead_letter.key.clone();
+        let value = serde_json::to_vec(dead_letter)?;
+        let producer = self.producer.clone();
+        let topic = self.topic.clone();
+        tokio::task::spawn_blocking(move || {
+            producer
+                .lock()
+                .map_err(|_| Error::from("Kafka producer is poisoned"))?
+                .send(&Record::from_key_value(&topic, key, value))?;
+            Ok::<_, Error>(())
+        })
+        .await?
+    }
+}
+
+/// Bytes as a base64 string, as JSON has no type for them.
+mod base64_bytes {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        STANDARD
+            .decode(String::deserialize(deserializer)?)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use serde_json::json;
+
+    fn dead_letter() -> DeadLetter {
+        DeadLetter {
+            topic: String::from("events"),
+            partition: 2,
+            offset: 42,
+            key: b"account-1".to_vec(),
+            value: vec![0, 159, 146, 150, 255],
+            reason: DeadLetterReason::Undelivered,
+            error: String::from("listener failed"),
+            aggregate_id: Some(String::from("account-1")),
+            sequence: Some(7),
+            failed_at: Utc.timestamp_opt(1_700_000_000, 123_000_000).unwrap(),
+        }
+    }
+
+    #[test]
+    fn dead_letter_round_trips_through_json() {
+        let json = serde_json::to_value(dead_letter()).unwrap();
+        assert_eq!(json["key"], json!(STANDARD.encode(b"account-1")));
+        assert_eq!(json["value"], json!("AJ+Slv8="));
+        assert_eq!(json["reason"], json!("undelivered"));
+        assert_eq!(
+            serde_json::from_value::<DeadLetter>(json).unwrap(),
+            dead_letter()
+        );
+    }
+
+    #[test]
+    fn dead_letter_of_an_undecodable_message_round_trips_through_json() {
+        let dead_letter = DeadLetter {
+            key: vec![],
+            reason: DeadLetterReason::Undecodable,
+            aggregate_id: None,
+            sequence: None,
+            ..dead_letter()
+        };
+        let json = serde_json::to_vec(&dead_letter).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<DeadLetter>(&json).unwrap(),
+            dead_letter
+        );
+    }
+
+    #[test]
+    fn dead_letter_with_invalid_base64_fails_to_deserialize() {
+        let mut json = serde_json::to_value(dead_letter()).unwrap();
+        json["value"] = json!("not base64!");
+        assert!(serde_json::from_value::<DeadLetter>(json).is_err());
+    }
+}
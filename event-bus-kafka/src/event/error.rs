@@ -0,0 +1,50 @@
+use event_sourcing::event::listener::DeliveryError;
+use event_sourcing::Error;
+use log::error;
+
+/// A message the container could not turn into an event.
+#[derive(Debug, Clone)]
+pub struct FailedMessage {
+    pub topic: String,
+    pub partition: i32,
+    pub offset: i64,
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+}
+
+/// Decides what happens to a message that could not be decoded. Returning an error stops the
+/// container without committing the message; returning `Ok` skips it.
+#[async_trait::async_trait]
+pub trait ErrorHandler: Send + Sync {
+    async fn handle(&self, message: &FailedMessage, error: &Error) -> Result<(), Error>;
+
+    /// Decides what happens to a message whose event a listener failed to handle, even once
+    /// retried. Returning `Ok` skips it; by default the error stops the container, and the
+    /// message is consumed again once it restarts.
+    async fn handle_undelivered(
+        &self,
+        _message: &FailedMessage,
+        error: &DeliveryError,
+    ) -> Result<(), Error> {
+        Err(Error::from(error.to_string()))
+    }
+}
+
+/// Logs messages that fail to decode and skips them.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LoggingErrorHandler;
+
+#[async_trait::async_trait]
+impl ErrorHandler for LoggingErrorHandler {
+    async fn handle(&self, message: &FailedMessage, error: &Error) -> Result<(), Error> {
+        error!(
+            "Skipping message {}:{}@{} that failed to decode: {}: {:?}",
+            message.topic,
+            message.partition,
+            message.offset,
+            error,
+            String::from_utf8_lossy(&message.value)
+        );
+        Ok(())
+    }
+}
@@ -1,19 +1,38 @@
+use event_sourcing::codec::Codecs;
 use event_sourcing::event::envelope::EventEnvelope;
-use event_sourcing::event::Event;
 use kafka::consumer::{Consumer, FetchOffset, GroupOffsetStorage};
-use log::{debug, error};
+use log::{debug, error, info, warn};
+use metrics::{counter, gauge};
 use retry::delay::Fixed;
 use retry::retry;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+use tracing::Span;
 
-use event_sourcing::event::listener::{EventListener, EventListenerContainer};
+use event_sourcing::event::listener::{
+    AsyncEventListener, AsyncEventListenerContainer, ContainerHandle, DeliveryOutcome,
+    EventListener, EventListenerContainer, EventListenerRegistry, RawEvent, RetryPolicy, Shutdown,
+};
+use event_sourcing::event::message::decode;
+use event_sourcing::trace;
 
+use crate::event::error::{ErrorHandler, FailedMessage, LoggingErrorHandler};
+
+/// Logs every event it receives.
 pub struct KafkaEventListener;
 
 impl EventListener for KafkaEventListener {
+    type Event = RawEvent;
     type Error = event_sourcing::Error;
 
-    fn on<E: Event>(&self, _event_envelope: EventEnvelope<E>) -> Result<(), Self::Error> {
-        todo!()
+    fn on(&self, event_envelope: EventEnvelope<RawEvent>) -> Result<(), Self::Error> {
+        debug!(
+            "{}#{}: {:?}",
+            event_envelope.aggregate_id, event_envelope.sequence, event_envelope.event
+        );
+        Ok(())
     }
 }
 
@@ -24,44 +43,652 @@ pub struct KafkaConnection {
     pub group: String,
 }
 
+/// How the events of polled messages are spread across the lanes handling them concurrently.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Dispatch {
+    /// Events of an aggregate are handled in order, and events of different aggregates at the
+    /// same time, even when they were read from the same partition.
+    #[default]
+    ByAggregate,
+    /// Events of a partition are handled in order, as they were published, and events of
+    /// different partitions at the same time.
+    ByPartition,
+}
+
+/// How often the consumer lag is refreshed, as it requires a round trip to the brokers.
+const LAG_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Consumes the events published to a topic by the Scylla CDC source connector, decoding each
+/// message into an envelope for the listener. Messages that fail to decode are passed to the
+/// [`ErrorHandler`], which logs and skips them by default.
+///
+/// Offsets are only committed for messages whose event every interested listener handled, so
+/// events are delivered at least once. A listener that fails is retried according to the
+/// [`RetryPolicy`], after which the container stops with the error, unless the error handler
+/// skips the message, e.g. by sending it to the dead letters.
 pub struct KafkaEventListenerContainer {
-    pub connection: KafkaConnection,
+    connection: KafkaConnection,
+    codecs: Codecs,
+    error_handler: Box<dyn ErrorHandler>,
+    concurrency: usize,
+    dispatch: Dispatch,
+    event_listeners: EventListenerRegistry,
 }
 
-impl EventListenerContainer for KafkaEventListenerContainer {
-    type Error = event_sourcing::Error;
+impl KafkaEventListenerContainer {
+    pub fn new(connection: KafkaConnection) -> Self {
+        Self {
+            connection,
+            codecs: Codecs::default(),
+            error_handler: Box::new(LoggingErrorHandler),
+            concurrency: 1,
+            dispatch: Dispatch::default(),
+            event_listeners: EventListenerRegistry::new(),
+        }
+    }
+
+    /// Codecs of the event payloads, matching the ones the events were stored with.
+    pub fn with_codecs(mut self, codecs: Codecs) -> Self {
+        self.codecs = codecs;
+        self
+    }
+
+    /// What to do with messages that fail to decode.
+    pub fn with_error_handler(mut self, error_handler: impl ErrorHandler + 'static) -> Self {
+        self.error_handler = Box::new(error_handler);
+        self
+    }
+
+    /// Deliver events to the listener, along with the other registered listeners.
+    pub fn with_listener<L: EventListener + 'static>(mut self, event_listener: L) -> Self {
+        self.event_listeners = self.event_listeners.with_listener(event_listener);
+        self
+    }
+
+    /// Deliver events to the async listener, along with the other registered listeners.
+    pub fn with_async_listener<L: AsyncEventListener + 'static>(
+        mut self,
+        event_listener: L,
+    ) -> Self {
+        self.event_listeners = self.event_listeners.with_async_listener(event_listener);
+        self
+    }
 
-    fn start<L: EventListener>(&self, _event_listener: L) -> Result<(), Self::Error> {
-        let brokers = &self.connection.brokers;
-        let topic = &self.connection.topic;
-        let group = &self.connection.group;
-        let mut consumer = retry(Fixed::from_millis(1000), || {
-            let result = Consumer::from_hosts(brokers.clone())
-                .with_topic(topic.clone())
-                .with_group(group.clone())
-                .with_fallback_offset(FetchOffset::Earliest)
-                .with_offset_storage(GroupOffsetStorage::Kafka)
-                .create();
-            if result.is_err() {
-                error!("Failed consuming messages: {:?}", result)
+    /// How often a listener is retried before the container gives up on an event and stops.
+    /// Offsets are only committed for the messages handled before it.
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.event_listeners = self.event_listeners.with_retry_policy(retry_policy);
+        self
+    }
+
+    /// Number of events handled at the same time, by default one. Events of an aggregate are
+    /// still handled one after the other, in order.
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency;
+        self
+    }
+
+    /// How events are spread across the concurrently handled lanes, by aggregate by default.
+    pub fn with_dispatch(mut self, dispatch: Dispatch) -> Self {
+        self.dispatch = dispatch;
+        self
+    }
+
+    /// The lane key of the event of a message, which is the same for every event that must be
+    /// handled in order.
+    fn lane_key(&self, message: &PolledMessage, event_envelope: &EventEnvelope<RawEvent>) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        match self.dispatch {
+            Dispatch::ByAggregate => event_envelope.aggregate_id.hash(&mut hasher),
+            Dispatch::ByPartition => (&message.topic, message.partition).hash(&mut hasher),
+        }
+        hasher.finish()
+    }
+
+    /// Decode a message into an envelope and the span its event is handled in.
+    async fn decode(
+        &self,
+        message: &PolledMessage,
+    ) -> Result<Option<(EventEnvelope<RawEvent>, Span)>, event_sourcing::Error> {
+        let PolledMessage {
+            topic,
+            partition,
+            offset,
+            ..
+        } = message;
+        let span = tracing::info_span!("kafka.process_message", topic, partition, offset);
+        let event_envelope = match decode::decode::<RawEvent>(&self.codecs, &message.value) {
+            Ok(Some(event_envelope)) => event_envelope,
+            Ok(None) => {
+                debug!("{topic}:{partition}@{offset}: no event");
+                return Ok(None);
+            }
+            Err(error) => {
+                counter!(
+                    "kafka_message_decode_errors_total",
+                    "topic" => topic.to_string(),
+                    "partition" => partition.to_string()
+                )
+                .increment(1);
+                self.error_handler.handle(&message.failed(), &error).await?;
+                return Ok(None);
             }
-            result
-        })?;
-        loop {
-            let message_sets = consumer.poll()?;
-            for message_set in message_sets.iter() {
-                for message in message_set.messages() {
-                    debug!(
-                        "{}:{}@{}: {:?}",
-                        message_set.topic(),
-                        message_set.partition(),
-                        message.offset,
-                        String::from_utf8(message.value.to_vec())
-                    );
+        };
+        trace::set_parent(&span, &event_envelope.metadata);
+        span.in_scope(|| {
+            debug!(
+                "{topic}:{partition}@{offset}: {}#{}",
+                event_envelope.aggregate_id, event_envelope.sequence
+            )
+        });
+        Ok(Some((event_envelope, span)))
+    }
+
+    /// Deliver the events of polled messages, returning whether each message was handled, and
+    /// the error stopping the container, if any. Events the error handler skipped once they
+    /// failed count as handled, and the events of their lane that were skipped in turn are
+    /// delivered again.
+    async fn handle(
+        &self,
+        event_listeners: &EventListenerRegistry,
+        messages: &[PolledMessage],
+    ) -> (Vec<bool>, Option<event_sourcing::Error>) {
+        let mut indices = vec![];
+        let mut batch = vec![];
+        for (index, message) in messages.iter().enumerate() {
+            match self.decode(message).await {
+                Ok(Some(event)) => {
+                    indices.push(index);
+                    batch.push(event);
                 }
-                let _ = consumer.consume_messageset(message_set);
+                Ok(None) => {}
+                Err(error) => return (vec![false; messages.len()], Some(error)),
             }
-            consumer.commit_consumed()?;
         }
+        let mut handled = vec![true; messages.len()];
+        while !batch.is_empty() {
+            let keys: Vec<u64> = indices
+                .iter()
+                .zip(&batch)
+                .map(|(&index, (event_envelope, _))| {
+                    self.lane_key(&messages[index], event_envelope)
+                })
+                .collect();
+            let outcomes = event_listeners
+                .deliver_in_lanes(self.concurrency, &batch, &keys)
+                .await;
+            let mut pending = (vec![], vec![]);
+            let mut failure = None;
+            for ((index, event), outcome) in indices.into_iter().zip(batch).zip(outcomes) {
+                match outcome {
+                    DeliveryOutcome::Delivered => {}
+                    DeliveryOutcome::Skipped => {
+                        pending.0.push(index);
+                        pending.1.push(event);
+                    }
+                    DeliveryOutcome::Failed(error) => {
+                        if let Err(error) = self
+                            .error_handler
+                            .handle_undelivered(&messages[index].failed(), &error)
+                            .await
+                        {
+                            handled[index] = false;
+                            failure = failure.or(Some(error));
+                        }
+                    }
+                }
+            }
+            if failure.is_some() {
+                for index in pending.0 {
+                    handled[index] = false;
+                }
+                return (handled, failure);
+            }
+            (indices, batch) = pending;
+        }
+        (handled, None)
+    }
+
+    async fn run(
+        &self,
+        event_listeners: &EventListenerRegistry,
+        shutdown: &Shutdown,
+    ) -> Result<(), event_sourcing::Error> {
+        let connection = self.connection.clone();
+        let mut consumer = tokio::task::spawn_blocking(move || {
+            retry(Fixed::from_millis(1000), || {
+                let result = Consumer::from_hosts(connection.brokers.clone())
+                    .with_topic(connection.topic.clone())
+                    .with_group(connection.group.clone())
+                    .with_fallback_offset(FetchOffset::Earliest)
+                    .with_offset_storage(GroupOffsetStorage::Kafka)
+                    .create();
+                if result.is_err() {
+                    error!("Failed consuming messages: {:?}", result)
+                }
+                result
+            })
+        })
+        .await??;
+        let mut lag_reported = Instant::now();
+        // Every polled message is delivered and committed before the shutdown is checked.
+        while !shutdown.is_stopped() {
+            let messages;
+            (consumer, messages) = blocking(consumer, poll).await?;
+            let messages = messages?;
+            let (handled, failure) = self.handle(event_listeners, &messages).await;
+            let consumed = committable_offsets(&messages, &handled);
+            let report_lag = lag_reported.elapsed() >= LAG_REPORT_INTERVAL;
+            let topic = self.connection.topic.clone();
+            let committed;
+            (consumer, committed) = blocking(consumer, move |consumer| {
+                for ((topic, partition), offset) in consumed {
+                    consumer.consume_message(&topic, partition, offset)?;
+                }
+                consumer.commit_consumed()?;
+                if report_lag {
+                    record_lag(consumer, &topic);
+                }
+                Ok::<_, kafka::Error>(())
+            })
+            .await?;
+            committed?;
+            if report_lag {
+                lag_reported = Instant::now();
+            }
+            if let Some(error) = failure {
+                return Err(error);
+            }
+        }
+        info!("Stopped consuming {}", self.connection.topic);
+        Ok(())
+    }
+}
+
+impl EventListenerContainer for KafkaEventListenerContainer {
+    type Error = event_sourcing::Error;
+
+    fn start<L: EventListener + 'static>(
+        self,
+        event_listener: L,
+    ) -> Result<ContainerHandle, Self::Error> {
+        ContainerHandle::spawn_thread(move |shutdown| {
+            let event_listeners = self.event_listeners.clone().with_listener(event_listener);
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?
+                .block_on(self.run(&event_listeners, &shutdown))
+        })
+    }
+}
+
+impl AsyncEventListenerContainer for KafkaEventListenerContainer {
+    type Error = event_sourcing::Error;
+
+    fn start<L: AsyncEventListener + 'static>(
+        self,
+        event_listener: L,
+    ) -> Result<ContainerHandle, Self::Error> {
+        Ok(ContainerHandle::spawn(move |shutdown| async move {
+            let event_listeners = self
+                .event_listeners
+                .clone()
+                .with_async_listener(event_listener);
+            self.run(&event_listeners, &shutdown).await
+        }))
+    }
+}
+
+/// A consumed message, copied out of the fetched message sets so it can outlive the poll.
+struct PolledMessage {
+    topic: String,
+    partition: i32,
+    offset: i64,
+    key: Vec<u8>,
+    value: Vec<u8>,
+}
+
+impl PolledMessage {
+    fn failed(&self) -> FailedMessage {
+        FailedMessage {
+            topic: self.topic.clone(),
+            partition: self.partition,
+            offset: self.offset,
+            key: self.key.clone(),
+            value: self.value.clone(),
+        }
+    }
+}
+
+/// The offset to mark as consumed for each partition of the polled messages. Offsets only
+/// advance up to the message before the first one of each partition that wasn't handled, so it
+/// is consumed again once the container restarts.
+fn committable_offsets(
+    messages: &[PolledMessage],
+    handled: &[bool],
+) -> HashMap<(String, i32), i64> {
+    let mut consumed = HashMap::new();
+    let mut unhandled = HashSet::new();
+    for (message, handled) in messages.iter().zip(handled) {
+        let partition = (message.topic.clone(), message.partition);
+        if *handled && !unhandled.contains(&partition) {
+            consumed.insert(partition, message.offset);
+        } else {
+            unhandled.insert(partition);
+        }
+    }
+    consumed
+}
+
+/// Run a blocking call of the consumer off the runtime's worker threads, handing the consumer
+/// back once it's done.
+async fn blocking<T: Send + 'static>(
+    mut consumer: Consumer,
+    call: impl FnOnce(&mut Consumer) -> T + Send + 'static,
+) -> Result<(Consumer, T), event_sourcing::Error> {
+    Ok(tokio::task::spawn_blocking(move || {
+        let result = call(&mut consumer);
+        (consumer, result)
+    })
+    .await?)
+}
+
+/// Fetch the next messages. They are only marked as consumed once handled.
+fn poll(consumer: &mut Consumer) -> Result<Vec<PolledMessage>, kafka::Error> {
+    let message_sets = consumer.poll()?;
+    let mut messages = vec![];
+    for message_set in message_sets.iter() {
+        counter!(
+            "kafka_messages_consumed_total",
+            "topic" => message_set.topic().to_string(),
+            "partition" => message_set.partition().to_string()
+        )
+        .increment(message_set.messages().len() as u64);
+        messages.extend(message_set.messages().iter().map(|message| PolledMessage {
+            topic: message_set.topic().to_string(),
+            partition: message_set.partition(),
+            offset: message.offset,
+            key: message.key.to_vec(),
+            value: message.value.to_vec(),
+        }));
+    }
+    Ok(messages)
+}
+
+/// Record how far the consumer group is behind the end of each partition of the topic.
+fn record_lag(consumer: &mut Consumer, topic: &str) {
+    let latest_offsets = match consumer
+        .client_mut()
+        .fetch_topic_offsets(topic, FetchOffset::Latest)
+    {
+        Ok(latest_offsets) => latest_offsets,
+        Err(error) => {
+            warn!("Failed fetching latest offsets: {:?}", error);
+            return;
+        }
+    };
+    for partition_offset in latest_offsets {
+        if let Some(consumed) = consumer.last_consumed_message(topic, partition_offset.partition) {
+            gauge!(
+                "kafka_consumer_lag",
+                "topic" => topic.to_string(),
+                "partition" => partition_offset.partition.to_string()
+            )
+            .set((partition_offset.offset - consumed - 1) as f64);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::dead_letter::{
+        DeadLetter, DeadLetterErrorHandler, DeadLetterReason, DeadLetterStore,
+    };
+    use event_sourcing::event::message::encode::encode;
+    use event_sourcing::test_support::{event_envelope, RecordingListener};
+    use std::sync::{Arc, Mutex};
+
+    fn message(partition: i32, offset: i64) -> PolledMessage {
+        PolledMessage {
+            topic: String::from("events"),
+            partition,
+            offset,
+            key: vec![],
+            value: vec![],
+        }
+    }
+
+    fn offsets(offsets: &[(i32, i64)]) -> HashMap<(String, i32), i64> {
+        offsets
+            .iter()
+            .map(|(partition, offset)| ((String::from("events"), *partition), *offset))
+            .collect()
+    }
+
+    #[test]
+    fn offsets_advance_to_the_last_message_of_each_partition() {
+        let messages = [
+            message(0, 10),
+            message(1, 20),
+            message(0, 11),
+            message(1, 21),
+        ];
+        assert_eq!(
+            committable_offsets(&messages, &[true; 4]),
+            offsets(&[(0, 11), (1, 21)])
+        );
+    }
+
+    #[test]
+    fn offsets_stop_before_a_failure_mid_partition() {
+        let messages = [
+            message(0, 10),
+            message(1, 20),
+            message(0, 11),
+            message(1, 21),
+            message(0, 12),
+        ];
+        assert_eq!(
+            committable_offsets(&messages, &[true, true, false, true, true]),
+            offsets(&[(0, 10), (1, 21)])
+        );
+    }
+
+    #[test]
+    fn partition_failing_on_its_first_message_is_not_committed() {
+        let messages = [
+            message(0, 10),
+            message(1, 20),
+            message(0, 11),
+            message(1, 21),
+        ];
+        assert_eq!(
+            committable_offsets(&messages, &[false, true, true, true]),
+            offsets(&[(1, 21)])
+        );
+    }
+
+    #[test]
+    fn offsets_of_topics_are_kept_apart() {
+        let mut other = message(0, 5);
+        other.topic = String::from("other");
+        let messages = [message(0, 10), other];
+        let mut expected = offsets(&[(0, 10)]);
+        expected.insert((String::from("other"), 0), 5);
+        assert_eq!(committable_offsets(&messages, &[true, true]), expected);
+    }
+
+    fn connection() -> KafkaConnection {
+        KafkaConnection {
+            brokers: vec![],
+            topic: String::from("events"),
+            group: String::from("test"),
+        }
+    }
+
+    /// A message of the event of an aggregate at a sequence, at an offset of partition 0.
+    fn event_message(aggregate_id: &str, sequence: i64, offset: i64) -> PolledMessage {
+        PolledMessage {
+            value: encode(&Codecs::default(), &event_envelope(aggregate_id, sequence)).unwrap(),
+            key: aggregate_id.as_bytes().to_vec(),
+            ..message(0, offset)
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct MemoryDeadLetterStore {
+        dead_letters: Arc<Mutex<Vec<DeadLetter>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl DeadLetterStore for MemoryDeadLetterStore {
+        async fn store(&self, dead_letter: &DeadLetter) -> Result<(), event_sourcing::Error> {
+            self.dead_letters.lock().unwrap().push(dead_letter.clone());
+            Ok(())
+        }
+    }
+
+    fn registry(listener: &RecordingListener) -> EventListenerRegistry {
+        EventListenerRegistry::new()
+            .with_listener(listener.clone())
+            .with_retry_policy(RetryPolicy::never())
+    }
+
+    #[tokio::test]
+    async fn event_sent_to_the_dead_letters_lets_its_lane_carry_on() {
+        let store = MemoryDeadLetterStore::default();
+        let container = KafkaEventListenerContainer::new(connection())
+            .with_error_handler(DeadLetterErrorHandler::new(store.clone()));
+        let listener = RecordingListener::failing_on("account-1", 2);
+        let messages: Vec<PolledMessage> = (1..=4)
+            .map(|sequence| event_message("account-1", sequence, 10 + sequence))
+            .collect();
+
+        let (handled, failure) = container.handle(&registry(&listener), &messages).await;
+
+        assert_eq!(handled, vec![true; 4]);
+        assert!(failure.is_none());
+        assert_eq!(listener.sequences(), vec![1, 3, 4]);
+        let dead_letters = store.dead_letters.lock().unwrap();
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0].offset, 12);
+        assert_eq!(dead_letters[0].reason, DeadLetterReason::Undelivered);
+        assert_eq!(dead_letters[0].aggregate_id.as_deref(), Some("account-1"));
+        assert_eq!(dead_letters[0].sequence, Some(2));
+        assert_eq!(dead_letters[0].value, messages[1].value);
+    }
+
+    #[tokio::test]
+    async fn undecodable_message_is_sent_to_the_dead_letters() {
+        let store = MemoryDeadLetterStore::default();
+        let container = KafkaEventListenerContainer::new(connection())
+            .with_error_handler(DeadLetterErrorHandler::new(store.clone()));
+        let listener = RecordingListener::default();
+        let messages = [
+            event_message("account-1", 1, 10),
+            PolledMessage {
+                value: b"not json".to_vec(),
+                ..message(0, 11)
+            },
+            event_message("account-1", 2, 12),
+        ];
+
+        let (handled, failure) = container.handle(&registry(&listener), &messages).await;
+
+        assert_eq!(handled, vec![true; 3]);
+        assert!(failure.is_none());
+        assert_eq!(listener.sequences(), vec![1, 2]);
+        let dead_letters = store.dead_letters.lock().unwrap();
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0].offset, 11);
+        assert_eq!(dead_letters[0].reason, DeadLetterReason::Undecodable);
+    }
+
+    #[tokio::test]
+    async fn undelivered_event_stops_its_lane_without_dead_letters() {
+        let container = KafkaEventListenerContainer::new(connection());
+        let listener = RecordingListener::failing_on("account-1", 2);
+        let messages: Vec<PolledMessage> = (1..=4)
+            .map(|sequence| event_message("account-1", sequence, 10 + sequence))
+            .collect();
+
+        let (handled, failure) = container.handle(&registry(&listener), &messages).await;
+
+        assert_eq!(handled, vec![true, false, false, false]);
+        assert!(failure.is_some());
+        assert_eq!(listener.sequences(), vec![1]);
+        assert_eq!(
+            committable_offsets(&messages, &handled),
+            offsets(&[(0, 11)])
+        );
+    }
+
+    fn event_message_of(partition: i32, aggregate_id: &str, sequence: i64) -> PolledMessage {
+        PolledMessage {
+            partition,
+            ..event_message(aggregate_id, sequence, sequence)
+        }
+    }
+
+    fn lane_key(container: &KafkaEventListenerContainer, message: &PolledMessage) -> u64 {
+        let event_envelope = decode::decode::<RawEvent>(&container.codecs, &message.value)
+            .unwrap()
+            .unwrap();
+        container.lane_key(message, &event_envelope)
+    }
+
+    #[test]
+    fn events_of_an_aggregate_share_a_lane_by_aggregate() {
+        let container = KafkaEventListenerContainer::new(connection());
+        assert_eq!(
+            lane_key(&container, &event_message_of(0, "account-1", 1)),
+            lane_key(&container, &event_message_of(1, "account-1", 2))
+        );
+        assert_ne!(
+            lane_key(&container, &event_message_of(0, "account-1", 1)),
+            lane_key(&container, &event_message_of(0, "account-2", 1))
+        );
+    }
+
+    #[test]
+    fn events_of_a_partition_share_a_lane_by_partition() {
+        let container =
+            KafkaEventListenerContainer::new(connection()).with_dispatch(Dispatch::ByPartition);
+        assert_eq!(
+            lane_key(&container, &event_message_of(0, "account-1", 1)),
+            lane_key(&container, &event_message_of(0, "account-2", 1))
+        );
+        assert_ne!(
+            lane_key(&container, &event_message_of(0, "account-1", 1)),
+            lane_key(&container, &event_message_of(1, "account-1", 2))
+        );
+    }
+
+    #[tokio::test]
+    async fn failure_by_partition_holds_back_the_rest_of_its_partition() {
+        let container = KafkaEventListenerContainer::new(connection())
+            .with_dispatch(Dispatch::ByPartition)
+            .with_concurrency(2);
+        // A partition delivered in another lane than partition 0 with a concurrency of 2.
+        let other = (1..)
+            .find(|partition| {
+                lane_key(&container, &event_message_of(*partition, "account-1", 1)) % 2
+                    != lane_key(&container, &event_message_of(0, "account-1", 1)) % 2
+            })
+            .unwrap();
+        let listener = RecordingListener::failing_on("account-1", 1);
+        let messages = [
+            event_message_of(0, "account-1", 1),
+            event_message_of(other, "account-2", 2),
+            event_message_of(0, "account-3", 3),
+            event_message_of(other, "account-4", 4),
+        ];
+
+        let (handled, failure) = container.handle(&registry(&listener), &messages).await;
+
+        assert!(failure.is_some());
+        assert_eq!(handled, vec![false, true, false, true]);
+        assert_eq!(listener.sequences(), vec![2, 4]);
     }
 }
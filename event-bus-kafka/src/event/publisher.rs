@@ -0,0 +1,77 @@
+use event_sourcing::codec::Codecs;
+use event_sourcing::event::envelope::EventEnvelope;
+use event_sourcing::event::message::encode;
+use event_sourcing::event::publisher::EventPublisher;
+use event_sourcing::event::Event;
+use event_sourcing::Error;
+use kafka::producer::{Producer, Record, RequiredAcks};
+use log::debug;
+use metrics::counter;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::event::listener::KafkaConnection;
+
+/// Publishes envelopes to the topic of the connection, for deployments that don't publish the
+/// events table through CDC. Messages are keyed by aggregate ID, so the events of an aggregate
+/// land on the same partition and are consumed in the order they were published.
+///
+/// The protocol spoken by the client predates record headers, so the metadata of the envelope
+/// is published within the message, in the same format as messages published through CDC.
+#[derive(Clone)]
+pub struct KafkaEventPublisher {
+    producer: Arc<Mutex<Producer>>,
+    topic: String,
+    codecs: Codecs,
+}
+
+impl KafkaEventPublisher {
+    /// Connects to the brokers of the connection, waiting for every in-sync replica to
+    /// acknowledge each message.
+    pub fn new(connection: &KafkaConnection) -> Result<Self, Error> {
+        let producer = Producer::from_hosts(connection.brokers.clone())
+            .with_ack_timeout(Duration::from_secs(1))
+            .with_required_acks(RequiredAcks::All)
+            .create()?;
+        Ok(Self {
+            producer: Arc::new(Mutex::new(producer)),
+            topic: connection.topic.clone(),
+            codecs: Codecs::default(),
+        })
+    }
+
+    /// Codecs of the event payloads, matching the ones of the consumers.
+    pub fn with_codecs(mut self, codecs: Codecs) -> Self {
+        self.codecs = codecs;
+        self
+    }
+}
+
+#[async_trait::async_trait]
+impl<E: Event> EventPublisher<E> for KafkaEventPublisher {
+    #[tracing::instrument(
+        name = "kafka.publish",
+        skip_all,
+        fields(aggregate_id = %event_envelope.aggregate_id, sequence = event_envelope.sequence)
+    )]
+    async fn publish(&self, event_envelope: &EventEnvelope<E>) -> Result<(), Error> {
+        let key = event_envelope.aggregate_id.clone();
+        let value = encode::encode(&self.codecs, event_envelope)?;
+        let producer = self.producer.clone();
+        let topic = self.topic.clone();
+        tokio::task::spawn_blocking(move || {
+            producer
+                .lock()
+                .map_err(|_| Error::from("Kafka producer is poisoned"))?
+                .send(&Record::from_key_value(&topic, key.as_bytes(), value))?;
+            Ok::<_, Error>(())
+        })
+        .await??;
+        debug!(
+            "{}: {}#{}",
+            self.topic, event_envelope.aggregate_id, event_envelope.sequence
+        );
+        counter!("kafka_events_published_total", "topic" => self.topic.clone()).increment(1);
+        Ok(())
+    }
+}
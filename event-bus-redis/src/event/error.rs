@@ -0,0 +1,36 @@
+use event_sourcing::Error;
+use log::error;
+
+/// An entry of a stream the container could not turn into an event.
+#[derive(Debug, Clone)]
+pub struct FailedEntry {
+    pub stream: String,
+    pub id: String,
+    pub value: Vec<u8>,
+}
+
+/// Decides what happens to an entry that could not be decoded. Returning `Ok` acknowledges and
+/// skips it; returning an error stops the container, leaving the entries it read pending, so
+/// they are read again once it restarts.
+#[async_trait::async_trait]
+pub trait ErrorHandler: Send + Sync {
+    async fn handle(&self, entry: &FailedEntry, error: &Error) -> Result<(), Error>;
+}
+
+/// Logs entries that fail to decode and skips them.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LoggingErrorHandler;
+
+#[async_trait::async_trait]
+impl ErrorHandler for LoggingErrorHandler {
+    async fn handle(&self, entry: &FailedEntry, error: &Error) -> Result<(), Error> {
+        error!(
+            "Skipping entry {}@{} that failed to decode: {}: {:?}",
+            entry.stream,
+            entry.id,
+            error,
+            String::from_utf8_lossy(&entry.value)
+        );
+        Ok(())
+    }
+}
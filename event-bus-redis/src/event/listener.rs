@@ -0,0 +1,483 @@
+use event_sourcing::codec::Codecs;
+use event_sourcing::event::envelope::EventEnvelope;
+use event_sourcing::event::listener::{
+    AsyncEventListener, AsyncEventListenerContainer, ContainerHandle, DeliveryOutcome,
+    EventListener, EventListenerContainer, EventListenerRegistry, RawEvent, RetryPolicy, Shutdown,
+};
+use event_sourcing::event::message::decode;
+use event_sourcing::trace;
+use event_sourcing::Error;
+use log::{debug, error, info};
+use metrics::counter;
+use redis::aio::MultiplexedConnection;
+use redis::streams::{
+    StreamAutoClaimOptions, StreamAutoClaimReply, StreamId, StreamReadOptions, StreamReadReply,
+};
+use redis::AsyncCommands;
+use std::time::{Duration, Instant};
+use tracing::Span;
+
+use crate::event::error::{ErrorHandler, FailedEntry, LoggingErrorHandler};
+
+/// Field of an entry holding the ID of the aggregate of its event.
+pub(crate) const AGGREGATE_ID_FIELD: &str = "aggregate_id";
+/// Field of an entry holding its event, encoded like the messages consumed from Kafka.
+pub(crate) const VALUE_FIELD: &str = "value";
+
+/// How long reading waits for new entries before the shutdown is checked again.
+const READ_BLOCK: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone)]
+pub struct RedisConnection {
+    // URL of the server, e.g. `redis://localhost:6379`.
+    pub url: String,
+    pub stream: String,
+    // Consumer group sharing the entries of the stream.
+    pub group: String,
+    // Name of the consumer within the group, which must be unique to each running container.
+    pub consumer: String,
+}
+
+/// Consumes the events appended to a stream by a [`RedisEventPublisher`] as a consumer of a
+/// group, which is created along with the stream when missing. Entries that fail to decode are
+/// passed to the [`ErrorHandler`], which logs and skips them by default.
+///
+/// Entries are only acknowledged once every interested listener handled their event, so events
+/// are delivered at least once. A listener that fails is retried according to the
+/// [`RetryPolicy`], after which the container stops with the error, leaving the entry pending.
+/// Pending entries are read again when the consumer restarts, or claimed by another consumer of
+/// the group once they have been idle for the claim timeout, e.g. when their consumer crashed.
+///
+/// Entries are spread across the consumers of a group, so events of an aggregate are only handled
+/// in order when the group has a single consumer.
+///
+/// [`RedisEventPublisher`]: crate::event::publisher::RedisEventPublisher
+pub struct RedisEventListenerContainer {
+    connection: RedisConnection,
+    codecs: Codecs,
+    error_handler: Box<dyn ErrorHandler>,
+    batch_size: usize,
+    claim_timeout: Duration,
+    concurrency: usize,
+    event_listeners: EventListenerRegistry,
+}
+
+impl RedisEventListenerContainer {
+    pub fn new(connection: RedisConnection) -> Self {
+        Self {
+            connection,
+            codecs: Codecs::default(),
+            error_handler: Box::new(LoggingErrorHandler),
+            batch_size: 100,
+            claim_timeout: Duration::from_secs(60),
+            concurrency: 1,
+            event_listeners: EventListenerRegistry::new(),
+        }
+    }
+
+    /// Codecs of the event payloads, matching the ones the events were published with.
+    pub fn with_codecs(mut self, codecs: Codecs) -> Self {
+        self.codecs = codecs;
+        self
+    }
+
+    /// What to do with entries that fail to decode.
+    pub fn with_error_handler(mut self, error_handler: impl ErrorHandler + 'static) -> Self {
+        self.error_handler = Box::new(error_handler);
+        self
+    }
+
+    /// Number of entries read at once, 100 by default.
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// How long an entry stays pending with another consumer of the group before this one
+    /// claims it, one minute by default. It must be longer than the listeners take to handle a
+    /// batch, or entries are handled twice while their consumer is still busy with them.
+    pub fn with_claim_timeout(mut self, claim_timeout: Duration) -> Self {
+        self.claim_timeout = claim_timeout;
+        self
+    }
+
+    /// Deliver events to the listener, along with the other registered listeners.
+    pub fn with_listener<L: EventListener + 'static>(mut self, event_listener: L) -> Self {
+        self.event_listeners = self.event_listeners.with_listener(event_listener);
+        self
+    }
+
+    /// Deliver events to the async listener, along with the other registered listeners.
+    pub fn with_async_listener<L: AsyncEventListener + 'static>(
+        mut self,
+        event_listener: L,
+    ) -> Self {
+        self.event_listeners = self.event_listeners.with_async_listener(event_listener);
+        self
+    }
+
+    /// How often a listener is retried before the container gives up on an event and stops.
+    /// Only the entries handled before it are acknowledged.
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.event_listeners = self.event_listeners.with_retry_policy(retry_policy);
+        self
+    }
+
+    /// Number of events handled at the same time, by default one. Events of an aggregate are
+    /// still handled one after the other, in order.
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency;
+        self
+    }
+
+    /// Decode an entry into an envelope and the span its event is handled in. Entries without
+    /// an event are skipped, and those whose event fails to decode are passed to the error
+    /// handler.
+    async fn decode(
+        &self,
+        entry: &StreamId,
+    ) -> Result<Option<(EventEnvelope<RawEvent>, Span)>, Error> {
+        let stream = &self.connection.stream;
+        let id = &entry.id;
+        let span = tracing::info_span!("redis.process_message", stream, id);
+        // Entries trimmed from the stream while pending are claimed without their fields.
+        let Some(value) = entry.get::<Vec<u8>>(VALUE_FIELD) else {
+            debug!("{stream}@{id}: no event");
+            return Ok(None);
+        };
+        match decode::decode::<RawEvent>(&self.codecs, &value) {
+            Ok(Some(event_envelope)) => {
+                trace::set_parent(&span, &event_envelope.metadata);
+                span.in_scope(|| {
+                    debug!(
+                        "{stream}@{id}: {}#{}",
+                        event_envelope.aggregate_id, event_envelope.sequence
+                    )
+                });
+                Ok(Some((event_envelope, span)))
+            }
+            Ok(None) => {
+                debug!("{stream}@{id}: no event");
+                Ok(None)
+            }
+            Err(error) => {
+                counter!("redis_message_decode_errors_total", "stream" => stream.clone())
+                    .increment(1);
+                let entry = FailedEntry {
+                    stream: stream.clone(),
+                    id: id.clone(),
+                    value,
+                };
+                self.error_handler.handle(&entry, &error).await?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Deliver the events of entries, returning the IDs of the entries to acknowledge, and the
+    /// error stopping the container, if any. None of the entries are acknowledged when the error
+    /// handler fails on one of them.
+    async fn handle(
+        &self,
+        event_listeners: &EventListenerRegistry,
+        entries: &[StreamId],
+    ) -> (Vec<String>, Option<Error>) {
+        let mut acknowledged = vec![];
+        let mut ids = vec![];
+        let mut batch = vec![];
+        for entry in entries {
+            match self.decode(entry).await {
+                Ok(Some(event)) => {
+                    ids.push(&entry.id);
+                    batch.push(event);
+                }
+                Ok(None) => acknowledged.push(entry.id.clone()),
+                Err(error) => return (vec![], Some(error)),
+            }
+        }
+        let outcomes = event_listeners.deliver(self.concurrency, &batch).await;
+        let mut failure = None;
+        for (id, outcome) in ids.into_iter().zip(outcomes) {
+            match outcome {
+                DeliveryOutcome::Delivered => acknowledged.push(id.clone()),
+                DeliveryOutcome::Failed(error) => failure = failure.or(Some(error.into())),
+                DeliveryOutcome::Skipped => {}
+            }
+        }
+        (acknowledged, failure)
+    }
+
+    /// Read entries of the stream after the ID for this consumer. `>` reads the entries no
+    /// consumer of the group has read yet, waiting for some to be appended, while other IDs read
+    /// the entries pending with this consumer.
+    async fn read(
+        &self,
+        connection: &mut MultiplexedConnection,
+        id: &str,
+    ) -> Result<Vec<StreamId>, Error> {
+        let mut options = StreamReadOptions::default()
+            .group(&self.connection.group, &self.connection.consumer)
+            .count(self.batch_size);
+        if id == ">" {
+            options = options.block(READ_BLOCK.as_millis() as usize);
+        }
+        let reply: Option<StreamReadReply> = connection
+            .xread_options(&[&self.connection.stream], &[id], &options)
+            .await?;
+        Ok(reply
+            .into_iter()
+            .flat_map(|reply| reply.keys)
+            .flat_map(|key| key.ids)
+            .collect())
+    }
+
+    /// Claim the entries that have been pending with other consumers for longer than the claim
+    /// timeout, scanning from the cursor. Returns the cursor to continue from, which is `0-0`
+    /// once the whole pending list has been scanned.
+    async fn claim(
+        &self,
+        connection: &mut MultiplexedConnection,
+        cursor: &str,
+    ) -> Result<(String, Vec<StreamId>), Error> {
+        let reply: StreamAutoClaimReply = connection
+            .xautoclaim_options(
+                &self.connection.stream,
+                &self.connection.group,
+                &self.connection.consumer,
+                self.claim_timeout.as_millis() as usize,
+                cursor,
+                StreamAutoClaimOptions::default().count(self.batch_size),
+            )
+            .await?;
+        if !reply.claimed.is_empty() {
+            info!(
+                "Claimed {} entries of {} pending with other consumers",
+                reply.claimed.len(),
+                self.connection.stream
+            );
+            counter!("redis_messages_claimed_total", "stream" => self.connection.stream.clone())
+                .increment(reply.claimed.len() as u64);
+        }
+        Ok((reply.next_stream_id, reply.claimed))
+    }
+
+    /// Connect to the server, retrying until it succeeds or the container is stopped.
+    async fn connect(&self, shutdown: &Shutdown) -> Result<Option<MultiplexedConnection>, Error> {
+        let client = redis::Client::open(self.connection.url.as_str())?;
+        loop {
+            match client.get_multiplexed_async_connection().await {
+                Ok(connection) => return Ok(Some(connection)),
+                Err(err) => error!("Failed connecting to {}: {err}", self.connection.url),
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(Duration::from_secs(1)) => {}
+                _ = shutdown.stopped() => return Ok(None),
+            }
+        }
+    }
+
+    async fn run(
+        &self,
+        event_listeners: &EventListenerRegistry,
+        shutdown: &Shutdown,
+    ) -> Result<(), Error> {
+        let Some(mut connection) = self.connect(shutdown).await? else {
+            return Ok(());
+        };
+        let created: Result<(), _> = connection
+            .xgroup_create_mkstream(&self.connection.stream, &self.connection.group, "0")
+            .await;
+        match created {
+            Ok(()) => info!(
+                "Created group {} of {}",
+                self.connection.group, self.connection.stream
+            ),
+            Err(err) if err.code() == Some("BUSYGROUP") => {}
+            Err(err) => return Err(err.into()),
+        }
+        // Entries left pending when the consumer last stopped are handled first, in order.
+        let mut pending = true;
+        let mut claiming: Option<String> = None;
+        let mut claimed = Instant::now();
+        while !shutdown.is_stopped() {
+            let entries = if pending {
+                let entries = self.read(&mut connection, "0").await?;
+                pending = !entries.is_empty();
+                entries
+            } else if let Some(cursor) = claiming.take() {
+                let (cursor, entries) = self.claim(&mut connection, &cursor).await?;
+                if cursor != "0-0" {
+                    claiming = Some(cursor);
+                }
+                entries
+            } else if claimed.elapsed() >= self.claim_timeout {
+                claimed = Instant::now();
+                claiming = Some(String::from("0-0"));
+                continue;
+            } else {
+                self.read(&mut connection, ">").await?
+            };
+            counter!("redis_messages_consumed_total", "stream" => self.connection.stream.clone())
+                .increment(entries.len() as u64);
+            let (acknowledged, failure) = self.handle(event_listeners, &entries).await;
+            if !acknowledged.is_empty() {
+                let _: i64 = connection
+                    .xack(
+                        &self.connection.stream,
+                        &self.connection.group,
+                        &acknowledged,
+                    )
+                    .await?;
+            }
+            if let Some(error) = failure {
+                return Err(error);
+            }
+        }
+        info!("Stopped consuming {}", self.connection.stream);
+        Ok(())
+    }
+}
+
+impl EventListenerContainer for RedisEventListenerContainer {
+    type Error = Error;
+
+    fn start<L: EventListener + 'static>(
+        self,
+        event_listener: L,
+    ) -> Result<ContainerHandle, Self::Error> {
+        ContainerHandle::spawn_thread(move |shutdown| {
+            let event_listeners = self.event_listeners.clone().with_listener(event_listener);
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?
+                .block_on(self.run(&event_listeners, &shutdown))
+        })
+    }
+}
+
+impl AsyncEventListenerContainer for RedisEventListenerContainer {
+    type Error = Error;
+
+    fn start<L: AsyncEventListener + 'static>(
+        self,
+        event_listener: L,
+    ) -> Result<ContainerHandle, Self::Error> {
+        Ok(ContainerHandle::spawn(move |shutdown| async move {
+            let event_listeners = self
+                .event_listeners
+                .clone()
+                .with_async_listener(event_listener);
+            self.run(&event_listeners, &shutdown).await
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use event_sourcing::event::message::encode::encode;
+    use event_sourcing::test_support::{event_envelope, RecordingListener};
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    fn container() -> RedisEventListenerContainer {
+        RedisEventListenerContainer::new(RedisConnection {
+            url: String::from("redis://localhost:6379"),
+            stream: String::from("events"),
+            group: String::from("test"),
+            consumer: String::from("test-1"),
+        })
+    }
+
+    fn entry(id: &str, value: Vec<u8>) -> StreamId {
+        StreamId {
+            id: String::from(id),
+            map: HashMap::from([(String::from(VALUE_FIELD), redis::Value::BulkString(value))]),
+        }
+    }
+
+    fn event_entry(id: &str, sequence: i64) -> StreamId {
+        let event_envelope = event_envelope("account-1", sequence);
+        entry(id, encode(&Codecs::default(), &event_envelope).unwrap())
+    }
+
+    /// Records the entries it is handed, failing when told to.
+    #[derive(Clone)]
+    struct RecordingErrorHandler {
+        entries: Arc<Mutex<Vec<String>>>,
+        fail: bool,
+    }
+
+    impl RecordingErrorHandler {
+        fn new(fail: bool) -> Self {
+            Self {
+                entries: Arc::default(),
+                fail,
+            }
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl ErrorHandler for RecordingErrorHandler {
+        async fn handle(&self, entry: &FailedEntry, _error: &Error) -> Result<(), Error> {
+            self.entries.lock().unwrap().push(entry.id.clone());
+            match self.fail {
+                true => Err(Error::from("dead letters unavailable")),
+                false => Ok(()),
+            }
+        }
+    }
+
+    fn entries() -> Vec<StreamId> {
+        vec![
+            event_entry("1-0", 1),
+            entry("2-0", b"not json".to_vec()),
+            event_entry("3-0", 2),
+        ]
+    }
+
+    #[tokio::test]
+    async fn undecodable_entry_skipped_by_the_error_handler_is_acknowledged() {
+        let error_handler = RecordingErrorHandler::new(false);
+        let container = container().with_error_handler(error_handler.clone());
+        let listener = RecordingListener::default();
+        let registry = EventListenerRegistry::new().with_listener(listener.clone());
+
+        let (acknowledged, failure) = container.handle(&registry, &entries()).await;
+
+        assert!(failure.is_none());
+        assert_eq!(acknowledged, vec!["2-0", "1-0", "3-0"]);
+        assert_eq!(*error_handler.entries.lock().unwrap(), vec!["2-0"]);
+        assert_eq!(listener.sequences(), vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn entries_stay_pending_when_the_error_handler_fails() {
+        let error_handler = RecordingErrorHandler::new(true);
+        let container = container().with_error_handler(error_handler.clone());
+        let listener = RecordingListener::default();
+        let registry = EventListenerRegistry::new().with_listener(listener.clone());
+
+        let (acknowledged, failure) = container.handle(&registry, &entries()).await;
+
+        assert_eq!(failure.unwrap().to_string(), "dead letters unavailable");
+        assert!(acknowledged.is_empty());
+        assert!(listener.sequences().is_empty());
+    }
+
+    #[tokio::test]
+    async fn entry_without_an_event_is_acknowledged() {
+        let container = container();
+        let trimmed = StreamId {
+            id: String::from("1-0"),
+            map: HashMap::new(),
+        };
+
+        let (acknowledged, failure) = container
+            .handle(&EventListenerRegistry::new(), &[trimmed])
+            .await;
+
+        assert!(failure.is_none());
+        assert_eq!(acknowledged, vec!["1-0"]);
+    }
+}
@@ -0,0 +1,81 @@
+use event_sourcing::codec::Codecs;
+use event_sourcing::event::envelope::EventEnvelope;
+use event_sourcing::event::message::encode;
+use event_sourcing::event::publisher::EventPublisher;
+use event_sourcing::event::Event;
+use event_sourcing::Error;
+use log::debug;
+use metrics::counter;
+use redis::aio::MultiplexedConnection;
+use redis::streams::StreamMaxlen;
+use redis::AsyncCommands;
+
+use crate::event::listener::{RedisConnection, AGGREGATE_ID_FIELD, VALUE_FIELD};
+
+/// Appends envelopes to the stream of the connection, in the same format as the messages
+/// consumed from Kafka, so the events of an aggregate are read in the order they were published.
+#[derive(Clone)]
+pub struct RedisEventPublisher {
+    connection: MultiplexedConnection,
+    stream: String,
+    codecs: Codecs,
+    max_len: Option<usize>,
+}
+
+impl RedisEventPublisher {
+    pub async fn new(connection: &RedisConnection) -> Result<Self, Error> {
+        let client = redis::Client::open(connection.url.as_str())?;
+        Ok(Self {
+            connection: client.get_multiplexed_async_connection().await?,
+            stream: connection.stream.clone(),
+            codecs: Codecs::default(),
+            max_len: None,
+        })
+    }
+
+    /// Codecs of the event payloads, matching the ones of the consumers.
+    pub fn with_codecs(mut self, codecs: Codecs) -> Self {
+        self.codecs = codecs;
+        self
+    }
+
+    /// Trim the stream to about this many entries as events are published. Entries trimmed
+    /// before every group has read them are lost, so it should leave room for the slowest one.
+    pub fn with_max_len(mut self, max_len: usize) -> Self {
+        self.max_len = Some(max_len);
+        self
+    }
+}
+
+#[async_trait::async_trait]
+impl<E: Event> EventPublisher<E> for RedisEventPublisher {
+    #[tracing::instrument(
+        name = "redis.publish",
+        skip_all,
+        fields(aggregate_id = %event_envelope.aggregate_id, sequence = event_envelope.sequence)
+    )]
+    async fn publish(&self, event_envelope: &EventEnvelope<E>) -> Result<(), Error> {
+        let fields = [
+            (
+                AGGREGATE_ID_FIELD,
+                event_envelope.aggregate_id.as_bytes().to_vec(),
+            ),
+            (VALUE_FIELD, encode::encode(&self.codecs, event_envelope)?),
+        ];
+        let mut connection = self.connection.clone();
+        let id: String = match self.max_len {
+            Some(max_len) => {
+                connection
+                    .xadd_maxlen(&self.stream, StreamMaxlen::Approx(max_len), "*", &fields)
+                    .await?
+            }
+            None => connection.xadd(&self.stream, "*", &fields).await?,
+        };
+        debug!(
+            "{}@{}: {}#{}",
+            self.stream, id, event_envelope.aggregate_id, event_envelope.sequence
+        );
+        counter!("redis_events_published_total", "stream" => self.stream.clone()).increment(1);
+        Ok(())
+    }
+}
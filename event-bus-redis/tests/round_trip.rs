@@ -0,0 +1,97 @@
+//! Round trips through a running Redis server. Run with `cargo test -- --ignored`, pointing
+//! `REDIS_URL` at the server when it isn't `redis://localhost:6379`.
+
+use chrono::Utc;
+use event_bus_redis::event::listener::{RedisConnection, RedisEventListenerContainer};
+use event_bus_redis::event::publisher::RedisEventPublisher;
+use event_sourcing::event::listener::{AsyncEventListenerContainer, SyncEventListener};
+use event_sourcing::event::publisher::EventPublisher;
+use event_sourcing::test_support::{event_envelope, RecordingListener};
+use redis::streams::{StreamPendingReply, StreamReadOptions, StreamReadReply};
+use redis::AsyncCommands;
+use std::time::Duration;
+
+fn connection(consumer: &str) -> RedisConnection {
+    let stream = format!("events-{}", Utc::now().timestamp_nanos_opt().unwrap());
+    RedisConnection {
+        url: std::env::var("REDIS_URL").unwrap_or(String::from("redis://localhost:6379")),
+        stream,
+        group: String::from("round-trip"),
+        consumer: String::from(consumer),
+    }
+}
+
+#[tokio::test]
+#[ignore = "requires a running Redis server"]
+async fn consumes_published_events_in_order() {
+    let connection = connection("consumer-1");
+    let publisher = RedisEventPublisher::new(&connection).await.unwrap();
+    for sequence in 1..=10 {
+        publisher
+            .publish(&event_envelope("account-1", sequence))
+            .await
+            .unwrap();
+    }
+
+    let listener = RecordingListener::default();
+    let handle = AsyncEventListenerContainer::start(
+        RedisEventListenerContainer::new(connection).with_batch_size(3),
+        SyncEventListener(listener.clone()),
+    )
+    .unwrap();
+    let sequences = listener.wait_for(10).await;
+    handle.stop();
+    handle.terminated().await.unwrap();
+
+    assert_eq!(sequences, (1..=10).collect::<Vec<_>>());
+}
+
+#[tokio::test]
+#[ignore = "requires a running Redis server"]
+async fn claims_entries_pending_with_a_crashed_consumer() {
+    let connection = connection("survivor");
+    let publisher = RedisEventPublisher::new(&connection).await.unwrap();
+    for sequence in 1..=3 {
+        publisher
+            .publish(&event_envelope("account-1", sequence))
+            .await
+            .unwrap();
+    }
+    // Another consumer of the group reads the entries, then crashes before acknowledging them.
+    let mut redis = redis::Client::open(connection.url.as_str())
+        .unwrap()
+        .get_multiplexed_async_connection()
+        .await
+        .unwrap();
+    let _: () = redis
+        .xgroup_create(&connection.stream, &connection.group, "0")
+        .await
+        .unwrap();
+    let read: StreamReadReply = redis
+        .xread_options(
+            &[&connection.stream],
+            &[">"],
+            &StreamReadOptions::default().group(&connection.group, "crashed"),
+        )
+        .await
+        .unwrap();
+    assert_eq!(read.keys[0].ids.len(), 3);
+
+    let listener = RecordingListener::default();
+    let handle = AsyncEventListenerContainer::start(
+        RedisEventListenerContainer::new(connection.clone())
+            .with_claim_timeout(Duration::from_millis(200)),
+        SyncEventListener(listener.clone()),
+    )
+    .unwrap();
+    let sequences = listener.wait_for(3).await;
+    handle.stop();
+    handle.terminated().await.unwrap();
+
+    assert_eq!(sequences, vec![1, 2, 3]);
+    let pending: StreamPendingReply = redis
+        .xpending(&connection.stream, &connection.group)
+        .await
+        .unwrap();
+    assert_eq!(pending.count(), 0);
+}
@@ -0,0 +1,36 @@
+use event_sourcing::Error;
+use log::error;
+use scylla::frame::response::result::CqlValue;
+use uuid::Uuid;
+
+/// A row of the CDC log the container could not turn into an event.
+#[derive(Debug, Clone)]
+pub struct FailedRow {
+    pub stream_id: Vec<u8>,
+    pub time: Option<Uuid>,
+    // The event columns of the row, in the order of `EVENT_COLUMNS`.
+    pub columns: Vec<Option<CqlValue>>,
+}
+
+/// Decides what happens to a row that could not be decoded. Returning `Ok` skips it; returning
+/// an error stops the container without checkpointing the window of the row, so it is read
+/// again once the container restarts.
+#[async_trait::async_trait]
+pub trait ErrorHandler: Send + Sync {
+    async fn handle(&self, row: &FailedRow, error: &Error) -> Result<(), Error>;
+}
+
+/// Logs rows that fail to decode and skips them.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LoggingErrorHandler;
+
+#[async_trait::async_trait]
+impl ErrorHandler for LoggingErrorHandler {
+    async fn handle(&self, row: &FailedRow, error: &Error) -> Result<(), Error> {
+        error!(
+            "Skipping row {:?} of CDC stream {:?} that failed to decode: {}: {:?}",
+            row.time, row.stream_id, error, row.columns
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,585 @@
+use chrono::{DateTime, Duration, Utc};
+use event_sourcing::event::envelope::EventEnvelope;
+use event_sourcing::event::listener::{
+    AsyncEventListener, AsyncEventListenerContainer, ContainerHandle, DeliveryOutcome,
+    EventListener, EventListenerContainer, EventListenerRegistry, RawEvent, RetryPolicy, Shutdown,
+};
+use event_sourcing::trace;
+use event_sourcing::Error;
+use event_store_scylladb::event::store::ScyllaDbEventStore;
+use event_store_scylladb::ScyllaDbConnection;
+use futures::StreamExt;
+use log::{debug, info};
+use metrics::counter;
+use scylla::frame::response::result::{CqlValue, Row};
+use scylla::statement::Consistency;
+use scylla::IntoTypedRows;
+use std::collections::HashMap;
+use tracing::Span;
+use uuid::Uuid;
+
+use crate::event::error::{ErrorHandler, FailedRow, LoggingErrorHandler};
+use crate::{migration, query};
+
+/// `cdc$operation` of a row inserted into the base table.
+const ROW_INSERT: i8 = 2;
+/// Maximum number of events read from the log before they are delivered to the listener.
+const DELIVERY_BATCH_SIZE: usize = 1000;
+
+/// Consumes the CDC log of the events table directly, delivering every appended event to the
+/// listener without going through Kafka Connect.
+///
+/// The log is read one window of time at a time for every stream of the current CDC generation.
+/// Events of an aggregate always belong to the same stream, so they are delivered in order. The
+/// end of the last window delivered for each stream is checkpointed under the consumer name, and
+/// a window is only checkpointed once every event in it has been handled, so events are
+/// delivered at least once.
+pub struct ScyllaDbCdcEventListenerContainer {
+    connection: ScyllaDbConnection,
+    consumer: String,
+    error_handler: Box<dyn ErrorHandler>,
+    poll_interval: std::time::Duration,
+    confidence_window: std::time::Duration,
+    concurrency: usize,
+    event_listeners: EventListenerRegistry,
+}
+
+impl ScyllaDbCdcEventListenerContainer {
+    /// Creates a container tracking its progress under the given consumer name.
+    pub fn new(connection: ScyllaDbConnection, consumer: impl Into<String>) -> Self {
+        Self {
+            connection,
+            consumer: consumer.into(),
+            error_handler: Box::new(LoggingErrorHandler),
+            poll_interval: std::time::Duration::from_secs(1),
+            confidence_window: std::time::Duration::from_secs(30),
+            concurrency: 1,
+            event_listeners: EventListenerRegistry::new(),
+        }
+    }
+
+    /// What to do with rows that fail to decode.
+    pub fn with_error_handler(mut self, error_handler: impl ErrorHandler + 'static) -> Self {
+        self.error_handler = Box::new(error_handler);
+        self
+    }
+
+    /// Time to wait between reads of the log.
+    pub fn with_poll_interval(mut self, poll_interval: std::time::Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    /// How far behind the present the log is read, so that writes still in flight with an
+    /// earlier timestamp are not skipped.
+    pub fn with_confidence_window(mut self, confidence_window: std::time::Duration) -> Self {
+        self.confidence_window = confidence_window;
+        self
+    }
+
+    /// Deliver events to the listener, along with the other registered listeners.
+    pub fn with_listener<L: EventListener + 'static>(mut self, event_listener: L) -> Self {
+        self.event_listeners = self.event_listeners.with_listener(event_listener);
+        self
+    }
+
+    /// Deliver events to the async listener, along with the other registered listeners.
+    pub fn with_async_listener<L: AsyncEventListener + 'static>(
+        mut self,
+        event_listener: L,
+    ) -> Self {
+        self.event_listeners = self.event_listeners.with_async_listener(event_listener);
+        self
+    }
+
+    /// How often a listener is retried before the container gives up on an event and stops,
+    /// leaving the window it belongs to to be read again once restarted.
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.event_listeners = self.event_listeners.with_retry_policy(retry_policy);
+        self
+    }
+
+    /// Number of events handled at the same time, by default one. Events of an aggregate are
+    /// still handled one after the other, in order.
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency;
+        self
+    }
+
+    async fn run(
+        &self,
+        event_listeners: &EventListenerRegistry,
+        shutdown: &Shutdown,
+    ) -> Result<(), Error> {
+        migration::migrate(&self.connection).await?;
+        let mut generation = self.initial_generation().await?;
+        loop {
+            let streams = self.streams(generation).await?;
+            let mut checkpoints = self.checkpoints(generation).await?;
+            info!(
+                "Consuming {} CDC streams of generation {}",
+                streams.len(),
+                generation
+            );
+            loop {
+                let next_generation = self.next_generation(generation).await?;
+                let window_end = window_end(
+                    Utc::now(),
+                    Duration::from_std(self.confidence_window)?,
+                    next_generation,
+                );
+                for stream_id in &streams {
+                    // A window is delivered and checkpointed as a whole before stopping.
+                    if shutdown.is_stopped() {
+                        info!("Stopped consuming CDC streams of generation {}", generation);
+                        return Ok(());
+                    }
+                    let window_start =
+                        window_start(checkpoints.get(stream_id).copied(), generation);
+                    if window_start >= window_end {
+                        continue;
+                    }
+                    self.consume_window(event_listeners, stream_id, window_start, window_end)
+                        .await?;
+                    self.save_checkpoint(generation, stream_id, window_end)
+                        .await?;
+                    checkpoints.insert(stream_id.clone(), window_end);
+                }
+                match next_generation {
+                    Some(next_generation) if window_end >= next_generation => {
+                        generation = next_generation;
+                        break;
+                    }
+                    _ => tokio::select! {
+                        _ = tokio::time::sleep(self.poll_interval) => {}
+                        _ = shutdown.stopped() => {
+                            info!("Stopped consuming CDC streams of generation {}", generation);
+                            return Ok(());
+                        }
+                    },
+                }
+            }
+        }
+    }
+
+    /// Deliver the events written to a stream after `window_start` up to and including
+    /// `window_end`.
+    async fn consume_window(
+        &self,
+        event_listeners: &EventListenerRegistry,
+        stream_id: &[u8],
+        window_start: DateTime<Utc>,
+        window_end: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        let session = self.connection.session().await?;
+        let statement = self
+            .connection
+            .prepare(&query::read_log(&self.connection), Consistency::LocalQuorum)
+            .await?;
+        let mut rows = session
+            .execute_iter(statement, (stream_id.to_vec(), window_start, window_end))
+            .await?
+            .chunks(DELIVERY_BATCH_SIZE);
+        while let Some(rows) = rows.next().await {
+            let mut batch = vec![];
+            for row in rows {
+                let mut row = row?;
+                let columns = row.columns.split_off(2);
+                let operation = row.columns.pop().flatten().and_then(|op| op.as_tinyint());
+                let time = row.columns.pop().flatten().and_then(|time| time.as_uuid());
+                if operation != Some(ROW_INSERT) {
+                    continue;
+                }
+                if let Some(event) = self.decode(stream_id, time, columns).await? {
+                    batch.push(event);
+                }
+            }
+            counter!(
+                "scylladb_cdc_events_consumed_total",
+                "consumer" => self.consumer.clone()
+            )
+            .increment(batch.len() as u64);
+            let outcomes = event_listeners.deliver(self.concurrency, &batch).await;
+            if let Some(error) = DeliveryOutcome::first_failure(outcomes) {
+                return Err(error.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Decode the event columns of a row inserted into the log, handing rows that fail to decode
+    /// to the error handler.
+    async fn decode(
+        &self,
+        stream_id: &[u8],
+        time: Option<Uuid>,
+        columns: Vec<Option<CqlValue>>,
+    ) -> Result<Option<(EventEnvelope<RawEvent>, Span)>, Error> {
+        let event_envelope = match ScyllaDbEventStore::map_event_envelope::<RawEvent>(
+            self.connection.codecs(),
+            Row {
+                columns: columns.clone(),
+            },
+        ) {
+            Ok(event_envelope) => event_envelope,
+            Err(error) => {
+                counter!(
+                    "scylladb_cdc_decode_errors_total",
+                    "consumer" => self.consumer.clone()
+                )
+                .increment(1);
+                let row = FailedRow {
+                    stream_id: stream_id.to_vec(),
+                    time,
+                    columns,
+                };
+                self.error_handler.handle(&row, &error).await?;
+                return Ok(None);
+            }
+        };
+        let span = tracing::info_span!(
+            "scylladb_cdc.process_event",
+            aggregate_id = %event_envelope.aggregate_id,
+            sequence = event_envelope.sequence,
+        );
+        trace::set_parent(&span, &event_envelope.metadata);
+        span.in_scope(|| {
+            debug!(
+                "{}@{:?}: {}#{}",
+                self.connection.events_table(),
+                time,
+                event_envelope.aggregate_id,
+                event_envelope.sequence
+            )
+        });
+        Ok(Some((event_envelope, span)))
+    }
+
+    /// The generation to resume from: the newest one this consumer has checkpoints for, or the
+    /// current generation when the consumer is new.
+    async fn initial_generation(&self) -> Result<DateTime<Utc>, Error> {
+        let session = self.connection.session().await?;
+        let statement = self
+            .connection
+            .prepare(
+                &query::read_latest_checkpointed_generation(&self.connection),
+                Consistency::LocalQuorum,
+            )
+            .await?;
+        let checkpointed = session
+            .execute(&statement, (&self.consumer,))
+            .await?
+            .rows
+            .unwrap_or_default()
+            .into_typed::<(DateTime<Utc>,)>()
+            .next()
+            .transpose()?
+            .map(|(generation,)| generation);
+        match checkpointed {
+            Some(generation) => Ok(generation),
+            None => self
+                .generations()
+                .await?
+                .into_iter()
+                .filter(|generation| generation <= &Utc::now())
+                .max()
+                .ok_or(Error::from("No CDC generation found")),
+        }
+    }
+
+    async fn next_generation(
+        &self,
+        generation: DateTime<Utc>,
+    ) -> Result<Option<DateTime<Utc>>, Error> {
+        Ok(self
+            .generations()
+            .await?
+            .into_iter()
+            .filter(|next| next > &generation)
+            .min())
+    }
+
+    async fn generations(&self) -> Result<Vec<DateTime<Utc>>, Error> {
+        let session = self.connection.session().await?;
+        let statement = self
+            .connection
+            .prepare(query::READ_GENERATIONS, Consistency::One)
+            .await?;
+        session
+            .execute(&statement, &[])
+            .await?
+            .rows
+            .unwrap_or_default()
+            .into_typed::<(DateTime<Utc>,)>()
+            .map(|row| Ok(row?.0))
+            .collect()
+    }
+
+    async fn streams(&self, generation: DateTime<Utc>) -> Result<Vec<Vec<u8>>, Error> {
+        let session = self.connection.session().await?;
+        let statement = self
+            .connection
+            .prepare(query::READ_STREAMS, Consistency::One)
+            .await?;
+        let mut streams = vec![];
+        for row in session
+            .execute(&statement, (generation,))
+            .await?
+            .rows
+            .unwrap_or_default()
+            .into_typed::<(Vec<Vec<u8>>,)>()
+        {
+            streams.extend(row?.0);
+        }
+        Ok(streams)
+    }
+
+    async fn checkpoints(
+        &self,
+        generation: DateTime<Utc>,
+    ) -> Result<HashMap<Vec<u8>, DateTime<Utc>>, Error> {
+        let session = self.connection.session().await?;
+        let statement = self
+            .connection
+            .prepare(
+                &query::read_checkpoints(&self.connection),
+                Consistency::LocalQuorum,
+            )
+            .await?;
+        session
+            .execute(&statement, (&self.consumer, generation))
+            .await?
+            .rows
+            .unwrap_or_default()
+            .into_typed::<(Vec<u8>, DateTime<Utc>)>()
+            .map(|row| Ok(row?))
+            .collect()
+    }
+
+    async fn save_checkpoint(
+        &self,
+        generation: DateTime<Utc>,
+        stream_id: &[u8],
+        window_end: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        let session = self.connection.session().await?;
+        let statement = self
+            .connection
+            .prepare(
+                &query::insert_checkpoint(&self.connection),
+                Consistency::LocalQuorum,
+            )
+            .await?;
+        session
+            .execute(
+                &statement,
+                (&self.consumer, generation, stream_id.to_vec(), window_end),
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+/// End of the next windows to read, inclusive: the present minus the confidence window, without
+/// crossing into the next generation, whose streams hold the writes from then on.
+fn window_end(
+    now: DateTime<Utc>,
+    confidence_window: Duration,
+    next_generation: Option<DateTime<Utc>>,
+) -> DateTime<Utc> {
+    let window_end = now - confidence_window;
+    match next_generation {
+        Some(next_generation) => window_end.min(next_generation),
+        None => window_end,
+    }
+}
+
+/// Start of the next window of a stream, exclusive: the end of the last window checkpointed, or
+/// just before the generation started for a stream read for the first time.
+fn window_start(checkpoint: Option<DateTime<Utc>>, generation: DateTime<Utc>) -> DateTime<Utc> {
+    checkpoint.unwrap_or(generation - Duration::milliseconds(1))
+}
+
+impl EventListenerContainer for ScyllaDbCdcEventListenerContainer {
+    type Error = Error;
+
+    /// The container runs on a runtime of its own, dropped once it stops, so it reads the log
+    /// through a session of its own rather than the one shared by the clones of its connection.
+    fn start<L: EventListener + 'static>(
+        mut self,
+        event_listener: L,
+    ) -> Result<ContainerHandle, Self::Error> {
+        self.connection = self.connection.detached();
+        ContainerHandle::spawn_thread(move |shutdown| {
+            let event_listeners = self.event_listeners.clone().with_listener(event_listener);
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?
+                .block_on(self.run(&event_listeners, &shutdown))
+        })
+    }
+}
+
+impl AsyncEventListenerContainer for ScyllaDbCdcEventListenerContainer {
+    type Error = Error;
+
+    fn start<L: AsyncEventListener + 'static>(
+        self,
+        event_listener: L,
+    ) -> Result<ContainerHandle, Self::Error> {
+        Ok(ContainerHandle::spawn(move |shutdown| async move {
+            let event_listeners = self
+                .event_listeners
+                .clone()
+                .with_async_listener(event_listener);
+            self.run(&event_listeners, &shutdown).await
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use std::sync::{Arc, Mutex};
+
+    fn time(seconds: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(1_700_000_000 + seconds, 0).unwrap()
+    }
+
+    #[test]
+    fn window_ends_the_confidence_window_before_now() {
+        assert_eq!(window_end(time(60), Duration::seconds(30), None), time(30));
+        assert_eq!(
+            window_end(time(60), Duration::seconds(30), Some(time(45))),
+            time(30)
+        );
+    }
+
+    #[test]
+    fn window_ends_at_the_next_generation() {
+        assert_eq!(
+            window_end(time(60), Duration::seconds(30), Some(time(10))),
+            time(10)
+        );
+    }
+
+    #[test]
+    fn window_of_a_new_stream_includes_the_start_of_the_generation() {
+        let start = window_start(None, time(0));
+        assert!(start < time(0));
+        assert_eq!(time(0) - start, Duration::milliseconds(1));
+    }
+
+    #[test]
+    fn window_resumes_after_the_checkpoint() {
+        assert_eq!(window_start(Some(time(30)), time(0)), time(30));
+    }
+
+    #[test]
+    fn consecutive_windows_neither_overlap_nor_leave_gaps() {
+        let first_end = window_end(time(60), Duration::seconds(30), None);
+        // The end of a window is checkpointed and is the exclusive start of the next one.
+        let second_start = window_start(Some(first_end), time(0));
+        let second_end = window_end(time(90), Duration::seconds(30), None);
+        assert_eq!(second_start, first_end);
+        assert!(second_end > second_start);
+    }
+
+    /// Records the time of the rows it is handed, failing when told to.
+    #[derive(Clone)]
+    struct RecordingErrorHandler {
+        rows: Arc<Mutex<Vec<Option<Uuid>>>>,
+        fail: bool,
+    }
+
+    impl RecordingErrorHandler {
+        fn new(fail: bool) -> Self {
+            Self {
+                rows: Arc::default(),
+                fail,
+            }
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl ErrorHandler for RecordingErrorHandler {
+        async fn handle(&self, row: &FailedRow, _error: &Error) -> Result<(), Error> {
+            self.rows.lock().unwrap().push(row.time);
+            match self.fail {
+                true => Err(Error::from("dead letters unavailable")),
+                false => Ok(()),
+            }
+        }
+    }
+
+    fn container(error_handler: RecordingErrorHandler) -> ScyllaDbCdcEventListenerContainer {
+        ScyllaDbCdcEventListenerContainer::new(
+            ScyllaDbConnection::new("localhost:9042", 1),
+            "account-projection",
+        )
+        .with_error_handler(error_handler)
+    }
+
+    /// The event columns of a row of the log holding the payload.
+    fn event_columns(payload: &[u8]) -> Vec<Option<CqlValue>> {
+        vec![
+            Some(CqlValue::Text(String::from("account-1"))),
+            Some(CqlValue::Text(String::from("account"))),
+            None,
+            Some(CqlValue::Text(String::from("Deposited"))),
+            Some(CqlValue::Timestamp(Duration::seconds(1_700_000_000))),
+            Some(CqlValue::BigInt(1)),
+            Some(CqlValue::BigInt(1)),
+            None,
+            Some(CqlValue::Int(0)),
+            Some(CqlValue::Blob(payload.to_vec())),
+            Some(CqlValue::Text(String::from("application/json"))),
+        ]
+    }
+
+    #[tokio::test]
+    async fn row_decodes_to_its_event() {
+        let error_handler = RecordingErrorHandler::new(false);
+        let container = container(error_handler.clone());
+
+        let (event_envelope, _) = container
+            .decode(b"stream", None, event_columns(b"{\"amount\": 10}"))
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(event_envelope.aggregate_id, "account-1");
+        assert_eq!(event_envelope.sequence, 1);
+        assert!(error_handler.rows.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn undecodable_row_skipped_by_the_error_handler_is_not_delivered() {
+        let error_handler = RecordingErrorHandler::new(false);
+        let container = container(error_handler.clone());
+        let time = Uuid::from_u128(42);
+
+        let decoded = container
+            .decode(b"stream", Some(time), event_columns(b"{\"amount\": "))
+            .await
+            .unwrap();
+
+        assert!(decoded.is_none());
+        assert_eq!(*error_handler.rows.lock().unwrap(), vec![Some(time)]);
+    }
+
+    #[tokio::test]
+    async fn failing_error_handler_stops_the_container() {
+        let error_handler = RecordingErrorHandler::new(true);
+        let container = container(error_handler.clone());
+
+        let error = container
+            .decode(b"stream", None, vec![None])
+            .await
+            .unwrap_err();
+
+        assert_eq!(error.to_string(), "dead letters unavailable");
+        assert_eq!(*error_handler.rows.lock().unwrap(), vec![None]);
+    }
+}
@@ -0,0 +1,27 @@
+use event_sourcing::Error;
+use event_store_scylladb::migration::{self, Migration, Schema, Statement};
+use event_store_scylladb::ScyllaDbConnection;
+
+use crate::query;
+
+/// The tables of the CDC listener container, whose versions are recorded in
+/// `cdc_schema_version`. Versions are never reused or reordered; a change to the tables is made
+/// by appending a new migration.
+pub fn schema(connection: &ScyllaDbConnection) -> Schema {
+    Schema {
+        version_table: String::from("cdc_schema_version"),
+        migrations: vec![Migration {
+            version: 1,
+            description: String::from("Create CDC checkpoints table"),
+            statements: vec![Statement::Idempotent(query::create_checkpoints_table(
+                connection,
+            ))],
+        }],
+    }
+}
+
+/// Apply the pending migrations of the tables of the CDC listener container. Containers apply
+/// them when started.
+pub async fn migrate(connection: &ScyllaDbConnection) -> Result<(), Error> {
+    migration::migrate_schema(connection, &schema(connection)).await
+}
@@ -0,0 +1,83 @@
+use event_store_scylladb::event::store::EVENT_COLUMNS;
+use event_store_scylladb::{table, ScyllaDbConnection};
+
+// language=cassandraql
+pub(crate) const READ_GENERATIONS: &str = "
+SELECT time
+FROM system_distributed.cdc_generation_timestamps
+WHERE key = 'timestamps'
+";
+// language=cassandraql
+pub(crate) const READ_STREAMS: &str = "
+SELECT streams
+FROM system_distributed.cdc_streams_descriptions_v2
+WHERE time = ?
+";
+
+pub(crate) fn read_log(connection: &ScyllaDbConnection) -> String {
+    let table = table(
+        connection,
+        &format!("{}_scylla_cdc_log", connection.events_table()),
+    );
+    // language=cassandraql
+    format!(
+        "
+SELECT \"cdc$time\", \"cdc$operation\", {EVENT_COLUMNS}
+FROM {table}
+WHERE \"cdc$stream_id\" = ? AND \"cdc$time\" > maxTimeuuid(?) AND \"cdc$time\" <= maxTimeuuid(?)
+"
+    )
+}
+
+pub(crate) fn create_checkpoints_table(connection: &ScyllaDbConnection) -> String {
+    let table = table(connection, "cdc_checkpoints");
+    // language=cassandraql
+    format!(
+        "
+CREATE TABLE IF NOT EXISTS {table}
+(
+    consumer   TEXT,
+    generation TIMESTAMP,
+    stream_id  BLOB,
+    window_end TIMESTAMP,
+    primary key (consumer, generation, stream_id)
+) WITH CLUSTERING ORDER BY (generation DESC, stream_id ASC)
+"
+    )
+}
+
+pub(crate) fn read_checkpoints(connection: &ScyllaDbConnection) -> String {
+    let table = table(connection, "cdc_checkpoints");
+    // language=cassandraql
+    format!(
+        "
+SELECT stream_id, window_end
+FROM {table}
+WHERE consumer = ? AND generation = ?
+"
+    )
+}
+
+pub(crate) fn read_latest_checkpointed_generation(connection: &ScyllaDbConnection) -> String {
+    let table = table(connection, "cdc_checkpoints");
+    // language=cassandraql
+    format!(
+        "
+SELECT generation
+FROM {table}
+WHERE consumer = ?
+LIMIT 1
+"
+    )
+}
+
+pub(crate) fn insert_checkpoint(connection: &ScyllaDbConnection) -> String {
+    let table = table(connection, "cdc_checkpoints");
+    // language=cassandraql
+    format!(
+        "
+INSERT INTO {table} (consumer, generation, stream_id, window_end)
+VALUES (?, ?, ?, ?)
+"
+    )
+}
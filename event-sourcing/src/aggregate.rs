@@ -3,6 +3,7 @@ use crate::event::Event;
 use crate::snapshot::envelope::SnapshotEnvelope;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
+use serde_json::Value;
 use std::fmt::Debug;
 
 /// An aggregate is a cluster of associated events that is treated as a unit for the purpose of data changes.
@@ -15,6 +16,20 @@ pub trait Aggregate: Sized + Send + Sync + Clone + Serialize + DeserializeOwned
     fn aggregate_type(&self) -> String;
     fn apply(state: Option<Self>, event: Self::Event) -> Result<Self, Self::Error>;
     fn apply_all(state: Option<Self>, events: Vec<Self::Event>) -> Result<Self, Self::Error>;
+
+    /// Revision of the serialized state, increased whenever its shape changes so snapshots taken
+    /// at an older revision are upcast or discarded rather than misread.
+    fn revision() -> i64 {
+        1
+    }
+
+    /// Migrate the serialized state of a snapshot taken at an older revision to the current one.
+    /// Snapshots that can't be upcast are discarded and the state is rebuilt from the events.
+    /// Numbers of the value are held as `i64`, `u64` or `f64`, so states with integers beyond 64
+    /// bits are better upcast from a string representation.
+    fn upcast(_revision: i64, _state: Value) -> Option<Value> {
+        None
+    }
 }
 
 pub fn current_sequence<E: Event, A: Aggregate>(
@@ -0,0 +1,217 @@
+use crate::Error;
+use custom_error::custom_error;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::Arc;
+
+custom_error! {pub CodecError
+    UnknownContentType{content_type: String} = "no codec registered for content type {content_type}",
+}
+
+/// Content type of payloads written by [`JsonCodec`].
+pub const APPLICATION_JSON: &str = "application/json";
+
+/// Turns the JSON of event and snapshot payloads into the bytes kept by a store, and back.
+/// Payloads are tagged with the content type of the codec that wrote them, so stores can read
+/// payloads written by codecs that are no longer in use.
+///
+/// Codecs work on the payload serialized as JSON, rather than on a JSON value, so numbers keep
+/// their exact representation and integers beyond 64 bits, such as `i128` amounts, survive the
+/// round trip. A codec can wrap another one to compress or encrypt its output.
+pub trait Codec: Send + Sync + Debug {
+    fn content_type(&self) -> &str;
+    fn encode(&self, json: &[u8]) -> Result<Vec<u8>, Error>;
+    fn decode(&self, payload: &[u8]) -> Result<Vec<u8>, Error>;
+}
+
+/// Payloads stored as the UTF-8 JSON they serialize to.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn content_type(&self) -> &str {
+        APPLICATION_JSON
+    }
+
+    fn encode(&self, json: &[u8]) -> Result<Vec<u8>, Error> {
+        Ok(json.to_vec())
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<Vec<u8>, Error> {
+        Ok(payload.to_vec())
+    }
+}
+
+/// The codec payloads are written with, and every codec payloads can be read with, by content
+/// type.
+#[derive(Debug, Clone)]
+pub struct Codecs {
+    current: Arc<dyn Codec>,
+    codecs: HashMap<String, Arc<dyn Codec>>,
+}
+
+impl Codecs {
+    /// Write payloads with the given codec.
+    pub fn new(codec: impl Codec + 'static) -> Self {
+        let codec: Arc<dyn Codec> = Arc::new(codec);
+        Self {
+            codecs: HashMap::from([(codec.content_type().to_string(), codec.clone())]),
+            current: codec,
+        }
+    }
+
+    /// Also read payloads written with the given codec, e.g. one that has been replaced.
+    pub fn with_codec(mut self, codec: impl Codec + 'static) -> Self {
+        self.codecs
+            .insert(codec.content_type().to_string(), Arc::new(codec));
+        self
+    }
+
+    /// Content type of the payloads written by [`Codecs::encode`].
+    pub fn content_type(&self) -> &str {
+        self.current.content_type()
+    }
+
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, Error> {
+        self.current.encode(&serde_json::to_vec(value)?)
+    }
+
+    pub fn decode<T: DeserializeOwned>(
+        &self,
+        content_type: &str,
+        payload: &[u8],
+    ) -> Result<T, Error> {
+        Ok(serde_json::from_slice(
+            &self.decode_json(content_type, payload)?,
+        )?)
+    }
+
+    /// Decode a payload to its JSON without deserializing it, e.g. to upcast it first.
+    pub fn decode_json(&self, content_type: &str, payload: &[u8]) -> Result<Vec<u8>, Error> {
+        self.codecs
+            .get(content_type)
+            .ok_or(CodecError::UnknownContentType {
+                content_type: content_type.to_string(),
+            })?
+            .decode(payload)
+    }
+}
+
+impl Default for Codecs {
+    fn default() -> Self {
+        Self::new(JsonCodec)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Account {
+        balance: i128,
+        limit: u128,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Amount {
+        value: i64,
+        fee: u64,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    #[serde(tag = "type")]
+    enum Transaction {
+        Deposited {
+            #[serde(flatten)]
+            amount: Amount,
+        },
+    }
+
+    /// JSON written back to front, standing in for a compressing or encrypting codec.
+    #[derive(Debug)]
+    struct ReversedJsonCodec;
+
+    impl Codec for ReversedJsonCodec {
+        fn content_type(&self) -> &str {
+            "application/x-reversed-json"
+        }
+
+        fn encode(&self, json: &[u8]) -> Result<Vec<u8>, Error> {
+            Ok(json.iter().rev().copied().collect())
+        }
+
+        fn decode(&self, payload: &[u8]) -> Result<Vec<u8>, Error> {
+            Ok(payload.iter().rev().copied().collect())
+        }
+    }
+
+    const ACCOUNT: Account = Account {
+        balance: i128::MIN + 1,
+        limit: u128::MAX,
+    };
+
+    #[test]
+    fn integers_beyond_64_bits_round_trip() {
+        let codecs = Codecs::default();
+        let payload = codecs.encode(&ACCOUNT).unwrap();
+        assert_eq!(
+            codecs
+                .decode::<Account>(codecs.content_type(), &payload)
+                .unwrap(),
+            ACCOUNT
+        );
+    }
+
+    #[test]
+    fn integers_beyond_64_bits_survive_the_decoded_json() {
+        let codecs = Codecs::default();
+        let payload = codecs.encode(&ACCOUNT).unwrap();
+        let json = codecs.decode_json(codecs.content_type(), &payload).unwrap();
+        assert_eq!(serde_json::from_slice::<Account>(&json).unwrap(), ACCOUNT);
+    }
+
+    #[test]
+    fn integers_beyond_64_bits_round_trip_through_a_wrapping_codec() {
+        let codecs = Codecs::new(ReversedJsonCodec);
+        let payload = codecs.encode(&ACCOUNT).unwrap();
+        assert_eq!(
+            codecs
+                .decode::<Account>(codecs.content_type(), &payload)
+                .unwrap(),
+            ACCOUNT
+        );
+    }
+
+    #[test]
+    fn flattened_and_tagged_numbers_round_trip() {
+        let codecs = Codecs::default();
+        let deposited = Transaction::Deposited {
+            amount: Amount {
+                value: -42,
+                fee: u64::MAX,
+            },
+        };
+        let payload = codecs.encode(&deposited).unwrap();
+        assert_eq!(
+            codecs
+                .decode::<Transaction>(codecs.content_type(), &payload)
+                .unwrap(),
+            deposited
+        );
+    }
+
+    #[test]
+    fn unknown_content_type_fails_to_decode() {
+        let error = Codecs::default()
+            .decode::<Account>("application/cbor", b"")
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<CodecError>(),
+            Some(CodecError::UnknownContentType { .. })
+        ));
+    }
+}
@@ -2,8 +2,12 @@ use serde::de::DeserializeOwned;
 use serde::Serialize;
 use std::fmt::Debug;
 
+pub mod bus;
 pub mod envelope;
+pub mod inbox;
 pub mod listener;
+pub mod message;
+pub mod publisher;
 pub mod store;
 
 pub trait Event: Serialize + DeserializeOwned + Sized + Send + Sync + Clone + Debug {
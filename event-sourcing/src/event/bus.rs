@@ -0,0 +1,358 @@
+use crate::event::envelope::EventEnvelope;
+use crate::event::listener::{
+    AsyncEventListener, AsyncEventListenerContainer, ContainerHandle, EventListener,
+    EventListenerContainer, EventListenerRegistry, RawEvent, RetryPolicy, Shutdown,
+};
+use crate::event::publisher::EventPublisher;
+use crate::event::Event;
+use crate::trace;
+use crate::Error;
+use custom_error::custom_error;
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::{info, warn, Instrument};
+
+custom_error! {pub EventBusError
+    Full{aggregate_id: String, sequence: i64} = "Event bus has no room for event {aggregate_id}#{sequence}",
+}
+
+/// What publishing does when a listener has fallen behind and its queue is full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Backpressure {
+    /// Wait until the listener has room for the event, slowing the publisher down to the pace of
+    /// the slowest listener.
+    #[default]
+    Wait,
+    /// Fail with [`EventBusError::Full`]. Listeners with room for the event still receive it.
+    Fail,
+    /// Drop the event for the listener, which never sees it.
+    Drop,
+}
+
+type Subscribers = Arc<Mutex<Vec<mpsc::Sender<EventEnvelope<RawEvent>>>>>;
+
+/// Delivers published events to the listeners started on it within the same process, without
+/// any infrastructure, e.g. for services running as a single process and for tests.
+///
+/// Clones share the same listeners, so the bus can be handed to the command handler as a
+/// publisher and started with listeners. Each listener has a queue of its own, receiving the
+/// events published once it has started, in the order they were published. Events aren't kept
+/// anywhere else, so events queued when the process stops are lost.
+#[derive(Clone)]
+pub struct InMemoryEventBus {
+    subscribers: Subscribers,
+    capacity: usize,
+    backpressure: Backpressure,
+    event_listeners: EventListenerRegistry,
+}
+
+impl InMemoryEventBus {
+    pub fn new() -> Self {
+        Self {
+            subscribers: Arc::new(Mutex::new(vec![])),
+            capacity: 1024,
+            backpressure: Backpressure::default(),
+            event_listeners: EventListenerRegistry::new(),
+        }
+    }
+
+    /// Number of events queued for each listener before publishing applies the backpressure,
+    /// 1024 by default.
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity.max(1);
+        self
+    }
+
+    /// What publishing does when a listener's queue is full.
+    pub fn with_backpressure(mut self, backpressure: Backpressure) -> Self {
+        self.backpressure = backpressure;
+        self
+    }
+
+    /// Deliver events to the listener, along with the other registered listeners.
+    pub fn with_listener<L: EventListener + 'static>(mut self, event_listener: L) -> Self {
+        self.event_listeners = self.event_listeners.with_listener(event_listener);
+        self
+    }
+
+    /// Deliver events to the async listener, along with the other registered listeners.
+    pub fn with_async_listener<L: AsyncEventListener + 'static>(
+        mut self,
+        event_listener: L,
+    ) -> Self {
+        self.event_listeners = self.event_listeners.with_async_listener(event_listener);
+        self
+    }
+
+    /// How often a listener is retried before the container gives up on an event and stops.
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.event_listeners = self.event_listeners.with_retry_policy(retry_policy);
+        self
+    }
+
+    /// Add a queue receiving every event published from now on.
+    fn subscribe(&self) -> Result<mpsc::Receiver<EventEnvelope<RawEvent>>, Error> {
+        let (sender, receiver) = mpsc::channel(self.capacity);
+        self.lock_subscribers()?.push(sender);
+        Ok(receiver)
+    }
+
+    /// The queues of the running listeners, forgetting the ones of stopped listeners.
+    fn subscribers(&self) -> Result<Vec<mpsc::Sender<EventEnvelope<RawEvent>>>, Error> {
+        let mut subscribers = self.lock_subscribers()?;
+        subscribers.retain(|sender| !sender.is_closed());
+        Ok(subscribers.clone())
+    }
+
+    fn lock_subscribers(
+        &self,
+    ) -> Result<MutexGuard<'_, Vec<mpsc::Sender<EventEnvelope<RawEvent>>>>, Error> {
+        self.subscribers
+            .lock()
+            .map_err(|_| Error::from("In-memory event bus subscribers are poisoned"))
+    }
+
+    async fn run(
+        mut receiver: mpsc::Receiver<EventEnvelope<RawEvent>>,
+        event_listeners: &EventListenerRegistry,
+        shutdown: &Shutdown,
+    ) -> Result<(), Error> {
+        loop {
+            let event_envelope = tokio::select! {
+                event_envelope = receiver.recv() => match event_envelope {
+                    Some(event_envelope) => event_envelope,
+                    None => break,
+                },
+                _ = shutdown.stopped() => break,
+            };
+            let span = tracing::info_span!(
+                "in_memory.process_event",
+                aggregate_id = event_envelope.aggregate_id,
+                sequence = event_envelope.sequence
+            );
+            trace::set_parent(&span, &event_envelope.metadata);
+            event_listeners
+                .dispatch(&event_envelope)
+                .instrument(span)
+                .await?;
+        }
+        info!("Stopped receiving events from the in-memory event bus");
+        Ok(())
+    }
+}
+
+impl Default for InMemoryEventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait::async_trait]
+impl<E: Event> EventPublisher<E> for InMemoryEventBus {
+    async fn publish(&self, event_envelope: &EventEnvelope<E>) -> Result<(), Error> {
+        let event_envelope = EventEnvelope::new(
+            event_envelope.aggregate_id.clone(),
+            event_envelope.aggregate_type.clone(),
+            RawEvent(serde_json::to_value(&event_envelope.event)?),
+            event_envelope.event_type.clone(),
+            event_envelope.event_time,
+            event_envelope.sequence,
+            event_envelope.revision,
+            event_envelope.metadata.clone(),
+        );
+        let mut full = false;
+        for subscriber in self.subscribers()? {
+            // A listener that stopped since is simply skipped.
+            match self.backpressure {
+                Backpressure::Wait => {
+                    let _ = subscriber.send(event_envelope.clone()).await;
+                }
+                Backpressure::Fail | Backpressure::Drop => {
+                    if let Err(TrySendError::Full(_)) = subscriber.try_send(event_envelope.clone())
+                    {
+                        full = true;
+                    }
+                }
+            }
+        }
+        if full {
+            if self.backpressure == Backpressure::Fail {
+                return Err(Box::new(EventBusError::Full {
+                    aggregate_id: event_envelope.aggregate_id,
+                    sequence: event_envelope.sequence,
+                }));
+            }
+            warn!(
+                "Dropped event {}#{} for a listener of the in-memory event bus that has fallen behind",
+                event_envelope.aggregate_id, event_envelope.sequence
+            );
+        }
+        Ok(())
+    }
+}
+
+impl EventListenerContainer for InMemoryEventBus {
+    type Error = Error;
+
+    fn start<L: EventListener + 'static>(
+        self,
+        event_listener: L,
+    ) -> Result<ContainerHandle, Self::Error> {
+        let receiver = self.subscribe()?;
+        ContainerHandle::spawn_thread(move |shutdown| {
+            let event_listeners = self.event_listeners.clone().with_listener(event_listener);
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?
+                .block_on(Self::run(receiver, &event_listeners, &shutdown))
+        })
+    }
+}
+
+impl AsyncEventListenerContainer for InMemoryEventBus {
+    type Error = Error;
+
+    fn start<L: AsyncEventListener + 'static>(
+        self,
+        event_listener: L,
+    ) -> Result<ContainerHandle, Self::Error> {
+        let receiver = self.subscribe()?;
+        Ok(ContainerHandle::spawn(move |shutdown| async move {
+            let event_listeners = self
+                .event_listeners
+                .clone()
+                .with_async_listener(event_listener);
+            Self::run(receiver, &event_listeners, &shutdown).await
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{event_envelope, RecordingListener};
+    use std::time::Duration;
+
+    async fn publish(bus: &InMemoryEventBus, sequence: i64) -> Result<(), Error> {
+        EventPublisher::<RawEvent>::publish(bus, &event_envelope("account-1", sequence)).await
+    }
+
+    #[tokio::test]
+    async fn async_listener_receives_published_events_in_order() {
+        let bus = InMemoryEventBus::new();
+        let listener = RecordingListener::default();
+        let handle = AsyncEventListenerContainer::start(bus.clone(), listener.clone()).unwrap();
+        for sequence in 1..=5 {
+            publish(&bus, sequence).await.unwrap();
+        }
+
+        assert_eq!(listener.wait_for(5).await, vec![1, 2, 3, 4, 5]);
+        handle.stop();
+        handle.terminated().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn listener_thread_receives_published_events_in_order() {
+        let bus = InMemoryEventBus::new();
+        let listener = RecordingListener::default();
+        let handle = EventListenerContainer::start(bus.clone(), listener.clone()).unwrap();
+        for sequence in 1..=5 {
+            publish(&bus, sequence).await.unwrap();
+        }
+
+        assert_eq!(listener.wait_for(5).await, vec![1, 2, 3, 4, 5]);
+        handle.stop();
+        handle.terminated().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn registered_listeners_receive_events_too() {
+        let registered = RecordingListener::default();
+        let bus = InMemoryEventBus::new().with_listener(registered.clone());
+        let listener = RecordingListener::default();
+        let handle = AsyncEventListenerContainer::start(bus.clone(), listener.clone()).unwrap();
+        publish(&bus, 1).await.unwrap();
+
+        assert_eq!(listener.wait_for(1).await, vec![1]);
+        assert_eq!(registered.wait_for(1).await, vec![1]);
+        handle.stop();
+        handle.terminated().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn full_queue_makes_publishing_wait() {
+        let bus = InMemoryEventBus::new().with_capacity(1);
+        let mut receiver = bus.subscribe().unwrap();
+        publish(&bus, 1).await.unwrap();
+
+        let waiting = tokio::time::timeout(Duration::from_millis(50), publish(&bus, 2)).await;
+        assert!(waiting.is_err());
+
+        assert_eq!(receiver.recv().await.unwrap().sequence, 1);
+        publish(&bus, 3).await.unwrap();
+        assert_eq!(receiver.recv().await.unwrap().sequence, 3);
+    }
+
+    #[tokio::test]
+    async fn full_queue_fails_publishing_while_others_receive() {
+        let bus = InMemoryEventBus::new()
+            .with_capacity(1)
+            .with_backpressure(Backpressure::Fail);
+        let mut behind = bus.subscribe().unwrap();
+        publish(&bus, 1).await.unwrap();
+        let mut keeping_up = bus.subscribe().unwrap();
+
+        let error = publish(&bus, 2).await.unwrap_err();
+
+        assert!(matches!(
+            error.downcast_ref::<EventBusError>(),
+            Some(EventBusError::Full { sequence: 2, .. })
+        ));
+        assert_eq!(keeping_up.recv().await.unwrap().sequence, 2);
+        assert_eq!(behind.recv().await.unwrap().sequence, 1);
+        assert!(behind.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn full_queue_drops_the_event_while_others_receive() {
+        let bus = InMemoryEventBus::new()
+            .with_capacity(1)
+            .with_backpressure(Backpressure::Drop);
+        let mut behind = bus.subscribe().unwrap();
+        publish(&bus, 1).await.unwrap();
+        let mut keeping_up = bus.subscribe().unwrap();
+
+        publish(&bus, 2).await.unwrap();
+
+        assert_eq!(keeping_up.recv().await.unwrap().sequence, 2);
+        assert_eq!(behind.recv().await.unwrap().sequence, 1);
+        publish(&bus, 3).await.unwrap();
+        assert_eq!(behind.recv().await.unwrap().sequence, 3);
+    }
+
+    #[test]
+    fn queues_of_stopped_listeners_are_forgotten() {
+        let bus = InMemoryEventBus::new();
+        let running = bus.subscribe().unwrap();
+        drop(bus.subscribe().unwrap());
+
+        assert_eq!(bus.subscribers().unwrap().len(), 1);
+        drop(running);
+        assert!(bus.subscribers().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn stopped_listener_stops_receiving() {
+        let bus = InMemoryEventBus::new();
+        let listener = RecordingListener::default();
+        let handle = AsyncEventListenerContainer::start(bus.clone(), listener.clone()).unwrap();
+
+        handle.stop();
+        tokio::time::timeout(Duration::from_secs(1), handle.terminated())
+            .await
+            .unwrap()
+            .unwrap();
+        publish(&bus, 1).await.unwrap();
+        assert!(bus.subscribers().unwrap().is_empty());
+    }
+}
@@ -7,7 +7,8 @@ use std::fmt::{Debug, Display};
 use std::str::FromStr;
 
 /// Event is a domain envelope describing a change that has happened to an aggregate.
-#[derive(Debug, Clone, Serialize, Deserialize, derive_new::new)]
+#[allow(clippy::too_many_arguments)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, derive_new::new)]
 pub struct EventEnvelope<E: Event> {
     // ID of the aggregate that the envelope belongs to.
     pub aggregate_id: String,
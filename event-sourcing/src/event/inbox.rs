@@ -0,0 +1,193 @@
+use crate::event::envelope::EventEnvelope;
+use crate::event::listener::{AsyncEventListener, EventFilter};
+use crate::Error;
+use tracing::debug;
+
+/// The events each consumer has processed, identified by aggregate and sequence.
+#[allow(clippy::ptr_arg)]
+#[async_trait::async_trait]
+pub trait Inbox: Sized + Send + Sync {
+    // Whether the consumer has already processed the event of the aggregate at the sequence.
+    async fn contains(
+        &self,
+        consumer: &String,
+        aggregate_id: &String,
+        sequence: i64,
+    ) -> Result<bool, Error>;
+    // Record that the consumer has processed the event of the aggregate at the sequence.
+    async fn record(
+        &self,
+        consumer: &String,
+        aggregate_id: &String,
+        sequence: i64,
+    ) -> Result<(), Error>;
+}
+
+/// Wraps a listener so it only sees each event once, skipping the events its inbox already
+/// records as processed, e.g. when a bus redelivers them.
+///
+/// An event is recorded once the listener has handled it, so a consumer that stops in between
+/// sees it again. Listeners whose effects are stored in the same database as the inbox can
+/// record the event themselves, in the same write, to rule that out.
+pub struct InboxListener<L: AsyncEventListener, I: Inbox> {
+    listener: L,
+    inbox: I,
+    consumer: String,
+}
+
+impl<L: AsyncEventListener, I: Inbox> InboxListener<L, I> {
+    /// Records the events processed by the listener under the given consumer name, which must
+    /// be unique to the listener and stay the same across restarts.
+    pub fn new(listener: L, inbox: I, consumer: impl Into<String>) -> Self {
+        Self {
+            listener,
+            inbox,
+            consumer: consumer.into(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<L: AsyncEventListener, I: Inbox> AsyncEventListener for InboxListener<L, I> {
+    type Event = L::Event;
+    type Error = Error;
+
+    async fn on(&self, event_envelope: EventEnvelope<Self::Event>) -> Result<(), Self::Error> {
+        let aggregate_id = event_envelope.aggregate_id.clone();
+        let sequence = event_envelope.sequence;
+        if self
+            .inbox
+            .contains(&self.consumer, &aggregate_id, sequence)
+            .await?
+        {
+            debug!(
+                "{} already processed {}#{}, skipping it",
+                self.consumer, aggregate_id, sequence
+            );
+            return Ok(());
+        }
+        self.listener.on(event_envelope).await.map_err(Into::into)?;
+        self.inbox
+            .record(&self.consumer, &aggregate_id, sequence)
+            .await
+    }
+
+    fn filter(&self) -> EventFilter {
+        self.listener.filter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{event_envelope, RecordingListener};
+    use std::collections::HashSet;
+    use std::sync::{Arc, Mutex};
+
+    /// Keeps the processed events in memory.
+    #[derive(Clone, Default)]
+    struct MemoryInbox {
+        processed: Arc<Mutex<HashSet<(String, String, i64)>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl Inbox for MemoryInbox {
+        async fn contains(
+            &self,
+            consumer: &String,
+            aggregate_id: &String,
+            sequence: i64,
+        ) -> Result<bool, Error> {
+            let event = (consumer.clone(), aggregate_id.clone(), sequence);
+            Ok(self.processed.lock().unwrap().contains(&event))
+        }
+
+        async fn record(
+            &self,
+            consumer: &String,
+            aggregate_id: &String,
+            sequence: i64,
+        ) -> Result<(), Error> {
+            let event = (consumer.clone(), aggregate_id.clone(), sequence);
+            self.processed.lock().unwrap().insert(event);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn redelivered_event_is_skipped() {
+        let listener = RecordingListener::default()
+            .with_filter(EventFilter::default().with_aggregate_type("account"));
+        let inbox_listener = InboxListener::new(listener.clone(), MemoryInbox::default(), "audit");
+
+        for sequence in [1, 2, 1, 2, 3] {
+            inbox_listener
+                .on(event_envelope("account-1", sequence))
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(listener.sequences(), vec![1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn events_are_recorded_per_consumer_and_aggregate() {
+        let inbox = MemoryInbox::default();
+        let audit = RecordingListener::default()
+            .with_filter(EventFilter::default().with_aggregate_type("account"));
+        let projection = RecordingListener::default()
+            .with_filter(EventFilter::default().with_aggregate_type("account"));
+        let audit_listener = InboxListener::new(audit.clone(), inbox.clone(), "audit");
+        let projection_listener = InboxListener::new(projection.clone(), inbox, "projection");
+
+        for event_envelope in [
+            event_envelope("account-1", 1),
+            event_envelope("account-2", 1),
+        ] {
+            audit_listener.on(event_envelope.clone()).await.unwrap();
+            projection_listener.on(event_envelope).await.unwrap();
+        }
+
+        assert_eq!(audit.sequences(), vec![1, 1]);
+        assert_eq!(projection.sequences(), vec![1, 1]);
+    }
+
+    #[tokio::test]
+    async fn event_the_listener_failed_on_is_not_recorded() {
+        let inbox = MemoryInbox::default();
+        let listener = RecordingListener::default()
+            .with_filter(EventFilter::default().with_aggregate_type("account"));
+        let inbox_listener = InboxListener::new(listener.clone(), inbox.clone(), "audit");
+
+        listener.set_failing(true);
+        assert!(inbox_listener
+            .on(event_envelope("account-1", 1))
+            .await
+            .is_err());
+        assert!(!inbox
+            .contains(&String::from("audit"), &String::from("account-1"), 1)
+            .await
+            .unwrap());
+
+        listener.set_failing(false);
+        inbox_listener
+            .on(event_envelope("account-1", 1))
+            .await
+            .unwrap();
+        assert_eq!(listener.sequences(), vec![1]);
+    }
+
+    #[test]
+    fn filter_is_the_one_of_the_listener() {
+        let inbox_listener = InboxListener::new(
+            RecordingListener::default()
+                .with_filter(EventFilter::default().with_aggregate_type("account")),
+            MemoryInbox::default(),
+            "audit",
+        );
+        assert_eq!(
+            inbox_listener.filter(),
+            EventFilter::default().with_aggregate_type("account")
+        );
+    }
+}
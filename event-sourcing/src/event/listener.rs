@@ -1,14 +1,1096 @@
 use crate::event::envelope::EventEnvelope;
 use crate::event::Event;
+use crate::Error;
+use futures::channel::oneshot;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::fmt::{self, Display, Formatter};
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::{warn, Instrument, Span};
 
 pub trait EventListener: Sized + Send + Sync {
+    type Event: Event;
+    type Error: Into<Error> + Send + Sync;
+
+    fn on(&self, event_envelope: EventEnvelope<Self::Event>) -> Result<(), Self::Error>;
+
+    // Events delivered to the listener, all of them by default.
+    fn filter(&self) -> EventFilter {
+        EventFilter::default()
+    }
+}
+
+pub trait EventListenerContainer: Sized + Send + Sync + 'static {
     type Error: Send + Sync;
 
-    fn on<E: Event>(&self, event_envelope: EventEnvelope<E>) -> Result<(), Self::Error>;
+    // Deliver events to the listener, as well as to the listeners registered with the container,
+    // on a thread of its own until stopped through the returned handle.
+    fn start<L: EventListener + 'static>(
+        self,
+        event_listener: L,
+    ) -> Result<ContainerHandle, Self::Error>;
 }
 
-pub trait EventListenerContainer: Sized + Send + Sync {
+/// Listener for events whose handling awaits other services, e.g. a database or an API.
+#[async_trait::async_trait]
+pub trait AsyncEventListener: Sized + Send + Sync {
+    type Event: Event;
+    type Error: Into<Error> + Send + Sync;
+
+    async fn on(&self, event_envelope: EventEnvelope<Self::Event>) -> Result<(), Self::Error>;
+
+    // Events delivered to the listener, all of them by default.
+    fn filter(&self) -> EventFilter {
+        EventFilter::default()
+    }
+}
+
+/// Container delivering events to an [`AsyncEventListener`], running on the caller's runtime.
+pub trait AsyncEventListenerContainer: Sized + Send + Sync + 'static {
     type Error: Send + Sync;
 
-    fn start<L: EventListener>(&self, event_listener: L) -> Result<(), Self::Error>;
+    // Deliver events to the listener, as well as to the listeners registered with the container,
+    // on a task of the caller's runtime until stopped through the returned handle.
+    fn start<L: AsyncEventListener + 'static>(
+        self,
+        event_listener: L,
+    ) -> Result<ContainerHandle, Self::Error>;
+}
+
+/// Tells a running container to stop. Clones share the same state, so any of them can stop it.
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    stopped: Arc<watch::Sender<bool>>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self {
+            stopped: Arc::new(watch::channel(false).0),
+        }
+    }
+
+    pub fn stop(&self) {
+        self.stopped.send_replace(true);
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        *self.stopped.borrow()
+    }
+
+    /// Completes once the container has been told to stop.
+    pub async fn stopped(&self) {
+        let mut stopped = self.stopped.subscribe();
+        while !*stopped.borrow_and_update() {
+            if stopped.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A running container. Stopping it lets the container finish delivering the events it has
+/// read and save its progress before it terminates.
+pub struct ContainerHandle {
+    shutdown: Shutdown,
+    terminated: oneshot::Receiver<Result<(), Error>>,
+}
+
+impl ContainerHandle {
+    /// Run a container on a thread of its own.
+    pub fn spawn_thread(
+        run: impl FnOnce(Shutdown) -> Result<(), Error> + Send + 'static,
+    ) -> Result<Self, Error> {
+        let shutdown = Shutdown::new();
+        let (terminated, receiver) = oneshot::channel();
+        let container_shutdown = shutdown.clone();
+        std::thread::Builder::new()
+            .name(String::from("event-listener-container"))
+            .spawn(move || {
+                let _ = terminated.send(run(container_shutdown));
+            })?;
+        Ok(Self {
+            shutdown,
+            terminated: receiver,
+        })
+    }
+
+    /// Run a container on a task of the current tokio runtime.
+    pub fn spawn<F>(run: impl FnOnce(Shutdown) -> F) -> Self
+    where
+        F: Future<Output = Result<(), Error>> + Send + 'static,
+    {
+        let shutdown = Shutdown::new();
+        let (terminated, receiver) = oneshot::channel();
+        let container = run(shutdown.clone());
+        tokio::spawn(async move {
+            let _ = terminated.send(container.await);
+        });
+        Self {
+            shutdown,
+            terminated: receiver,
+        }
+    }
+
+    /// A shutdown that stops the container, e.g. to hand to another task.
+    pub fn shutdown(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+
+    pub fn stop(&self) {
+        self.shutdown.stop()
+    }
+
+    /// Completes once the container has terminated, either because it was stopped or because
+    /// it failed.
+    pub async fn terminated(self) -> Result<(), Error> {
+        Self::result(self.terminated.await)
+    }
+
+    /// Block until the container has terminated.
+    pub fn wait(self) -> Result<(), Error> {
+        futures::executor::block_on(self.terminated())
+    }
+
+    /// Stop the container once the process receives Ctrl-C or, on Unix, `SIGTERM`, and wait for
+    /// it to terminate. Returns early if the container terminates first.
+    pub async fn stop_on_signal(mut self) -> Result<(), Error> {
+        tokio::select! {
+            result = &mut self.terminated => return Self::result(result),
+            result = shutdown_signal() => result?,
+        }
+        self.stop();
+        self.terminated().await
+    }
+
+    fn result(terminated: Result<Result<(), Error>, oneshot::Canceled>) -> Result<(), Error> {
+        terminated.unwrap_or_else(|_| Err(Error::from("Event listener container panicked")))
+    }
+}
+
+#[cfg(unix)]
+async fn shutdown_signal() -> Result<(), Error> {
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => Ok(result?),
+        _ = terminate.recv() => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+async fn shutdown_signal() -> Result<(), Error> {
+    Ok(tokio::signal::ctrl_c().await?)
+}
+
+/// Runs a blocking [`EventListener`] where an [`AsyncEventListener`] is expected, on the task
+/// delivering the event, which the listener blocks while it handles the event. Listeners
+/// registered with [`EventListenerRegistry::with_listener`] run on the blocking thread pool
+/// instead.
+pub struct SyncEventListener<L: EventListener>(pub L);
+
+#[async_trait::async_trait]
+impl<L: EventListener> AsyncEventListener for SyncEventListener<L> {
+    type Event = L::Event;
+    type Error = L::Error;
+
+    async fn on(&self, event_envelope: EventEnvelope<Self::Event>) -> Result<(), Self::Error> {
+        self.0.on(event_envelope)
+    }
+
+    fn filter(&self) -> EventFilter {
+        self.0.filter()
+    }
+}
+
+/// Selects events by the type of their aggregate and by their own type. A filter without any
+/// aggregate type or event type matches every event.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EventFilter {
+    aggregate_types: HashSet<String>,
+    event_types: HashSet<String>,
+}
+
+impl EventFilter {
+    /// Also match events of aggregates of the given type.
+    pub fn with_aggregate_type(mut self, aggregate_type: impl Into<String>) -> Self {
+        self.aggregate_types.insert(aggregate_type.into());
+        self
+    }
+
+    /// Also match events of the given type.
+    pub fn with_event_type(mut self, event_type: impl Into<String>) -> Self {
+        self.event_types.insert(event_type.into());
+        self
+    }
+
+    pub fn matches(&self, aggregate_type: &str, event_type: &str) -> bool {
+        (self.aggregate_types.is_empty() || self.aggregate_types.contains(aggregate_type))
+            && (self.event_types.is_empty() || self.event_types.contains(event_type))
+    }
+}
+
+/// An event as read by a container, before it is deserialized into the event type of each
+/// listener it is routed to. Its type is only known from its envelope.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct RawEvent(pub Value);
+
+impl Event for RawEvent {
+    fn event_type(&self) -> String {
+        String::new()
+    }
+
+    fn revision(&self) -> i64 {
+        0
+    }
+}
+
+/// How often a listener is retried when it fails to handle an event, waiting twice as long
+/// before each attempt as before the previous one, up to `max_delay`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    /// Attempts made in total, including the first one, at least 1.
+    pub max_attempts: u32,
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Give up on an event as soon as a listener fails to handle it.
+    pub fn never() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Self::default()
+        }
+    }
+
+    fn delay(&self, attempt: u32) -> Duration {
+        self.initial_delay
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_delay)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(5),
+        }
+    }
+}
+
+/// The listeners of a container. Each event is routed to the listeners whose filter matches it,
+/// in the order they were registered, and deserialized into the event type of each of them.
+#[derive(Clone, Default)]
+pub struct EventListenerRegistry {
+    listeners: Vec<Arc<dyn RegisteredListener>>,
+    retry_policy: RetryPolicy,
+}
+
+impl EventListenerRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Deliver events to a blocking listener, on the blocking thread pool of the runtime, so
+    /// that it doesn't hold up the other lanes of a batch while it handles an event.
+    pub fn with_listener<L: EventListener + 'static>(self, event_listener: L) -> Self {
+        self.with_async_listener(BlockingListener(Arc::new(event_listener)))
+    }
+
+    pub fn with_async_listener<L: AsyncEventListener + 'static>(
+        mut self,
+        event_listener: L,
+    ) -> Self {
+        self.listeners.push(Arc::new(Registered {
+            filter: event_listener.filter(),
+            listener: event_listener,
+        }));
+        self
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.listeners.is_empty()
+    }
+
+    /// Deliver an event to every listener interested in it, one after the other. A listener
+    /// that fails is retried on its own, so the listeners before it don't see the event twice.
+    pub async fn dispatch(&self, event_envelope: &EventEnvelope<RawEvent>) -> Result<(), Error> {
+        for registered in &self.listeners {
+            if !registered
+                .filter()
+                .matches(&event_envelope.aggregate_type, &event_envelope.event_type)
+            {
+                continue;
+            }
+            let mut attempt = 1;
+            while let Err(error) = registered.on(event_envelope).await {
+                if attempt >= self.retry_policy.max_attempts {
+                    return Err(error);
+                }
+                warn!(
+                    "Event listener failed to process event {}#{} on attempt {}, retrying: {}",
+                    event_envelope.aggregate_id, event_envelope.sequence, attempt, error
+                );
+                tokio::time::sleep(self.retry_policy.delay(attempt)).await;
+                attempt += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Deliver a batch of events, each within its span, handling at most `concurrency` at a
+    /// time. Events of an aggregate are always handled one after the other in the order of the
+    /// batch, as they are assigned to the same lane. Lanes run as tasks of their own, so they
+    /// are handled in parallel on a multi-threaded runtime. When an event can't be handled, the
+    /// later events of its lane are skipped while the other lanes carry on.
+    ///
+    /// Returns the outcome of each event, in the order of the batch.
+    pub async fn deliver(
+        &self,
+        concurrency: usize,
+        batch: &[(EventEnvelope<RawEvent>, Span)],
+    ) -> Vec<DeliveryOutcome> {
+        let keys: Vec<u64> = batch
+            .iter()
+            .map(|(event_envelope, _)| aggregate_key(&event_envelope.aggregate_id))
+            .collect();
+        self.deliver_in_lanes(concurrency, batch, &keys).await
+    }
+
+    /// Deliver a batch of events like [`EventListenerRegistry::deliver`], assigning them to
+    /// lanes by the given key of each event instead of by aggregate, e.g. by the partition they
+    /// were read from. Events are only handled in order per aggregate when every event of an
+    /// aggregate has the same key.
+    ///
+    /// # Panics
+    ///
+    /// When there isn't a key for every event of the batch.
+    pub async fn deliver_in_lanes(
+        &self,
+        concurrency: usize,
+        batch: &[(EventEnvelope<RawEvent>, Span)],
+        keys: &[u64],
+    ) -> Vec<DeliveryOutcome> {
+        assert_eq!(
+            keys.len(),
+            batch.len(),
+            "every event of the batch needs a lane key"
+        );
+        let concurrency = concurrency.max(1);
+        let mut outcomes: Vec<DeliveryOutcome> =
+            batch.iter().map(|_| DeliveryOutcome::Skipped).collect();
+        let mut lanes: Vec<Lane> = (0..concurrency).map(|_| vec![]).collect();
+        for (index, ((event_envelope, span), key)) in batch.iter().zip(keys).enumerate() {
+            lanes[(key % concurrency as u64) as usize].push((
+                index,
+                event_envelope.clone(),
+                span.clone(),
+            ));
+        }
+        let tasks: Vec<_> = lanes
+            .into_iter()
+            .filter_map(|lane| {
+                let (index, event_envelope, _) = lane.first()?;
+                let first = (
+                    *index,
+                    event_envelope.aggregate_id.clone(),
+                    event_envelope.sequence,
+                );
+                let event_listeners = self.clone();
+                Some((
+                    first,
+                    tokio::spawn(async move { event_listeners.deliver_lane(lane).await }),
+                ))
+            })
+            .collect();
+        for ((index, aggregate_id, sequence), task) in tasks {
+            match task.await {
+                Ok(delivered) => {
+                    for (index, outcome) in delivered {
+                        outcomes[index] = outcome;
+                    }
+                }
+                // A listener panicked, so which events of the lane were handled is unknown.
+                Err(error) => {
+                    outcomes[index] = DeliveryOutcome::Failed(DeliveryError {
+                        aggregate_id,
+                        sequence,
+                        error: error.into(),
+                    })
+                }
+            }
+        }
+        outcomes
+    }
+
+    /// Deliver the events of a lane one after the other, up to the first that can't be handled.
+    async fn deliver_lane(&self, lane: Lane) -> Vec<(usize, DeliveryOutcome)> {
+        let mut delivered = vec![];
+        for (index, event_envelope, span) in lane {
+            match self.dispatch(&event_envelope).instrument(span).await {
+                Ok(()) => delivered.push((index, DeliveryOutcome::Delivered)),
+                Err(error) => {
+                    delivered.push((
+                        index,
+                        DeliveryOutcome::Failed(DeliveryError {
+                            aggregate_id: event_envelope.aggregate_id,
+                            sequence: event_envelope.sequence,
+                            error,
+                        }),
+                    ));
+                    break;
+                }
+            }
+        }
+        delivered
+    }
+}
+
+/// Events of a batch delivered one after the other, along with their index in the batch.
+type Lane = Vec<(usize, EventEnvelope<RawEvent>, Span)>;
+
+/// Key of the lane the events of an aggregate are delivered in.
+fn aggregate_key(aggregate_id: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    aggregate_id.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// What became of an event of a delivered batch.
+#[derive(Debug)]
+pub enum DeliveryOutcome {
+    Delivered,
+    // A listener failed to handle the event, even once retried.
+    Failed(DeliveryError),
+    // The event wasn't handled, as an earlier event of its lane failed.
+    Skipped,
+}
+
+impl DeliveryOutcome {
+    pub fn is_delivered(&self) -> bool {
+        matches!(self, DeliveryOutcome::Delivered)
+    }
+
+    /// The first failure of a batch, if any.
+    pub fn first_failure(outcomes: Vec<DeliveryOutcome>) -> Option<DeliveryError> {
+        outcomes.into_iter().find_map(|outcome| match outcome {
+            DeliveryOutcome::Failed(error) => Some(error),
+            _ => None,
+        })
+    }
+}
+
+/// An event a listener failed to handle.
+#[derive(Debug)]
+pub struct DeliveryError {
+    pub aggregate_id: String,
+    pub sequence: i64,
+    pub error: Error,
+}
+
+impl Display for DeliveryError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Event listener failed to process event {}#{}: {}",
+            self.aggregate_id, self.sequence, self.error
+        )
+    }
+}
+
+impl std::error::Error for DeliveryError {}
+
+/// Runs a blocking listener of a registry on the blocking thread pool.
+struct BlockingListener<L: EventListener>(Arc<L>);
+
+#[async_trait::async_trait]
+impl<L: EventListener + 'static> AsyncEventListener for BlockingListener<L> {
+    type Event = L::Event;
+    type Error = Error;
+
+    async fn on(&self, event_envelope: EventEnvelope<Self::Event>) -> Result<(), Self::Error> {
+        let listener = self.0.clone();
+        tokio::task::spawn_blocking(move || listener.on(event_envelope).map_err(Into::into)).await?
+    }
+
+    fn filter(&self) -> EventFilter {
+        self.0.filter()
+    }
+}
+
+/// A listener of a registry, with its event type erased.
+#[async_trait::async_trait]
+trait RegisteredListener: Send + Sync {
+    fn filter(&self) -> &EventFilter;
+
+    async fn on(&self, event_envelope: &EventEnvelope<RawEvent>) -> Result<(), Error>;
+}
+
+struct Registered<L: AsyncEventListener> {
+    listener: L,
+    filter: EventFilter,
+}
+
+#[async_trait::async_trait]
+impl<L: AsyncEventListener> RegisteredListener for Registered<L> {
+    fn filter(&self) -> &EventFilter {
+        &self.filter
+    }
+
+    async fn on(&self, event_envelope: &EventEnvelope<RawEvent>) -> Result<(), Error> {
+        let event = serde_json::from_value(event_envelope.event.0.clone())?;
+        self.listener
+            .on(EventEnvelope::new(
+                event_envelope.aggregate_id.clone(),
+                event_envelope.aggregate_type.clone(),
+                event,
+                event_envelope.event_type.clone(),
+                event_envelope.event_time,
+                event_envelope.sequence,
+                event_envelope.revision,
+                event_envelope.metadata.clone(),
+            ))
+            .await
+            .map_err(Into::into)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{event_envelope, RecordingListener};
+    use serde_json::json;
+    use std::sync::Mutex;
+
+    fn batch(events: &[(&str, i64)]) -> Vec<(EventEnvelope<RawEvent>, Span)> {
+        events
+            .iter()
+            .map(|(aggregate_id, sequence)| (event_envelope(aggregate_id, *sequence), Span::none()))
+            .collect()
+    }
+
+    /// A listener taking a while for each event, so that events of other lanes get a chance to
+    /// be handled in between.
+    fn slow_listener() -> RecordingListener {
+        RecordingListener::default().with_delay(Duration::from_millis(5))
+    }
+
+    /// Two aggregates whose events are delivered in different lanes with the concurrency.
+    fn aggregates_in_different_lanes(concurrency: u64) -> (&'static str, &'static str) {
+        let lane = |aggregate_id: &str| aggregate_key(aggregate_id) % concurrency;
+        let first = "account-0";
+        let second = [
+            "account-1",
+            "account-2",
+            "account-3",
+            "account-4",
+            "account-5",
+        ]
+        .into_iter()
+        .find(|aggregate_id| lane(aggregate_id) != lane(first))
+        .unwrap();
+        (first, second)
+    }
+
+    #[tokio::test]
+    async fn events_of_an_aggregate_are_delivered_in_order() {
+        let listener = slow_listener();
+        let registry = EventListenerRegistry::new().with_async_listener(listener.clone());
+        let (first, second) = aggregates_in_different_lanes(4);
+        let events: Vec<(&str, i64)> = (1..=5)
+            .flat_map(|sequence| [(first, sequence), (second, sequence)])
+            .collect();
+
+        let outcomes = registry.deliver(4, &batch(&events)).await;
+
+        assert!(outcomes.iter().all(DeliveryOutcome::is_delivered));
+        assert_eq!(listener.sequences_of(first), vec![1, 2, 3, 4, 5]);
+        assert_eq!(listener.sequences_of(second), vec![1, 2, 3, 4, 5]);
+    }
+
+    #[tokio::test]
+    async fn events_of_other_aggregates_interleave() {
+        let listener = slow_listener();
+        let registry = EventListenerRegistry::new().with_async_listener(listener.clone());
+        let (first, second) = aggregates_in_different_lanes(2);
+        // Every event of the first aggregate comes before the ones of the second in the batch.
+        let events: Vec<(&str, i64)> = (1..=5)
+            .map(|sequence| (first, sequence))
+            .chain((1..=5).map(|sequence| (second, sequence)))
+            .collect();
+
+        registry.deliver(2, &batch(&events)).await;
+
+        let received = listener.events();
+        let last_of_first = received.iter().rposition(|(id, _)| id == first).unwrap();
+        let first_of_second = received.iter().position(|(id, _)| id == second).unwrap();
+        assert!(first_of_second < last_of_first, "{received:?}");
+    }
+
+    #[tokio::test]
+    async fn events_are_delivered_in_batch_order_without_concurrency() {
+        let listener = slow_listener();
+        let registry = EventListenerRegistry::new().with_async_listener(listener.clone());
+        let events = [("account-1", 1), ("account-2", 1), ("account-1", 2)];
+
+        registry.deliver(1, &batch(&events)).await;
+
+        let expected: Vec<(String, i64)> = events
+            .iter()
+            .map(|(aggregate_id, sequence)| (aggregate_id.to_string(), *sequence))
+            .collect();
+        assert_eq!(listener.events(), expected);
+    }
+
+    #[tokio::test]
+    async fn failed_event_skips_the_rest_of_its_lane_only() {
+        let (first, second) = aggregates_in_different_lanes(2);
+        let listener = RecordingListener::failing_on(first, 2).with_delay(Duration::from_millis(5));
+        let registry = EventListenerRegistry::new()
+            .with_async_listener(listener.clone())
+            .with_retry_policy(RetryPolicy::never());
+        let events = [(first, 1), (second, 1), (first, 2), (second, 2), (first, 3)];
+
+        let outcomes = registry.deliver(2, &batch(&events)).await;
+
+        assert!(outcomes[0].is_delivered());
+        assert!(outcomes[1].is_delivered());
+        assert!(matches!(&outcomes[2], DeliveryOutcome::Failed(error) if error.sequence == 2));
+        assert!(outcomes[3].is_delivered());
+        assert!(matches!(outcomes[4], DeliveryOutcome::Skipped));
+        assert_eq!(listener.sequences_of(first), vec![1]);
+        assert_eq!(listener.sequences_of(second), vec![1, 2]);
+    }
+
+    #[test]
+    fn empty_filter_matches_every_event() {
+        let filter = EventFilter::default();
+        assert!(filter.matches("account", "Deposited"));
+        assert!(filter.matches("customer", "Registered"));
+    }
+
+    #[test]
+    fn filter_matches_any_of_its_aggregate_types() {
+        let filter = EventFilter::default()
+            .with_aggregate_type("account")
+            .with_aggregate_type("customer");
+        assert!(filter.matches("account", "Deposited"));
+        assert!(filter.matches("customer", "Registered"));
+        assert!(!filter.matches("order", "Placed"));
+    }
+
+    #[test]
+    fn filter_matches_any_of_its_event_types() {
+        let filter = EventFilter::default()
+            .with_event_type("Deposited")
+            .with_event_type("Withdrawn");
+        assert!(filter.matches("account", "Deposited"));
+        assert!(filter.matches("wallet", "Withdrawn"));
+        assert!(!filter.matches("account", "Opened"));
+    }
+
+    #[test]
+    fn filter_with_both_matches_events_satisfying_each() {
+        let filter = EventFilter::default()
+            .with_aggregate_type("account")
+            .with_event_type("Deposited");
+        assert!(filter.matches("account", "Deposited"));
+        assert!(!filter.matches("account", "Withdrawn"));
+        assert!(!filter.matches("wallet", "Deposited"));
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    enum AccountEvent {
+        Deposited { amount: i64 },
+    }
+
+    impl Event for AccountEvent {
+        fn event_type(&self) -> String {
+            String::from("Deposited")
+        }
+
+        fn revision(&self) -> i64 {
+            1
+        }
+    }
+
+    /// Appends its name and the event it received to a log shared with the other listeners.
+    struct NamedListener<E> {
+        name: &'static str,
+        filter: EventFilter,
+        log: Arc<Mutex<Vec<(&'static str, E)>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl<E: Event + Send + Sync + 'static> AsyncEventListener for NamedListener<E> {
+        type Event = E;
+        type Error = Error;
+
+        async fn on(&self, event_envelope: EventEnvelope<E>) -> Result<(), Self::Error> {
+            self.log
+                .lock()
+                .unwrap()
+                .push((self.name, event_envelope.event));
+            Ok(())
+        }
+
+        fn filter(&self) -> EventFilter {
+            self.filter.clone()
+        }
+    }
+
+    #[tokio::test]
+    async fn event_is_routed_to_matching_listeners_in_registration_order() {
+        let log: Arc<Mutex<Vec<(&str, RawEvent)>>> = Arc::new(Mutex::new(vec![]));
+        let listener = |name, filter| NamedListener {
+            name,
+            filter,
+            log: log.clone(),
+        };
+        let registry = EventListenerRegistry::new()
+            .with_async_listener(listener("second", EventFilter::default()))
+            .with_async_listener(listener(
+                "customers",
+                EventFilter::default().with_aggregate_type("customer"),
+            ))
+            .with_async_listener(listener(
+                "first",
+                EventFilter::default().with_event_type("Deposited"),
+            ));
+
+        registry
+            .dispatch(&event_envelope("account-1", 1))
+            .await
+            .unwrap();
+
+        let names: Vec<&str> = log.lock().unwrap().iter().map(|(name, _)| *name).collect();
+        assert_eq!(names, vec!["second", "first"]);
+    }
+
+    #[tokio::test]
+    async fn event_is_deserialized_into_the_event_of_each_listener() {
+        let typed = Arc::new(Mutex::new(vec![]));
+        let raw = Arc::new(Mutex::new(vec![]));
+        let registry = EventListenerRegistry::new()
+            .with_async_listener(NamedListener {
+                name: "typed",
+                filter: EventFilter::default(),
+                log: typed.clone(),
+            })
+            .with_async_listener(NamedListener {
+                name: "raw",
+                filter: EventFilter::default(),
+                log: raw.clone(),
+            });
+
+        registry
+            .dispatch(&event_envelope("account-1", 100))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            *typed.lock().unwrap(),
+            vec![("typed", AccountEvent::Deposited { amount: 100 })]
+        );
+        assert_eq!(
+            *raw.lock().unwrap(),
+            vec![("raw", RawEvent(json!({ "Deposited": { "amount": 100 } })))]
+        );
+    }
+
+    #[tokio::test]
+    async fn matched_event_failing_to_deserialize_fails_the_dispatch() {
+        let log: Arc<Mutex<Vec<(&str, AccountEvent)>>> = Arc::new(Mutex::new(vec![]));
+        let registry = EventListenerRegistry::new()
+            .with_async_listener(NamedListener {
+                name: "typed",
+                filter: EventFilter::default(),
+                log: log.clone(),
+            })
+            .with_retry_policy(RetryPolicy::never());
+        let mut event_envelope = event_envelope("account-1", 1);
+        event_envelope.event = RawEvent(json!({ "Withdrawn": { "amount": 1 } }));
+
+        let error = registry.dispatch(&event_envelope).await.unwrap_err();
+
+        assert!(
+            error.downcast_ref::<serde_json::Error>().is_some(),
+            "{error}"
+        );
+        assert!(log.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn unmatched_event_is_not_deserialized() {
+        let log: Arc<Mutex<Vec<(&str, AccountEvent)>>> = Arc::new(Mutex::new(vec![]));
+        let registry = EventListenerRegistry::new().with_async_listener(NamedListener {
+            name: "typed",
+            filter: EventFilter::default().with_event_type("Deposited"),
+            log: log.clone(),
+        });
+        let mut event_envelope = event_envelope("account-1", 1);
+        event_envelope.event = RawEvent(json!({ "Withdrawn": { "amount": 1 } }));
+        event_envelope.event_type = String::from("Withdrawn");
+
+        registry.dispatch(&event_envelope).await.unwrap();
+
+        assert!(log.lock().unwrap().is_empty());
+    }
+
+    /// A container running until it is stopped.
+    async fn run_until_stopped(shutdown: Shutdown) -> Result<(), Error> {
+        shutdown.stopped().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stopped_container_terminates() {
+        let handle = ContainerHandle::spawn(run_until_stopped);
+        handle.stop();
+        handle.terminated().await.unwrap();
+    }
+
+    #[test]
+    fn stopped_container_thread_terminates() {
+        let handle = ContainerHandle::spawn_thread(|shutdown| {
+            futures::executor::block_on(run_until_stopped(shutdown))
+        })
+        .unwrap();
+        handle.stop();
+        handle.wait().unwrap();
+    }
+
+    #[tokio::test]
+    async fn stopped_resolves_when_stopped_before_it_is_awaited() {
+        let shutdown = Shutdown::new();
+        let stopped = shutdown.stopped();
+        shutdown.stop();
+        tokio::time::timeout(Duration::from_secs(1), stopped)
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(1), shutdown.clone().stopped())
+            .await
+            .unwrap();
+        assert!(shutdown.is_stopped());
+    }
+
+    #[tokio::test]
+    async fn failing_container_terminates_with_its_error() {
+        let handle = ContainerHandle::spawn(|_| async { Err(Error::from("broker unreachable")) });
+        let error = handle.terminated().await.unwrap_err();
+        assert_eq!(error.to_string(), "broker unreachable");
+    }
+
+    #[tokio::test]
+    async fn panicking_container_terminates_with_an_error() {
+        let handle = ContainerHandle::spawn(|_| async { panic!("listener panicked") });
+        let error = handle.terminated().await.unwrap_err();
+        assert_eq!(error.to_string(), "Event listener container panicked");
+    }
+
+    #[test]
+    fn panicking_container_thread_terminates_with_an_error() {
+        let handle = ContainerHandle::spawn_thread(|_| panic!("listener panicked")).unwrap();
+        let error = handle.wait().unwrap_err();
+        assert_eq!(error.to_string(), "Event listener container panicked");
+    }
+
+    /// Counts its calls, failing until it has been called a number of times.
+    #[derive(Clone, Default)]
+    struct FlakyListener {
+        calls: Arc<Mutex<u32>>,
+        failures: u32,
+    }
+
+    impl FlakyListener {
+        fn calls(&self) -> u32 {
+            *self.calls.lock().unwrap()
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl AsyncEventListener for FlakyListener {
+        type Event = RawEvent;
+        type Error = Error;
+
+        async fn on(&self, _event_envelope: EventEnvelope<RawEvent>) -> Result<(), Self::Error> {
+            let mut calls = self.calls.lock().unwrap();
+            *calls += 1;
+            match *calls <= self.failures {
+                true => Err(Error::from("listener failed")),
+                false => Ok(()),
+            }
+        }
+    }
+
+    fn retry_policy(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            initial_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(1),
+        }
+    }
+
+    #[tokio::test]
+    async fn failing_listener_is_retried_without_the_listeners_before_it() {
+        let before = FlakyListener::default();
+        let flaky = FlakyListener {
+            failures: 2,
+            ..FlakyListener::default()
+        };
+        let registry = EventListenerRegistry::new()
+            .with_async_listener(before.clone())
+            .with_async_listener(flaky.clone())
+            .with_retry_policy(retry_policy(3));
+
+        registry
+            .dispatch(&event_envelope("account-1", 1))
+            .await
+            .unwrap();
+
+        assert_eq!(before.calls(), 1);
+        assert_eq!(flaky.calls(), 3);
+    }
+
+    #[tokio::test]
+    async fn listener_failing_every_attempt_fails_the_dispatch() {
+        let before = FlakyListener::default();
+        let failing = FlakyListener {
+            failures: u32::MAX,
+            ..FlakyListener::default()
+        };
+        let after = FlakyListener::default();
+        let registry = EventListenerRegistry::new()
+            .with_async_listener(before.clone())
+            .with_async_listener(failing.clone())
+            .with_async_listener(after.clone())
+            .with_retry_policy(retry_policy(4));
+
+        let error = registry
+            .dispatch(&event_envelope("account-1", 1))
+            .await
+            .unwrap_err();
+
+        assert_eq!(error.to_string(), "listener failed");
+        assert_eq!(before.calls(), 1);
+        assert_eq!(failing.calls(), 4);
+        assert_eq!(after.calls(), 0);
+    }
+
+    #[test]
+    fn retry_delay_doubles_up_to_the_maximum() {
+        let retry_policy = RetryPolicy {
+            max_attempts: 5,
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(300),
+        };
+        let delays: Vec<Duration> = (1..=4).map(|attempt| retry_policy.delay(attempt)).collect();
+        assert_eq!(
+            delays,
+            [100, 200, 300, 300].map(Duration::from_millis).to_vec()
+        );
+    }
+
+    /// Blocks until as many events as it expects are being handled at the same time, or gives
+    /// up after a while, recording whether they were.
+    struct RendezvousListener {
+        expected: usize,
+        handling: Arc<(Mutex<usize>, std::sync::Condvar)>,
+        met: Arc<Mutex<Vec<bool>>>,
+    }
+
+    impl EventListener for RendezvousListener {
+        type Event = RawEvent;
+        type Error = Error;
+
+        fn on(&self, _event_envelope: EventEnvelope<RawEvent>) -> Result<(), Self::Error> {
+            let (handling, condvar) = &*self.handling;
+            let mut handling = handling.lock().unwrap();
+            *handling += 1;
+            condvar.notify_all();
+            let (handling, _) = condvar
+                .wait_timeout_while(handling, Duration::from_secs(5), |handling| {
+                    *handling < self.expected
+                })
+                .unwrap();
+            self.met.lock().unwrap().push(*handling >= self.expected);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn blocking_listeners_of_different_lanes_run_in_parallel() {
+        let met = Arc::new(Mutex::new(vec![]));
+        let registry = EventListenerRegistry::new().with_listener(RendezvousListener {
+            expected: 2,
+            handling: Arc::default(),
+            met: met.clone(),
+        });
+        let (first, second) = aggregates_in_different_lanes(2);
+
+        let outcomes = registry
+            .deliver(2, &batch(&[(first, 1), (second, 1)]))
+            .await;
+
+        assert!(outcomes.iter().all(DeliveryOutcome::is_delivered));
+        assert_eq!(*met.lock().unwrap(), vec![true, true]);
+    }
+
+    /// Panics on the events of an aggregate.
+    struct PanickingListener(&'static str);
+
+    #[async_trait::async_trait]
+    impl AsyncEventListener for PanickingListener {
+        type Event = RawEvent;
+        type Error = Error;
+
+        async fn on(&self, event_envelope: EventEnvelope<RawEvent>) -> Result<(), Self::Error> {
+            if event_envelope.aggregate_id == self.0 {
+                panic!("listener panicked");
+            }
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn panicking_lane_fails_without_stopping_the_others() {
+        let (first, second) = aggregates_in_different_lanes(2);
+        let registry = EventListenerRegistry::new().with_async_listener(PanickingListener(first));
+
+        let outcomes = registry
+            .deliver(2, &batch(&[(first, 1), (second, 1), (first, 2)]))
+            .await;
+
+        assert!(matches!(&outcomes[0], DeliveryOutcome::Failed(error) if error.sequence == 1));
+        assert!(outcomes[1].is_delivered());
+        assert!(matches!(outcomes[2], DeliveryOutcome::Skipped));
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "every event of the batch needs a lane key")]
+    async fn delivering_without_a_key_for_every_event_panics() {
+        EventListenerRegistry::new()
+            .deliver_in_lanes(2, &batch(&[("account-1", 1), ("account-1", 2)]), &[0])
+            .await;
+    }
 }
@@ -0,0 +1,5 @@
+//! Envelopes as the messages of a bus, in the format the Scylla CDC source connector publishes
+//! the events table in, so buses other than Kafka carry events the same way.
+
+pub mod decode;
+pub mod encode;
@@ -0,0 +1,383 @@
+use crate::codec::Codecs;
+use crate::event::envelope::EventEnvelope;
+use crate::event::Event;
+use crate::Error;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::{DateTime, Duration, Utc};
+use custom_error::custom_error;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+
+custom_error! {pub DecodeError
+    MissingField{field: &'static str} = "missing field {field}",
+    InvalidField{field: &'static str} = "invalid field {field}",
+}
+
+/// Decode a message produced by the Scylla CDC source connector from the events table into an
+/// envelope. Returns `None` for messages that don't carry an appended event, such as tombstones
+/// and deletes.
+///
+/// Rows are expected as flattened by the `ExtractField` and `ScyllaExtractNewRecordState`
+/// transforms, serialized by the `JsonConverter` with or without schemas. Unflattened Debezium
+/// envelopes and cells still wrapped in `{"value": ...}` are accepted as well.
+pub fn decode<E: Event>(codecs: &Codecs, value: &[u8]) -> Result<Option<EventEnvelope<E>>, Error> {
+    if value.is_empty() {
+        return Ok(None);
+    }
+    let mut record: Value = serde_json::from_slice(value)?;
+    // Schemas enabled in the converter wrap the row in a schema envelope.
+    if record.get("schema").is_some() {
+        record = record["payload"].take();
+    }
+    // Without the transforms the row is the after image of a Debezium envelope.
+    if let Some(op) = record.get("op") {
+        if op != "c" && op != "r" {
+            return Ok(None);
+        }
+        record = record["after"].take();
+    }
+    let record = match record {
+        Value::Object(record) => record,
+        Value::Null => return Ok(None),
+        _ => return Err(DecodeError::InvalidField { field: "after" }.into()),
+    };
+
+    let event_time = timestamp(&record, "event_time")?
+        + Duration::nanoseconds(optional_i64(&record, "event_time_nanos")?.unwrap_or_default());
+    let event = match (
+        optional_string(&record, "payload")?,
+        optional_string(&record, "content_type")?,
+    ) {
+        (Some(payload), Some(content_type)) => codecs.decode(
+            &content_type,
+            &STANDARD
+                .decode(payload)
+                .map_err(|_| DecodeError::InvalidField { field: "payload" })?,
+        )?,
+        _ => match field(&record, "event") {
+            Some(Value::String(event)) => serde_json::from_str(event)?,
+            Some(event @ Value::Object(_)) => serde_json::from_value(event.clone())?,
+            _ => return Err(DecodeError::MissingField { field: "event" }.into()),
+        },
+    };
+    Ok(Some(EventEnvelope::new(
+        string(&record, "aggregate_id")?,
+        string(&record, "aggregate_type")?,
+        event,
+        string(&record, "event_type")?,
+        event_time,
+        i64(&record, "sequence")?,
+        i64(&record, "revision")?,
+        map(field(&record, "metadata").unwrap_or(&Value::Null))
+            .ok_or(DecodeError::InvalidField { field: "metadata" })?,
+    )))
+}
+
+/// Value of a column, unwrapping the `{"value": ...}` cell of rows that weren't flattened.
+fn field<'a>(record: &'a Map<String, Value>, name: &'static str) -> Option<&'a Value> {
+    match record.get(name)? {
+        Value::Object(cell) if cell.len() == 1 && cell.contains_key("value") => cell.get("value"),
+        value => Some(value),
+    }
+    .filter(|value| !value.is_null())
+}
+
+fn optional_string(
+    record: &Map<String, Value>,
+    name: &'static str,
+) -> Result<Option<String>, Error> {
+    match field(record, name) {
+        Some(Value::String(value)) => Ok(Some(value.clone())),
+        Some(_) => Err(DecodeError::InvalidField { field: name }.into()),
+        None => Ok(None),
+    }
+}
+
+fn string(record: &Map<String, Value>, name: &'static str) -> Result<String, Error> {
+    optional_string(record, name)?.ok_or(DecodeError::MissingField { field: name }.into())
+}
+
+fn optional_i64(record: &Map<String, Value>, name: &'static str) -> Result<Option<i64>, Error> {
+    match field(record, name) {
+        Some(value) => Ok(Some(
+            value
+                .as_i64()
+                .ok_or(DecodeError::InvalidField { field: name })?,
+        )),
+        None => Ok(None),
+    }
+}
+
+fn i64(record: &Map<String, Value>, name: &'static str) -> Result<i64, Error> {
+    optional_i64(record, name)?.ok_or(DecodeError::MissingField { field: name }.into())
+}
+
+/// A timestamp column, as milliseconds since the epoch or as an RFC 3339 string.
+fn timestamp(record: &Map<String, Value>, name: &'static str) -> Result<DateTime<Utc>, Error> {
+    let invalid = DecodeError::InvalidField { field: name };
+    match field(record, name) {
+        Some(Value::Number(millis)) => millis
+            .as_i64()
+            .and_then(DateTime::<Utc>::from_timestamp_millis)
+            .ok_or(invalid.into()),
+        Some(Value::String(time)) => Ok(DateTime::parse_from_rfc3339(time)
+            .map_err(|_| invalid)?
+            .with_timezone(&Utc)),
+        Some(_) => Err(invalid.into()),
+        None => Err(DecodeError::MissingField { field: name }.into()),
+    }
+}
+
+/// A map column, either as an object, as a list of key and value entries, or as the elements
+/// of a collection cell.
+fn map(value: &Value) -> Option<HashMap<String, String>> {
+    match value {
+        Value::Null => Some(HashMap::new()),
+        Value::Object(entries) if entries.values().all(Value::is_string) => Some(
+            entries
+                .iter()
+                .map(|(key, value)| (key.clone(), value.as_str().unwrap_or_default().to_string()))
+                .collect(),
+        ),
+        Value::Object(cell) if cell.contains_key("elements") => map(&cell["elements"]),
+        Value::Object(cell) if cell.len() == 1 && cell.contains_key("value") => map(&cell["value"]),
+        Value::Array(entries) => entries
+            .iter()
+            .map(|entry| match entry {
+                Value::Object(entry) => Some((
+                    entry.get("key")?.as_str()?.to_string(),
+                    entry.get("value")?.as_str()?.to_string(),
+                )),
+                Value::Array(entry) if entry.len() == 2 => Some((
+                    entry[0].as_str()?.to_string(),
+                    entry[1].as_str()?.to_string(),
+                )),
+                _ => None,
+            })
+            .collect(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::CodecError;
+    use crate::event::listener::RawEvent;
+    use serde_json::json;
+
+    /// A row of the events table as flattened by the transforms.
+    fn row() -> Value {
+        json!({
+            "aggregate_id": "account-1",
+            "aggregate_type": "account",
+            "event": "{\"Deposited\":{\"amount\":100}}",
+            "event_type": "Deposited",
+            "event_time": 1_700_000_000_123i64,
+            "sequence": 1,
+            "revision": 1,
+            "metadata": {"correlation_id": "c-1"},
+        })
+    }
+
+    /// The row with its column replaced.
+    fn with(mut row: Value, column: &str, value: Value) -> Value {
+        row[column] = value;
+        row
+    }
+
+    fn expected() -> EventEnvelope<RawEvent> {
+        EventEnvelope::new(
+            String::from("account-1"),
+            String::from("account"),
+            RawEvent(json!({"Deposited": {"amount": 100}})),
+            String::from("Deposited"),
+            DateTime::from_timestamp_millis(1_700_000_000_123).unwrap(),
+            1,
+            1,
+            HashMap::from([(String::from("correlation_id"), String::from("c-1"))]),
+        )
+    }
+
+    fn decode_json(value: &Value) -> Result<Option<EventEnvelope<RawEvent>>, Error> {
+        decode(&Codecs::default(), &serde_json::to_vec(value).unwrap())
+    }
+
+    #[test]
+    fn accepted_shapes_decode_to_the_envelope() {
+        let cells: Map<String, Value> = row()
+            .as_object()
+            .unwrap()
+            .iter()
+            .map(|(column, value)| (column.clone(), json!({ "value": value })))
+            .collect();
+        let cases = [
+            ("flattened row", row(), expected()),
+            (
+                "schema envelope",
+                json!({"schema": {"type": "struct"}, "payload": row()}),
+                expected(),
+            ),
+            (
+                "debezium create",
+                json!({"op": "c", "after": row()}),
+                expected(),
+            ),
+            (
+                "debezium read",
+                json!({"op": "r", "after": row()}),
+                expected(),
+            ),
+            ("value cells", Value::Object(cells), expected()),
+            (
+                "event as object",
+                with(row(), "event", json!({"Deposited": {"amount": 100}})),
+                expected(),
+            ),
+            (
+                "metadata as key and value entries",
+                with(
+                    row(),
+                    "metadata",
+                    json!([{"key": "correlation_id", "value": "c-1"}]),
+                ),
+                expected(),
+            ),
+            (
+                "metadata as pairs",
+                with(row(), "metadata", json!([["correlation_id", "c-1"]])),
+                expected(),
+            ),
+            (
+                "metadata as collection elements",
+                with(
+                    row(),
+                    "metadata",
+                    json!({"elements": [{"key": "correlation_id", "value": "c-1"}]}),
+                ),
+                expected(),
+            ),
+            (
+                "without metadata",
+                with(row(), "metadata", Value::Null),
+                EventEnvelope {
+                    metadata: HashMap::new(),
+                    ..expected()
+                },
+            ),
+            (
+                "base64 payload",
+                with(
+                    with(
+                        row(),
+                        "payload",
+                        json!(STANDARD.encode(b"{\"Deposited\":{\"amount\":100}}")),
+                    ),
+                    "content_type",
+                    json!("application/json"),
+                ),
+                expected(),
+            ),
+            (
+                "nanos column",
+                with(row(), "event_time_nanos", json!(456)),
+                EventEnvelope {
+                    event_time: expected().event_time + Duration::nanoseconds(456),
+                    ..expected()
+                },
+            ),
+            (
+                "RFC 3339 event time",
+                with(row(), "event_time", json!("2023-11-14T22:13:20.123Z")),
+                expected(),
+            ),
+        ];
+        for (name, value, expected) in cases {
+            assert_eq!(decode_json(&value).unwrap(), Some(expected), "{name}");
+        }
+    }
+
+    #[test]
+    fn messages_without_an_appended_event_decode_to_none() {
+        let cases = [
+            ("tombstone", vec![]),
+            (
+                "delete",
+                serde_json::to_vec(&json!({"op": "d", "before": row(), "after": null})).unwrap(),
+            ),
+            (
+                "update",
+                serde_json::to_vec(&json!({"op": "u", "after": row()})).unwrap(),
+            ),
+            (
+                "no after image",
+                serde_json::to_vec(&json!({"op": "c", "after": null})).unwrap(),
+            ),
+        ];
+        for (name, value) in cases {
+            let decoded = decode::<RawEvent>(&Codecs::default(), &value).unwrap();
+            assert_eq!(decoded, None, "{name}");
+        }
+    }
+
+    /// Whether a decode error is the expected one.
+    type ErrorMatcher = fn(&Error) -> bool;
+
+    #[test]
+    fn malformed_messages_fail_to_decode() {
+        let payload = |payload: &str, content_type: &str| {
+            with(
+                with(row(), "payload", json!(payload)),
+                "content_type",
+                json!(content_type),
+            )
+        };
+        let cases: [(&str, Value, ErrorMatcher); 4] = [
+            (
+                "bad base64",
+                payload("not base64!", "application/json"),
+                |error| {
+                    matches!(
+                        error.downcast_ref::<DecodeError>(),
+                        Some(DecodeError::InvalidField { field: "payload" })
+                    )
+                },
+            ),
+            (
+                "unknown content type",
+                payload(&STANDARD.encode(b"{}"), "application/cbor"),
+                |error| {
+                    matches!(
+                        error.downcast_ref::<CodecError>(),
+                        Some(CodecError::UnknownContentType { .. })
+                    )
+                },
+            ),
+            (
+                "missing column",
+                with(row(), "sequence", Value::Null),
+                |error| {
+                    matches!(
+                        error.downcast_ref::<DecodeError>(),
+                        Some(DecodeError::MissingField { field: "sequence" })
+                    )
+                },
+            ),
+            (
+                "invalid metadata",
+                with(row(), "metadata", json!(42)),
+                |error| {
+                    matches!(
+                        error.downcast_ref::<DecodeError>(),
+                        Some(DecodeError::InvalidField { field: "metadata" })
+                    )
+                },
+            ),
+        ];
+        for (name, value, expected) in cases {
+            let error = decode_json(&value).unwrap_err();
+            assert!(expected(&error), "{name}: {error}");
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use crate::codec::Codecs;
+use crate::event::envelope::EventEnvelope;
+use crate::event::Event;
+use crate::Error;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::SecondsFormat;
+use serde_json::json;
+
+/// Encode an envelope as a message in the format of the Scylla CDC source connector, so it can
+/// be consumed by the same containers as the events published through CDC.
+pub fn encode<E: Event>(
+    codecs: &Codecs,
+    event_envelope: &EventEnvelope<E>,
+) -> Result<Vec<u8>, Error> {
+    Ok(serde_json::to_vec(&json!({
+        "aggregate_id": event_envelope.aggregate_id,
+        "aggregate_type": event_envelope.aggregate_type,
+        "event_type": event_envelope.event_type,
+        "event_time": event_envelope
+            .event_time
+            .to_rfc3339_opts(SecondsFormat::AutoSi, true),
+        "sequence": event_envelope.sequence,
+        "revision": event_envelope.revision,
+        "metadata": event_envelope.metadata,
+        "payload": STANDARD.encode(codecs.encode(&event_envelope.event)?),
+        "content_type": codecs.content_type(),
+    }))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::{Codec, JsonCodec};
+    use crate::event::message::decode::decode;
+    use chrono::{TimeZone, Utc};
+    use serde::{Deserialize, Serialize};
+    use std::collections::HashMap;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    enum AccountEvent {
+        Deposited { amount: i128 },
+    }
+
+    impl Event for AccountEvent {
+        fn event_type(&self) -> String {
+            String::from("Deposited")
+        }
+
+        fn revision(&self) -> i64 {
+            1
+        }
+    }
+
+    /// JSON written back to front, standing in for a binary format.
+    #[derive(Debug)]
+    struct ReversedJsonCodec;
+
+    impl Codec for ReversedJsonCodec {
+        fn content_type(&self) -> &str {
+            "application/x-reversed-json"
+        }
+
+        fn encode(&self, json: &[u8]) -> Result<Vec<u8>, Error> {
+            Ok(json.iter().rev().copied().collect())
+        }
+
+        fn decode(&self, payload: &[u8]) -> Result<Vec<u8>, Error> {
+            Ok(payload.iter().rev().copied().collect())
+        }
+    }
+
+    fn event_envelope() -> EventEnvelope<AccountEvent> {
+        EventEnvelope::new(
+            String::from("account-1"),
+            String::from("account"),
+            AccountEvent::Deposited { amount: i128::MAX },
+            String::from("Deposited"),
+            Utc.timestamp_opt(1_700_000_000, 123_456_789).unwrap(),
+            7,
+            2,
+            HashMap::from([
+                (String::from("correlation_id"), String::from("c-1")),
+                (String::from("traceparent"), String::from("00-abc-def-01")),
+            ]),
+        )
+    }
+
+    #[test]
+    fn encoded_envelope_decodes_equal() {
+        for codecs in [Codecs::default(), Codecs::new(ReversedJsonCodec)] {
+            let event_envelope = event_envelope();
+            let encoded = encode(&codecs, &event_envelope).unwrap();
+            assert_eq!(
+                decode(&codecs, &encoded).unwrap(),
+                Some(event_envelope),
+                "{}",
+                codecs.content_type()
+            );
+        }
+    }
+
+    #[test]
+    fn envelope_encoded_with_a_replaced_codec_decodes_equal() {
+        let encoded = encode(&Codecs::default(), &event_envelope()).unwrap();
+        let codecs = Codecs::new(ReversedJsonCodec).with_codec(JsonCodec);
+        assert_eq!(decode(&codecs, &encoded).unwrap(), Some(event_envelope()));
+    }
+}
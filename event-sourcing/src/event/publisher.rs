@@ -0,0 +1,13 @@
+use crate::Error;
+
+use crate::event::envelope::EventEnvelope;
+use crate::event::Event;
+
+#[async_trait::async_trait]
+pub trait EventPublisher<E>: Sized + Send + Sync + Clone
+where
+    E: Event,
+{
+    // Publish the event, after every event of the aggregate published before it.
+    async fn publish(&self, event_envelope: &EventEnvelope<E>) -> Result<(), Error>;
+}
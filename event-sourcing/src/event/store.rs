@@ -8,6 +8,7 @@ custom_error! {pub EventStoreError
     Concurrency = "concurrency error",
 }
 
+#[allow(clippy::ptr_arg)]
 #[async_trait::async_trait]
 pub trait EventStore<E>: Sized + Send + Sync + Clone
 where
@@ -1,9 +1,13 @@
 pub mod aggregate;
+pub mod codec;
 pub mod command;
 pub mod command_handler;
 pub mod event;
 pub mod query_handler;
 pub mod snapshot;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
+pub mod trace;
 
 extern crate custom_error;
 
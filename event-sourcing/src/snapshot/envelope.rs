@@ -5,7 +5,7 @@ use serde::{de, Deserialize, Deserializer, Serialize};
 use std::fmt::{Debug, Display};
 use std::str::FromStr;
 
-#[derive(Debug, Clone, Serialize, Deserialize, derive_new::new)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, derive_new::new)]
 pub struct SnapshotEnvelope<A: Aggregate> {
     // ID of the aggregate that the envelope belongs to.
     pub aggregate_id: String,
@@ -19,6 +19,8 @@ pub struct SnapshotEnvelope<A: Aggregate> {
     pub state_time: DateTime<Utc>,
     // Location in a sequence of events that has been captured.
     pub sequence: i64,
+    // Revision of the state, see Aggregate::revision.
+    pub revision: i64,
 }
 
 fn deserialize_state<'de, S, D>(deserializer: D) -> Result<S, D::Error>
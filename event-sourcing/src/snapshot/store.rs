@@ -3,11 +3,22 @@ use crate::Error;
 use crate::aggregate::Aggregate;
 use crate::snapshot::envelope::SnapshotEnvelope;
 
+#[allow(clippy::ptr_arg)]
 #[async_trait::async_trait]
 pub trait SnapshotStore<A>: Sized + Send + Sync + Clone
 where
     A: Aggregate,
 {
+    // Fetch the latest snapshot of the aggregate.
     async fn read(&self, aggregate_id: &String) -> Result<Option<SnapshotEnvelope<A>>, Error>;
+    // Fetch the latest snapshot of the aggregate taken on or before the specified sequence.
+    async fn read_at(
+        &self,
+        aggregate_id: &String,
+        max_sequence: i64,
+    ) -> Result<Option<SnapshotEnvelope<A>>, Error>;
+    // Fetch every stored snapshot of the aggregate in order of sequence.
+    async fn list(&self, aggregate_id: &String) -> Result<Vec<SnapshotEnvelope<A>>, Error>;
+    // Persist the snapshot of the aggregate.
     async fn persist(&self, snapshot_envelope: &SnapshotEnvelope<A>) -> Result<(), Error>;
 }
@@ -0,0 +1,186 @@
+//! Fixtures shared by the tests of the listeners, buses and stores. Other crates use them by
+//! depending on this one with the `test-support` feature in their dev-dependencies.
+
+use crate::event::envelope::EventEnvelope;
+use crate::event::listener::{AsyncEventListener, EventFilter, EventListener, RawEvent};
+use crate::event::publisher::EventPublisher;
+use crate::event::Event;
+use crate::Error;
+use chrono::Utc;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The event of an `account` aggregate at a sequence, depositing the sequence as amount.
+pub fn event_envelope(aggregate_id: &str, sequence: i64) -> EventEnvelope<RawEvent> {
+    EventEnvelope::new(
+        String::from(aggregate_id),
+        String::from("account"),
+        RawEvent(json!({ "Deposited": { "amount": sequence } })),
+        String::from("Deposited"),
+        Utc::now(),
+        sequence,
+        1,
+        HashMap::new(),
+    )
+}
+
+/// Records every event it handles, failing on a given event or while told to fail. As an async
+/// listener, it can take a while for each event so that events of other lanes get a chance to
+/// be handled in between.
+#[derive(Clone)]
+pub struct RecordingListener {
+    received: Arc<Mutex<Vec<EventEnvelope<RawEvent>>>>,
+    fail_on: Option<(String, i64)>,
+    failing: Arc<AtomicBool>,
+    delay: Duration,
+    filter: EventFilter,
+}
+
+impl Default for RecordingListener {
+    fn default() -> Self {
+        Self {
+            received: Arc::default(),
+            fail_on: None,
+            failing: Arc::default(),
+            delay: Duration::ZERO,
+            filter: EventFilter::default(),
+        }
+    }
+}
+
+impl RecordingListener {
+    /// A listener failing on the event of the aggregate at the sequence.
+    pub fn failing_on(aggregate_id: &str, sequence: i64) -> Self {
+        Self {
+            fail_on: Some((String::from(aggregate_id), sequence)),
+            ..Self::default()
+        }
+    }
+
+    /// Time taken to handle each event as an async listener.
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    pub fn with_filter(mut self, filter: EventFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Fail on every event until told otherwise.
+    pub fn set_failing(&self, failing: bool) {
+        self.failing.store(failing, Ordering::SeqCst);
+    }
+
+    pub fn received(&self) -> Vec<EventEnvelope<RawEvent>> {
+        self.received.lock().unwrap().clone()
+    }
+
+    /// Aggregate ID and sequence of the events received, in the order they were handled.
+    pub fn events(&self) -> Vec<(String, i64)> {
+        self.received()
+            .into_iter()
+            .map(|event_envelope| (event_envelope.aggregate_id, event_envelope.sequence))
+            .collect()
+    }
+
+    /// Sequences received, in the order they were handled.
+    pub fn sequences(&self) -> Vec<i64> {
+        self.events()
+            .into_iter()
+            .map(|(_, sequence)| sequence)
+            .collect()
+    }
+
+    /// Sequences received for the aggregate, in the order they were handled.
+    pub fn sequences_of(&self, aggregate_id: &str) -> Vec<i64> {
+        self.events()
+            .into_iter()
+            .filter(|(id, _)| id == aggregate_id)
+            .map(|(_, sequence)| sequence)
+            .collect()
+    }
+
+    /// Wait until the listener has received the number of events, or give up after a while.
+    /// Returns the sequences received.
+    pub async fn wait_for(&self, count: usize) -> Vec<i64> {
+        for _ in 0..1000 {
+            if self.received.lock().unwrap().len() >= count {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        self.sequences()
+    }
+
+    fn record(&self, event_envelope: EventEnvelope<RawEvent>) -> Result<(), Error> {
+        let event = (event_envelope.aggregate_id.clone(), event_envelope.sequence);
+        if self.failing.load(Ordering::SeqCst) || self.fail_on.as_ref() == Some(&event) {
+            return Err(Error::from("listener failed"));
+        }
+        self.received.lock().unwrap().push(event_envelope);
+        Ok(())
+    }
+}
+
+impl EventListener for RecordingListener {
+    type Event = RawEvent;
+    type Error = Error;
+
+    fn on(&self, event_envelope: EventEnvelope<RawEvent>) -> Result<(), Self::Error> {
+        self.record(event_envelope)
+    }
+
+    fn filter(&self) -> EventFilter {
+        self.filter.clone()
+    }
+}
+
+#[async_trait::async_trait]
+impl AsyncEventListener for RecordingListener {
+    type Event = RawEvent;
+    type Error = Error;
+
+    async fn on(&self, event_envelope: EventEnvelope<RawEvent>) -> Result<(), Self::Error> {
+        if !self.delay.is_zero() {
+            tokio::time::sleep(self.delay).await;
+        }
+        self.record(event_envelope)
+    }
+
+    fn filter(&self) -> EventFilter {
+        self.filter.clone()
+    }
+}
+
+/// Keeps every event it publishes.
+#[derive(Clone)]
+pub struct RecordingPublisher<E: Event> {
+    published: Arc<Mutex<Vec<EventEnvelope<E>>>>,
+}
+
+impl<E: Event> Default for RecordingPublisher<E> {
+    fn default() -> Self {
+        Self {
+            published: Arc::default(),
+        }
+    }
+}
+
+impl<E: Event> RecordingPublisher<E> {
+    pub fn published(&self) -> Vec<EventEnvelope<E>> {
+        self.published.lock().unwrap().clone()
+    }
+}
+
+#[async_trait::async_trait]
+impl<E: Event> EventPublisher<E> for RecordingPublisher<E> {
+    async fn publish(&self, event_envelope: &EventEnvelope<E>) -> Result<(), Error> {
+        self.published.lock().unwrap().push(event_envelope.clone());
+        Ok(())
+    }
+}
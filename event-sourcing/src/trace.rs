@@ -0,0 +1,26 @@
+use opentelemetry::propagation::TextMapPropagator;
+use opentelemetry::Context;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use std::collections::HashMap;
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// Metadata key carrying the W3C `traceparent` of the span that produced an event.
+pub const TRACEPARENT: &str = "traceparent";
+/// Metadata key carrying the W3C `tracestate` of the span that produced an event.
+pub const TRACESTATE: &str = "tracestate";
+
+/// Write the W3C trace context of the current span into the metadata of an event.
+pub fn inject_context(metadata: &mut HashMap<String, String>) {
+    TraceContextPropagator::new().inject_context(&Span::current().context(), metadata);
+}
+
+/// Read the W3C trace context out of the metadata of an event.
+pub fn extract_context(metadata: &HashMap<String, String>) -> Context {
+    TraceContextPropagator::new().extract(metadata)
+}
+
+/// Continue the trace recorded in the metadata of an event on the given span.
+pub fn set_parent(span: &Span, metadata: &HashMap<String, String>) {
+    span.set_parent(extract_context(metadata));
+}
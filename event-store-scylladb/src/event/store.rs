@@ -1,4 +1,5 @@
-use chrono::{DateTime, NaiveDateTime, Utc};
+use chrono::{DateTime, Utc};
+use event_sourcing::codec::Codecs;
 use event_sourcing::event::envelope::EventEnvelope;
 use event_sourcing::event::store::EventStore;
 use event_sourcing::event::Event;
@@ -6,131 +7,347 @@ use event_sourcing::Error;
 
 use std::collections::HashMap;
 
-use crate::{query, ScyllaDbConnection};
+use crate::{metrics, outbox, query, time, ScyllaDbConnection};
 use event_sourcing::event::store::EventStoreError::Concurrency;
 use scylla::frame::response::result::Row;
-use scylla::{IntoTypedRows, Session, SessionBuilder};
+use scylla::frame::value::{MaybeUnset, ValueList};
+use std::time::Instant;
+
+/// Columns selected from the events table, or its CDC log, in the order expected by
+/// [`ScyllaDbEventStore::map_event_envelope`].
+///
+/// Events appended before payloads were stored as blobs have their JSON in the `event` column and
+/// no `payload`; newer events leave `event` unset.
+pub const EVENT_COLUMNS: &str = "aggregate_id, aggregate_type, event, event_type, event_time, sequence, revision, metadata, event_time_nanos, payload, content_type";
+
+/// Row of the events table, as bound by [`ScyllaDbEventStore`] when appending an event.
+type EventValues = (
+    String,
+    String,
+    MaybeUnset<String>,
+    String,
+    DateTime<Utc>,
+    i64,
+    i64,
+    HashMap<String, String>,
+    i32,
+    Vec<u8>,
+    String,
+);
 
 #[derive(Debug, Clone)]
 pub struct ScyllaDbEventStore {
     pub connection: ScyllaDbConnection,
+    aggregate_type: Option<String>,
+}
+
+impl ScyllaDbEventStore {
+    pub fn new(connection: ScyllaDbConnection) -> Self {
+        Self {
+            connection,
+            aggregate_type: None,
+        }
+    }
+
+    /// Aggregate type the store is dedicated to, labelling the metrics of reads that fail before
+    /// any event tells which aggregate type was read.
+    pub fn with_aggregate_type(mut self, aggregate_type: impl Into<String>) -> Self {
+        self.aggregate_type = Some(aggregate_type.into());
+        self
+    }
 }
 
 #[async_trait::async_trait]
 impl<E: Event> EventStore<E> for ScyllaDbEventStore {
+    #[tracing::instrument(
+        name = "scylladb.read_events",
+        skip_all,
+        fields(aggregate_id = %aggregate_id),
+        err
+    )]
     async fn read(&self, aggregate_id: &String) -> Result<Vec<EventEnvelope<E>>, Error> {
-        let session: Session = SessionBuilder::new()
-            .known_node(&self.connection.host)
-            .build()
-            .await?;
-        match session
-            .query(query::READ_EVENTS, [aggregate_id].as_ref())
-            .await?
-            .rows
-        {
-            Some(rows) => Self::map_event_envelope(rows),
-            None => Ok(vec![]),
-        }
+        let started = Instant::now();
+        let rows = self
+            .select_rows(query::read_events(&self.connection), (aggregate_id,))
+            .await;
+        self.map_events("read_events", started, rows)
     }
 
+    #[tracing::instrument(
+        name = "scylladb.read_events_from",
+        skip_all,
+        fields(aggregate_id = %aggregate_id, sequence = sequence),
+        err
+    )]
     async fn read_from(
         &self,
         aggregate_id: &String,
         sequence: i64,
     ) -> Result<Vec<EventEnvelope<E>>, Error> {
-        let session: Session = SessionBuilder::new()
-            .known_node(&self.connection.host)
-            .build()
+        let started = Instant::now();
+        let rows = self
+            .select_rows(
+                query::read_events_from(&self.connection),
+                (aggregate_id, sequence),
+            )
+            .await;
+        self.map_events("read_events_from", started, rows)
+    }
+
+    #[tracing::instrument(
+        name = "scylladb.insert_event",
+        skip_all,
+        fields(
+            aggregate_id = %event_envelope.aggregate_id,
+            aggregate_type = %event_envelope.aggregate_type,
+            sequence = event_envelope.sequence,
+        ),
+        err
+    )]
+    async fn persist(&self, event_envelope: &EventEnvelope<E>) -> Result<(), Error> {
+        let started = Instant::now();
+        let result = self.insert_event(event_envelope).await;
+        metrics::record_query(
+            "insert_event",
+            &event_envelope.aggregate_type,
+            started,
+            &result,
+        );
+        result
+    }
+}
+
+impl ScyllaDbEventStore {
+    async fn select_rows(&self, query: String, values: impl ValueList) -> Result<Vec<Row>, Error> {
+        let session = self.connection.session().await?;
+        let statement = self
+            .connection
+            .prepare(&query, self.connection.consistency.read_events)
             .await?;
-        match session
-            .query(query::READ_EVENTS_FROM, (aggregate_id, sequence))
+        Ok(session
+            .execute(&statement, values)
             .await?
             .rows
-        {
-            Some(rows) => Self::map_event_envelope(rows),
-            None => Ok(vec![]),
+            .unwrap_or_default())
+    }
+
+    /// Build envelopes from the rows of a read, recording the read.
+    fn map_events<E: Event>(
+        &self,
+        operation: &'static str,
+        started: Instant,
+        rows: Result<Vec<Row>, Error>,
+    ) -> Result<Vec<EventEnvelope<E>>, Error> {
+        let aggregate_type = metrics::read_aggregate_type(&rows, self.aggregate_type.as_deref());
+        let result = rows.and_then(|rows| {
+            rows.into_iter()
+                .map(|row| Self::map_event_envelope(self.connection.codecs(), row))
+                .collect::<Result<Vec<_>, Error>>()
+        });
+        metrics::record_query(operation, &aggregate_type, started, &result);
+        if let Ok(event_envelopes) = &result {
+            metrics::record_events_replayed(&aggregate_type, event_envelopes.len());
         }
+        result
     }
 
-    async fn persist(&self, event_envelope: &EventEnvelope<E>) -> Result<(), Error> {
-        let session: Session = SessionBuilder::new()
-            .known_node(&self.connection.host)
-            .build()
+    async fn insert_event<E: Event>(&self, event_envelope: &EventEnvelope<E>) -> Result<(), Error> {
+        if self.connection.outbox {
+            outbox::record(
+                &self.connection,
+                &event_envelope.aggregate_id,
+                event_envelope.sequence,
+            )
             .await?;
-        match session
-            .query(
-                query::INSERT_EVENT,
-                (
-                    &event_envelope.aggregate_id,
-                    &event_envelope.aggregate_type,
-                    &serde_json::to_string(&event_envelope.event)?,
-                    &event_envelope.event_type,
-                    &event_envelope.event_time.timestamp_millis(),
-                    &event_envelope.sequence,
-                    &event_envelope.revision,
-                    &event_envelope.metadata,
-                ),
+        }
+        let session = self.connection.session().await?;
+        let statement = self
+            .connection
+            .prepare(
+                &query::insert_event(&self.connection),
+                self.connection.consistency.append_events,
             )
-            .await?
-            .rows
-        {
-            Some(rows) => rows
-                .into_typed::<(
-                    bool,
-                    Option<String>,
-                    Option<i64>,
-                    Option<String>,
-                    Option<String>,
-                    Option<i64>,
-                    Option<String>,
-                    Option<HashMap<String, String>>,
-                    Option<i64>,
-                )>()
-                .try_for_each(|row| {
-                    let applied: bool = row?.0;
-                    match applied {
-                        true => Ok(()),
-                        false => Err(Concurrency.into()),
-                    }
-                }),
-            None => Ok(()),
+            .await?;
+        let result = session
+            .execute(
+                &statement,
+                Self::event_values(self.connection.codecs(), event_envelope)?,
+            )
+            .await?;
+        match query::applied(result) {
+            true => Ok(()),
+            false => Err(Concurrency.into()),
         }
     }
-}
 
-impl ScyllaDbEventStore {
-    fn map_event_envelope<E: Event>(rows: Vec<Row>) -> Result<Vec<EventEnvelope<E>>, Error> {
-        rows.into_typed::<(
-            String,
+    /// Values bound to the insert statement, in the order of [`EVENT_COLUMNS`].
+    fn event_values<E: Event>(
+        codecs: &Codecs,
+        event_envelope: &EventEnvelope<E>,
+    ) -> Result<EventValues, Error> {
+        let (event_time, event_time_nanos) = time::split(event_envelope.event_time);
+        Ok((
+            event_envelope.aggregate_id.clone(),
+            event_envelope.aggregate_type.clone(),
+            MaybeUnset::Unset,
+            event_envelope.event_type.clone(),
+            event_time,
+            event_envelope.sequence,
+            event_envelope.revision,
+            event_envelope.metadata.clone(),
+            event_time_nanos,
+            codecs.encode(&event_envelope.event)?,
+            codecs.content_type().to_string(),
+        ))
+    }
+
+    /// Build an envelope from a row holding the [`EVENT_COLUMNS`].
+    pub fn map_event_envelope<E: Event>(
+        codecs: &Codecs,
+        row: Row,
+    ) -> Result<EventEnvelope<E>, Error> {
+        let (
+            agg_id,
+            agg_type,
+            event,
+            event_type,
+            event_time,
+            sequence,
+            revision,
+            metadata,
+            event_time_nanos,
+            payload,
+            content_type,
+        ) = row.into_typed::<(
             String,
             String,
+            Option<String>,
             String,
-            i64,
+            DateTime<Utc>,
             i64,
             i64,
             Option<HashMap<String, String>>,
-        )>()
-        .map(|row| {
-            let (agg_id, agg_type, event, event_type, event_time, sequence, revision, metadata) =
-                row?;
-            let ts_secs = event_time / 1000;
-            let ts_ns = (event_time % 1000) * 1_000_000;
-            let date_time = DateTime::<Utc>::from_utc(
-                NaiveDateTime::from_timestamp_opt(ts_secs, ts_ns as u32)
-                    .ok_or(Error::from("Failed to get timestamp"))?,
-                Utc,
+            Option<i32>,
+            Option<Vec<u8>>,
+            Option<String>,
+        )>()?;
+        let event = match (payload, content_type, event) {
+            (Some(payload), Some(content_type), _) => codecs.decode(&content_type, &payload)?,
+            (_, _, Some(event)) => serde_json::from_str(&event)?,
+            _ => return Err(Error::from("Event has no payload")),
+        };
+        Ok(EventEnvelope::new(
+            agg_id,
+            agg_type,
+            event,
+            event_type,
+            time::join(event_time, event_time_nanos),
+            sequence,
+            revision,
+            metadata.unwrap_or(HashMap::new()),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use scylla::frame::response::result::{ColumnType, CqlValue};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    enum AccountEvent {
+        Deposited { amount: i64 },
+    }
+
+    impl Event for AccountEvent {
+        fn event_type(&self) -> String {
+            String::from("Deposited")
+        }
+
+        fn revision(&self) -> i64 {
+            1
+        }
+    }
+
+    /// Column types of the [`EVENT_COLUMNS`].
+    fn event_column_types() -> Vec<ColumnType> {
+        vec![
+            ColumnType::Text,
+            ColumnType::Text,
+            ColumnType::Text,
+            ColumnType::Text,
+            ColumnType::Timestamp,
+            ColumnType::BigInt,
+            ColumnType::BigInt,
+            ColumnType::Map(Box::new(ColumnType::Text), Box::new(ColumnType::Text)),
+            ColumnType::Int,
+            ColumnType::Blob,
+            ColumnType::Text,
+        ]
+    }
+
+    fn event_envelope(event_time: DateTime<Utc>) -> EventEnvelope<AccountEvent> {
+        EventEnvelope::new(
+            String::from("account-1"),
+            String::from("account"),
+            AccountEvent::Deposited { amount: 100 },
+            String::from("Deposited"),
+            event_time,
+            3,
+            1,
+            HashMap::from([(String::from("correlation-id"), String::from("42"))]),
+        )
+    }
+
+    #[test]
+    fn persisted_event_reads_back_equal() {
+        for event_time in [
+            Utc.timestamp_opt(1_700_000_000, 123_456_789).unwrap(),
+            Utc.timestamp_opt(1_700_000_000, 999_999_999).unwrap(),
+            Utc::now(),
+        ] {
+            let event_envelope = event_envelope(event_time);
+            let row = query::bind_and_read(
+                ScyllaDbEventStore::event_values(&Codecs::default(), &event_envelope).unwrap(),
+                &event_column_types(),
             );
-            Ok(EventEnvelope::new(
-                agg_id,
-                agg_type,
-                serde_json::from_str(&event)?,
-                event_type,
-                date_time,
-                sequence,
-                revision,
-                metadata.unwrap_or(HashMap::new()),
-            ))
-        })
-        .collect()
+            let read = ScyllaDbEventStore::map_event_envelope(&Codecs::default(), row).unwrap();
+            assert_eq!(read, event_envelope);
+        }
+    }
+
+    #[test]
+    fn event_written_before_nanos_were_stored_reads_at_millisecond_precision() {
+        let event_envelope = event_envelope(Utc.timestamp_opt(1_700_000_000, 123_456_789).unwrap());
+        let mut row = query::bind_and_read(
+            ScyllaDbEventStore::event_values(&Codecs::default(), &event_envelope).unwrap(),
+            &event_column_types(),
+        );
+        row.columns[8] = None;
+        let read = ScyllaDbEventStore::map_event_envelope::<AccountEvent>(&Codecs::default(), row)
+            .unwrap();
+        assert_eq!(
+            read.event_time,
+            Utc.timestamp_opt(1_700_000_000, 123_000_000).unwrap()
+        );
+    }
+
+    #[test]
+    fn event_appended_before_payloads_were_blobs_reads_from_text() {
+        let event_envelope = event_envelope(Utc.timestamp_opt(1_700_000_000, 0).unwrap());
+        let mut row = query::bind_and_read(
+            ScyllaDbEventStore::event_values(&Codecs::default(), &event_envelope).unwrap(),
+            &event_column_types(),
+        );
+        row.columns[2] = Some(CqlValue::Text(
+            serde_json::to_string(&event_envelope.event).unwrap(),
+        ));
+        row.columns[8] = None;
+        row.columns[9] = None;
+        row.columns[10] = None;
+        let read = ScyllaDbEventStore::map_event_envelope(&Codecs::default(), row).unwrap();
+        assert_eq!(read, event_envelope);
     }
 }
@@ -0,0 +1,75 @@
+use crate::{query, time, ScyllaDbConnection};
+use chrono::Utc;
+use event_sourcing::event::inbox::Inbox;
+use event_sourcing::Error;
+
+/// Records the events processed by each consumer in the inbox table, partitioned by consumer
+/// and aggregate.
+#[derive(Debug, Clone)]
+pub struct ScyllaDbInbox {
+    pub connection: ScyllaDbConnection,
+}
+
+#[async_trait::async_trait]
+impl Inbox for ScyllaDbInbox {
+    #[tracing::instrument(
+        name = "scylladb.read_inbox",
+        skip_all,
+        fields(consumer = %consumer, aggregate_id = %aggregate_id, sequence = sequence),
+        err
+    )]
+    async fn contains(
+        &self,
+        consumer: &String,
+        aggregate_id: &String,
+        sequence: i64,
+    ) -> Result<bool, Error> {
+        let session = self.connection.session().await?;
+        let statement = self
+            .connection
+            .prepare(
+                &query::read_inbox_entry(&self.connection),
+                self.connection.consistency.read_events,
+            )
+            .await?;
+        let result = session
+            .execute(&statement, (consumer, aggregate_id, sequence))
+            .await?;
+        Ok(result.rows.is_some_and(|rows| !rows.is_empty()))
+    }
+
+    #[tracing::instrument(
+        name = "scylladb.insert_inbox",
+        skip_all,
+        fields(consumer = %consumer, aggregate_id = %aggregate_id, sequence = sequence),
+        err
+    )]
+    async fn record(
+        &self,
+        consumer: &String,
+        aggregate_id: &String,
+        sequence: i64,
+    ) -> Result<(), Error> {
+        let session = self.connection.session().await?;
+        let statement = self
+            .connection
+            .prepare(
+                &query::insert_inbox_entry(&self.connection),
+                self.connection.consistency.append_events,
+            )
+            .await?;
+        let ttl = self
+            .connection
+            .inbox_ttl
+            .map(time::ttl_seconds)
+            .transpose()?
+            .unwrap_or(0);
+        session
+            .execute(
+                &statement,
+                (consumer, aggregate_id, sequence, Utc::now(), ttl),
+            )
+            .await?;
+        Ok(())
+    }
+}
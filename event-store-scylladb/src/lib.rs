@@ -1,32 +1,372 @@
+use event_sourcing::codec::Codecs;
 use event_sourcing::Error;
-use futures::executor;
-use log::error;
-use retry::delay::Fixed;
-use retry::retry;
-use scylla::{Session, SessionBuilder};
+use openssl::ssl::{SslContext, SslContextBuilder, SslFiletype, SslMethod, SslVerifyMode};
+use scylla::load_balancing::DefaultPolicy;
+use scylla::prepared_statement::PreparedStatement;
+use scylla::statement::{Consistency, SerialConsistency};
+use scylla::transport::session::PoolSize;
+use scylla::{ExecutionProfile, Session, SessionBuilder};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{self, Debug, Formatter};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{OnceCell, RwLock};
 
 pub mod event;
+pub mod inbox;
+pub(crate) mod metrics;
+pub mod migration;
+pub mod outbox;
 pub(crate) mod query;
 pub mod snapshot;
+pub(crate) mod time;
 
+pub use query::{identifier, table};
+
+/// Replication strategy of the keyspace created by the migrations.
+#[derive(Debug, Clone)]
+pub enum Replication {
+    SimpleStrategy { replication_factor: i8 },
+    // Replication factor per datacenter.
+    NetworkTopologyStrategy { datacenters: BTreeMap<String, i8> },
+}
+
+/// Consistency level used by each operation of the stores.
+#[derive(Debug, Clone, Copy)]
+pub struct ConsistencyLevels {
+    pub read_events: Consistency,
+    pub append_events: Consistency,
+    pub read_snapshot: Consistency,
+    pub write_snapshot: Consistency,
+    // Consistency of the Paxos phase of the conditional inserts.
+    pub serial: SerialConsistency,
+}
+
+impl Default for ConsistencyLevels {
+    fn default() -> Self {
+        Self {
+            read_events: Consistency::LocalQuorum,
+            append_events: Consistency::LocalQuorum,
+            read_snapshot: Consistency::LocalQuorum,
+            write_snapshot: Consistency::LocalQuorum,
+            serial: SerialConsistency::LocalSerial,
+        }
+    }
+}
+
+/// Which snapshots of an aggregate the snapshot store keeps. Snapshots are only a shortcut to
+/// the state of an aggregate, so the ones that are removed are rebuilt from the events.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SnapshotRetention {
+    /// Number of newest snapshots kept per aggregate, at least 1. Older snapshots are deleted
+    /// whenever a snapshot is persisted. Every snapshot is kept when unset.
+    pub keep_last: Option<u32>,
+    /// Time after which a snapshot expires once a newer one was persisted, so the latest snapshot
+    /// of an aggregate never expires. Snapshots never expire when unset. Scylla takes TTLs
+    /// between 1 second and 20 years, in whole seconds.
+    pub ttl: Option<Duration>,
+}
+
+/// TLS settings for the connections to the cluster. Files are expected in PEM format.
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    /// Certificate authority used to verify the nodes.
+    pub ca_file: PathBuf,
+    /// Client certificate and private key, when the cluster requires client authentication.
+    pub cert_file: Option<PathBuf>,
+    pub key_file: Option<PathBuf>,
+}
+
+/// Username and password for the cluster's password authenticator.
+#[derive(Clone)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl Debug for Credentials {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .field("password", &"***")
+            .finish()
+    }
+}
+
+/// Connection settings for a ScyllaDB cluster. The session is established on first use and
+/// shared by every clone of the connection, so the stores and migrations reuse the same pool.
 #[derive(Debug, Clone)]
 pub struct ScyllaDbConnection {
-    pub host: String,
-    pub replication_factor: i8,
+    hosts: Vec<String>,
+    tls: Option<TlsConfig>,
+    credentials: Option<Credentials>,
+    local_datacenter: Option<String>,
+    keyspace: String,
+    events_table: String,
+    snapshots_table: String,
+    outbox_table: String,
+    outbox: bool,
+    inbox_table: String,
+    inbox_ttl: Option<Duration>,
+    replication: Replication,
+    consistency: ConsistencyLevels,
+    codecs: Codecs,
+    snapshot_retention: SnapshotRetention,
+    pool_size: PoolSize,
+    connection_timeout: Duration,
+    request_timeout: Option<Duration>,
+    session: Arc<OnceCell<Arc<Session>>>,
+    statements: Arc<RwLock<HashMap<String, PreparedStatement>>>,
 }
 
-pub async fn run_migration(connection: &ScyllaDbConnection) -> Result<(), Error> {
-    let session: Session = retry(Fixed::from_millis(1000), || {
-        let result = executor::block_on(SessionBuilder::new().known_node(&connection.host).build());
-        if result.is_err() {
-            error!("Failed to run migration: {:?}", result)
+impl ScyllaDbConnection {
+    pub fn new(host: impl Into<String>, replication_factor: i8) -> Self {
+        Self {
+            hosts: vec![host.into()],
+            tls: None,
+            credentials: None,
+            local_datacenter: None,
+            keyspace: String::from("event_store"),
+            events_table: String::from("events"),
+            snapshots_table: String::from("snapshots"),
+            outbox_table: String::from("outbox"),
+            outbox: false,
+            inbox_table: String::from("inbox"),
+            inbox_ttl: None,
+            replication: Replication::SimpleStrategy { replication_factor },
+            consistency: ConsistencyLevels::default(),
+            codecs: Codecs::default(),
+            snapshot_retention: SnapshotRetention::default(),
+            pool_size: PoolSize::default(),
+            connection_timeout: Duration::from_secs(5),
+            request_timeout: Some(Duration::from_secs(30)),
+            session: Arc::new(OnceCell::new()),
+            statements: Arc::new(RwLock::new(HashMap::new())),
         }
-        result
-    })?;
-    session
-        .query(query::create_keyspace(&connection.replication_factor), &[])
-        .await?;
-    session.query(query::CREATE_EVENTS_TABLE, &[]).await?;
-    session.query(query::CREATE_SNAPSHOT_TABLE, &[]).await?;
-    Ok(())
+    }
+
+    /// Contact points used to discover the cluster, replacing the host given on creation.
+    pub fn with_hosts(mut self, hosts: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.hosts = hosts.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Encrypt the connections to the cluster.
+    pub fn with_tls(mut self, tls: TlsConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Authenticate with a username and password.
+    pub fn with_credentials(mut self, credentials: Credentials) -> Self {
+        self.credentials = Some(credentials);
+        self
+    }
+
+    /// Datacenter whose nodes are preferred as coordinators; others are only used on failure.
+    pub fn with_local_datacenter(mut self, local_datacenter: impl Into<String>) -> Self {
+        self.local_datacenter = Some(local_datacenter.into());
+        self
+    }
+
+    /// Keyspace holding the event store tables, `event_store` by default.
+    pub fn with_keyspace(mut self, keyspace: impl Into<String>) -> Self {
+        self.keyspace = keyspace.into();
+        self
+    }
+
+    /// Name of the events table, `events` by default.
+    pub fn with_events_table(mut self, events_table: impl Into<String>) -> Self {
+        self.events_table = events_table.into();
+        self
+    }
+
+    /// Name of the snapshots table, `snapshots` by default.
+    pub fn with_snapshots_table(mut self, snapshots_table: impl Into<String>) -> Self {
+        self.snapshots_table = snapshots_table.into();
+        self
+    }
+
+    /// Name of the outbox table, `outbox` by default.
+    pub fn with_outbox_table(mut self, outbox_table: impl Into<String>) -> Self {
+        self.outbox_table = outbox_table.into();
+        self
+    }
+
+    /// Record every appended event in the outbox table, from which an [`outbox::OutboxRelay`]
+    /// publishes it. Events are not recorded in the outbox by default.
+    pub fn with_outbox(mut self, outbox: bool) -> Self {
+        self.outbox = outbox;
+        self
+    }
+
+    pub fn with_inbox_table(mut self, inbox_table: impl Into<String>) -> Self {
+        self.inbox_table = inbox_table.into();
+        self
+    }
+
+    /// Time after which the inbox forgets that an event was processed. Events redelivered after
+    /// that are processed again. The inbox never forgets when unset. Fails unless the TTL is
+    /// between 1 second and the 20 years allowed by Scylla.
+    pub fn with_inbox_ttl(mut self, inbox_ttl: Duration) -> Result<Self, Error> {
+        time::ttl_seconds(inbox_ttl)?;
+        self.inbox_ttl = Some(inbox_ttl);
+        Ok(self)
+    }
+
+    /// Replication strategy used when the keyspace is created.
+    pub fn with_replication(mut self, replication: Replication) -> Self {
+        self.replication = replication;
+        self
+    }
+
+    /// Consistency levels used by the stores.
+    pub fn with_consistency(mut self, consistency: ConsistencyLevels) -> Self {
+        self.consistency = consistency;
+        self
+    }
+
+    /// Codecs of the event and snapshot payloads. Payloads are written as JSON by default.
+    pub fn with_codecs(mut self, codecs: Codecs) -> Self {
+        self.codecs = codecs;
+        self
+    }
+
+    /// Which snapshots are kept by the snapshot store. All of them are kept by default. Fails
+    /// unless the TTL, when set, is between 1 second and the 20 years allowed by Scylla.
+    pub fn with_snapshot_retention(
+        mut self,
+        snapshot_retention: SnapshotRetention,
+    ) -> Result<Self, Error> {
+        snapshot_retention.ttl.map(time::ttl_seconds).transpose()?;
+        self.snapshot_retention = snapshot_retention;
+        Ok(self)
+    }
+
+    /// Number of connections kept open per node or per shard.
+    pub fn with_pool_size(mut self, pool_size: PoolSize) -> Self {
+        self.pool_size = pool_size;
+        self
+    }
+
+    /// Time allowed to establish a connection to a node.
+    pub fn with_connection_timeout(mut self, connection_timeout: Duration) -> Self {
+        self.connection_timeout = connection_timeout;
+        self
+    }
+
+    /// Time allowed for a single request, or `None` to wait indefinitely.
+    pub fn with_request_timeout(mut self, request_timeout: Option<Duration>) -> Self {
+        self.request_timeout = request_timeout;
+        self
+    }
+
+    pub fn keyspace(&self) -> &str {
+        &self.keyspace
+    }
+
+    pub fn events_table(&self) -> &str {
+        &self.events_table
+    }
+
+    pub fn snapshots_table(&self) -> &str {
+        &self.snapshots_table
+    }
+
+    pub fn outbox_table(&self) -> &str {
+        &self.outbox_table
+    }
+
+    pub fn inbox_table(&self) -> &str {
+        &self.inbox_table
+    }
+
+    pub fn codecs(&self) -> &Codecs {
+        &self.codecs
+    }
+
+    /// The shared session, connecting to the cluster if this is the first use.
+    pub async fn session(&self) -> Result<Arc<Session>, Error> {
+        let session = self
+            .session
+            .get_or_try_init(|| async {
+                let mut load_balancing = DefaultPolicy::builder().token_aware(true);
+                if let Some(local_datacenter) = &self.local_datacenter {
+                    load_balancing = load_balancing.prefer_datacenter(local_datacenter.clone());
+                }
+                let execution_profile = ExecutionProfile::builder()
+                    .request_timeout(self.request_timeout)
+                    .load_balancing_policy(load_balancing.build())
+                    .build();
+                let mut builder = SessionBuilder::new()
+                    .known_nodes(&self.hosts)
+                    .pool_size(self.pool_size)
+                    .connection_timeout(self.connection_timeout)
+                    .default_execution_profile_handle(execution_profile.into_handle());
+                if let Some(tls) = &self.tls {
+                    builder = builder.ssl_context(Some(Self::ssl_context(tls)?));
+                }
+                if let Some(credentials) = &self.credentials {
+                    builder = builder.user(&credentials.username, &credentials.password);
+                }
+                Ok::<_, Error>(Arc::new(builder.build().await?))
+            })
+            .await?;
+        Ok(session.clone())
+    }
+
+    /// The same settings with a session of its own, established on first use like the shared
+    /// one. The connections of a session are driven by the runtime it was established on, so a
+    /// component running on a runtime it drops, e.g. once stopped, needs a session of its own.
+    pub fn detached(&self) -> Self {
+        Self {
+            session: Arc::new(OnceCell::new()),
+            statements: Arc::new(RwLock::new(HashMap::new())),
+            ..self.clone()
+        }
+    }
+
+    fn ssl_context(tls: &TlsConfig) -> Result<SslContext, Error> {
+        let mut context = SslContextBuilder::new(SslMethod::tls())?;
+        context.set_ca_file(&tls.ca_file)?;
+        if let Some(cert_file) = &tls.cert_file {
+            context.set_certificate_file(cert_file, SslFiletype::PEM)?;
+        }
+        if let Some(key_file) = &tls.key_file {
+            context.set_private_key_file(key_file, SslFiletype::PEM)?;
+        }
+        context.set_verify(SslVerifyMode::PEER);
+        Ok(context.build())
+    }
+
+    /// The prepared form of the query, preparing it on the shared session the first time it is
+    /// used. Executing prepared statements lets the driver route each request to a replica that
+    /// owns the partition.
+    pub async fn prepare(
+        &self,
+        query: &str,
+        consistency: Consistency,
+    ) -> Result<PreparedStatement, Error> {
+        let cached = self.statements.read().await.get(query).cloned();
+        let mut statement = match cached {
+            Some(statement) => statement,
+            None => {
+                let statement = self.session().await?.prepare(query).await?;
+                self.statements
+                    .write()
+                    .await
+                    .insert(query.to_string(), statement.clone());
+                statement
+            }
+        };
+        statement.set_consistency(consistency);
+        statement.set_serial_consistency(Some(self.consistency.serial));
+        Ok(statement)
+    }
+}
+
+/// Create the keyspace and bring its schema up to date. See [`migration::migrate`].
+pub async fn run_migration(connection: &ScyllaDbConnection) -> Result<(), Error> {
+    migration::migrate(connection).await
 }
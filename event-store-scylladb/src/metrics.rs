@@ -0,0 +1,133 @@
+use crate::query;
+use ::metrics::{counter, histogram};
+use event_sourcing::event::store::EventStoreError;
+use event_sourcing::Error;
+use scylla::frame::response::result::Row;
+use std::time::Instant;
+
+/// Label used when the aggregate type cannot be derived from the rows of a query.
+pub(crate) const UNKNOWN_AGGREGATE_TYPE: &str = "unknown";
+
+/// Aggregate type a read is recorded under: the one of the rows it returned, before they are
+/// decoded so failing to decode them is attributed too, or else the one the store is dedicated
+/// to, if any.
+pub(crate) fn read_aggregate_type(
+    rows: &Result<Vec<Row>, Error>,
+    aggregate_type: Option<&str>,
+) -> String {
+    rows.as_ref()
+        .ok()
+        .and_then(|rows| rows.first())
+        .and_then(query::aggregate_type)
+        .or(aggregate_type)
+        .unwrap_or(UNKNOWN_AGGREGATE_TYPE)
+        .to_string()
+}
+
+/// Record the latency of a query and count it as an error when it failed. Concurrency
+/// conflicts are counted separately as they are an expected outcome of optimistic locking.
+pub(crate) fn record_query<T>(
+    operation: &'static str,
+    aggregate_type: &str,
+    started: Instant,
+    result: &Result<T, Error>,
+) {
+    let aggregate_type = aggregate_type.to_string();
+    histogram!(
+        "scylladb_query_duration_seconds",
+        "operation" => operation,
+        "aggregate_type" => aggregate_type.clone()
+    )
+    .record(started.elapsed());
+    match result {
+        Ok(_) => {}
+        Err(error) if is_concurrency_error(error) => counter!(
+            "scylladb_concurrency_conflicts_total",
+            "operation" => operation,
+            "aggregate_type" => aggregate_type
+        )
+        .increment(1),
+        Err(_) => counter!(
+            "scylladb_query_errors_total",
+            "operation" => operation,
+            "aggregate_type" => aggregate_type
+        )
+        .increment(1),
+    }
+}
+
+/// Record the number of events read to rebuild an aggregate.
+pub(crate) fn record_events_replayed(aggregate_type: &str, count: usize) {
+    histogram!(
+        "event_store_events_replayed",
+        "aggregate_type" => aggregate_type.to_string()
+    )
+    .record(count as f64);
+}
+
+/// Record whether a snapshot was found for an aggregate.
+pub(crate) fn record_snapshot_read(aggregate_type: &str, hit: bool) {
+    counter!(
+        "snapshot_store_reads_total",
+        "aggregate_type" => aggregate_type.to_string(),
+        "result" => if hit { "hit" } else { "miss" }
+    )
+    .increment(1);
+}
+
+/// Record a snapshot that was found but discarded, for the given reason, as its state couldn't
+/// be used.
+pub(crate) fn record_snapshot_discarded(aggregate_type: &str, reason: &'static str) {
+    counter!(
+        "snapshot_store_discarded_total",
+        "aggregate_type" => aggregate_type.to_string(),
+        "reason" => reason
+    )
+    .increment(1);
+}
+
+/// Record what became of an outbox entry on a pass of the relay, as its `result`.
+pub(crate) fn record_outbox_entry(result: &'static str) {
+    counter!("outbox_entries_total", "result" => result).increment(1);
+}
+
+fn is_concurrency_error(error: &Error) -> bool {
+    matches!(
+        error.downcast_ref::<EventStoreError>(),
+        Some(EventStoreError::Concurrency)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scylla::frame::response::result::CqlValue;
+
+    fn row(aggregate_type: &str) -> Row {
+        Row {
+            columns: vec![
+                Some(CqlValue::Text(String::from("account-1"))),
+                Some(CqlValue::Text(String::from(aggregate_type))),
+            ],
+        }
+    }
+
+    #[test]
+    fn read_aggregate_type_is_taken_from_the_rows() {
+        let rows = Ok(vec![row("account"), row("account")]);
+        assert_eq!(read_aggregate_type(&rows, Some("store")), "account");
+    }
+
+    #[test]
+    fn read_aggregate_type_falls_back_to_the_store() {
+        assert_eq!(read_aggregate_type(&Ok(vec![]), Some("account")), "account");
+        assert_eq!(
+            read_aggregate_type(&Err(Error::from("timed out")), Some("account")),
+            "account"
+        );
+        assert_eq!(
+            read_aggregate_type(&Err(Error::from("timed out")), None),
+            UNKNOWN_AGGREGATE_TYPE
+        );
+    }
+}
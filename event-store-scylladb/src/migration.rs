@@ -0,0 +1,319 @@
+use crate::{query, ScyllaDbConnection};
+use chrono::{DateTime, Utc};
+use event_sourcing::Error;
+use log::{error, info};
+use scylla::statement::Consistency;
+use scylla::{IntoTypedRows, Session};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// How long the migration lock is held before it expires, should the holder die.
+const LOCK_TTL: Duration = Duration::from_secs(300);
+/// Delay between attempts to connect or to acquire the migration lock.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// A schema change applied to the keyspace exactly once, in order of version.
+#[derive(Debug, Clone)]
+pub struct Migration {
+    pub version: i64,
+    pub description: String,
+    pub statements: Vec<Statement>,
+}
+
+/// A statement of a migration. A migration that failed partway is run again from its first
+/// statement, so every statement must be safe to run again.
+#[derive(Debug, Clone)]
+pub enum Statement {
+    /// A statement that has no effect when run again, such as `CREATE TABLE IF NOT EXISTS`.
+    Idempotent(String),
+    /// Add a column to a table of the keyspace. CQL has no `ADD IF NOT EXISTS`, so the column is
+    /// only added when `system_schema.columns` doesn't list it yet.
+    AddColumn {
+        table: String,
+        column: String,
+        cql_type: String,
+    },
+}
+
+impl Statement {
+    pub fn add_column(table: &str, column: &str, cql_type: &str) -> Self {
+        Self::AddColumn {
+            table: table.to_string(),
+            column: column.to_string(),
+            cql_type: cql_type.to_string(),
+        }
+    }
+}
+
+/// A known migration and when it was applied, if it has been.
+#[derive(Debug, Clone)]
+pub struct MigrationStatus {
+    pub version: i64,
+    pub description: String,
+    pub applied_at: Option<DateTime<Utc>>,
+}
+
+/// Migrations versioned on their own, recording the versions applied in a table of their own, so
+/// that other crates keeping tables in the keyspace can migrate them alongside the event store.
+#[derive(Debug, Clone)]
+pub struct Schema {
+    pub version_table: String,
+    pub migrations: Vec<Migration>,
+}
+
+/// The event store schema, whose versions are recorded in `schema_version`.
+pub fn schema(connection: &ScyllaDbConnection) -> Schema {
+    Schema {
+        version_table: String::from("schema_version"),
+        migrations: migrations(connection),
+    }
+}
+
+/// The migrations of the event store schema. Versions are never reused or reordered; a change
+/// to the schema is made by appending a new migration.
+pub fn migrations(connection: &ScyllaDbConnection) -> Vec<Migration> {
+    vec![
+        Migration {
+            version: 1,
+            description: String::from("Create events and snapshots tables"),
+            statements: vec![
+                Statement::Idempotent(query::create_events_table(connection)),
+                Statement::Idempotent(query::create_snapshot_table(connection)),
+            ],
+        },
+        Migration {
+            version: 2,
+            description: String::from("Store event and snapshot times with nanosecond precision"),
+            statements: vec![
+                Statement::add_column(&connection.events_table, "event_time_nanos", "INT"),
+                Statement::add_column(&connection.snapshots_table, "state_time_nanos", "INT"),
+            ],
+        },
+        Migration {
+            version: 3,
+            description: String::from("Store event and snapshot payloads as blobs"),
+            statements: vec![
+                Statement::add_column(&connection.events_table, "payload", "BLOB"),
+                Statement::add_column(&connection.events_table, "content_type", "VARCHAR"),
+                Statement::add_column(&connection.snapshots_table, "payload", "BLOB"),
+                Statement::add_column(&connection.snapshots_table, "content_type", "VARCHAR"),
+            ],
+        },
+        Migration {
+            version: 4,
+            description: String::from("Store the revision of snapshots"),
+            statements: vec![Statement::add_column(
+                &connection.snapshots_table,
+                "revision",
+                "BIGINT",
+            )],
+        },
+        Migration {
+            version: 5,
+            description: String::from("Create outbox table"),
+            statements: vec![Statement::Idempotent(query::create_outbox_table(
+                connection,
+            ))],
+        },
+        Migration {
+            version: 6,
+            description: String::from("Create inbox table"),
+            statements: vec![Statement::Idempotent(query::create_inbox_table(connection))],
+        },
+    ]
+}
+
+/// Apply the pending migrations of the event store schema.
+pub async fn migrate(connection: &ScyllaDbConnection) -> Result<(), Error> {
+    migrate_schema(connection, &schema(connection)).await
+}
+
+/// Apply the pending migrations of a schema in order of version. A lock in the keyspace ensures
+/// only one process migrates at a time, and each applied version is recorded in the version
+/// table of the schema so it is never run again.
+pub async fn migrate_schema(connection: &ScyllaDbConnection, schema: &Schema) -> Result<(), Error> {
+    // Boxed, so the futures awaiting a migration stay shallow enough for the compiler to lay out.
+    Box::pin(apply_schema(connection, schema)).await
+}
+
+#[tracing::instrument(
+    name = "scylladb.run_migration",
+    skip_all,
+    fields(version_table = %schema.version_table),
+    err
+)]
+async fn apply_schema(connection: &ScyllaDbConnection, schema: &Schema) -> Result<(), Error> {
+    let session = connect(connection).await;
+    session
+        .query(query::create_keyspace(connection), &[])
+        .await?;
+    session
+        .query(
+            query::create_schema_version_table(connection, &schema.version_table),
+            &[],
+        )
+        .await?;
+    session
+        .query(query::create_schema_lock_table(connection), &[])
+        .await?;
+
+    let owner = Uuid::new_v4();
+    acquire_lock(connection, owner).await?;
+    let result = apply_pending(connection, &session, schema).await;
+    let released = release_lock(connection, owner).await;
+    result.and(released)
+}
+
+/// Every migration of the event store schema with the time it was applied.
+pub async fn status(connection: &ScyllaDbConnection) -> Result<Vec<MigrationStatus>, Error> {
+    schema_status(connection, &schema(connection)).await
+}
+
+/// Every migration of a schema with the time it was applied.
+pub async fn schema_status(
+    connection: &ScyllaDbConnection,
+    schema: &Schema,
+) -> Result<Vec<MigrationStatus>, Error> {
+    let applied = applied_versions(connection, &schema.version_table).await?;
+    Ok(schema
+        .migrations
+        .iter()
+        .map(|migration| MigrationStatus {
+            applied_at: applied.get(&migration.version).copied(),
+            version: migration.version,
+            description: migration.description.clone(),
+        })
+        .collect())
+}
+
+async fn connect(connection: &ScyllaDbConnection) -> Arc<Session> {
+    loop {
+        match connection.session().await {
+            Ok(session) => return session,
+            Err(error) => error!("Failed to run migration: {:?}", error),
+        }
+        tokio::time::sleep(RETRY_DELAY).await;
+    }
+}
+
+async fn apply_pending(
+    connection: &ScyllaDbConnection,
+    session: &Session,
+    schema: &Schema,
+) -> Result<(), Error> {
+    let applied = applied_versions(connection, &schema.version_table).await?;
+    let mut migrations: Vec<&Migration> = schema
+        .migrations
+        .iter()
+        .filter(|migration| !applied.contains_key(&migration.version))
+        .collect();
+    migrations.sort_by_key(|migration| migration.version);
+    for migration in migrations {
+        info!(
+            "Applying migration {}: {}",
+            migration.version, migration.description
+        );
+        for statement in &migration.statements {
+            apply(connection, session, statement).await?;
+        }
+        let statement = connection
+            .prepare(
+                &query::insert_schema_version(connection, &schema.version_table),
+                Consistency::Quorum,
+            )
+            .await?;
+        session
+            .execute(
+                &statement,
+                (migration.version, &migration.description, Utc::now()),
+            )
+            .await?;
+    }
+    Ok(())
+}
+
+async fn apply(
+    connection: &ScyllaDbConnection,
+    session: &Session,
+    statement: &Statement,
+) -> Result<(), Error> {
+    match statement {
+        Statement::Idempotent(statement) => {
+            session.query(statement.as_str(), &[]).await?;
+        }
+        Statement::AddColumn {
+            table,
+            column,
+            cql_type,
+        } => {
+            let existing = session
+                .query(query::read_column(), (&connection.keyspace, table, column))
+                .await?
+                .rows
+                .is_some_and(|rows| !rows.is_empty());
+            match existing {
+                true => info!("Column {}.{} already exists", table, column),
+                false => {
+                    session
+                        .query(query::add_column(connection, table, column, cql_type), &[])
+                        .await?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn applied_versions(
+    connection: &ScyllaDbConnection,
+    version_table: &str,
+) -> Result<HashMap<i64, DateTime<Utc>>, Error> {
+    let session = connection.session().await?;
+    let statement = connection
+        .prepare(
+            &query::read_schema_versions(connection, version_table),
+            Consistency::Quorum,
+        )
+        .await?;
+    match session.execute(&statement, &[]).await?.rows {
+        Some(rows) => rows
+            .into_typed::<(i64, String, DateTime<Utc>)>()
+            .map(|row| {
+                let (version, _, applied_at) = row?;
+                Ok((version, applied_at))
+            })
+            .collect(),
+        None => Ok(HashMap::new()),
+    }
+}
+
+async fn acquire_lock(connection: &ScyllaDbConnection, owner: Uuid) -> Result<(), Error> {
+    let session = connection.session().await?;
+    let statement = connection
+        .prepare(&query::acquire_schema_lock(connection), Consistency::Quorum)
+        .await?;
+    let started = Instant::now();
+    while !query::applied(
+        session
+            .execute(&statement, (owner, LOCK_TTL.as_secs() as i32))
+            .await?,
+    ) {
+        if started.elapsed() >= LOCK_TTL {
+            return Err(Error::from("Timed out waiting for the migration lock"));
+        }
+        info!("Waiting for the migration lock held by another process");
+        tokio::time::sleep(RETRY_DELAY).await;
+    }
+    Ok(())
+}
+
+async fn release_lock(connection: &ScyllaDbConnection, owner: Uuid) -> Result<(), Error> {
+    let session = connection.session().await?;
+    let statement = connection
+        .prepare(&query::release_schema_lock(connection), Consistency::Quorum)
+        .await?;
+    session.execute(&statement, (owner,)).await?;
+    Ok(())
+}
@@ -0,0 +1,327 @@
+use crate::event::store::ScyllaDbEventStore;
+use crate::{metrics, query, ScyllaDbConnection};
+use chrono::{DateTime, Utc};
+use event_sourcing::event::envelope::EventEnvelope;
+use event_sourcing::event::publisher::EventPublisher;
+use event_sourcing::event::Event;
+use event_sourcing::Error;
+use log::{error, info, warn};
+use scylla::IntoTypedRows;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+use std::time::Duration;
+
+/// Number of partitions the outbox is spread over, so that no single partition holds every
+/// pending event. Entries are read from every shard, so the number only matters for balance.
+const SHARDS: i32 = 16;
+
+/// Record that the event of an aggregate at the given sequence is to be published.
+///
+/// Lightweight transactions can't span tables, so the entry can't be written in the same batch
+/// as the conditional insert of the event. It is written just before it instead, and only tells
+/// the relay where to look: the relay publishes the event stored at that sequence, never the
+/// event the append was made with. An entry left by an append that failed therefore publishes
+/// nothing, and one left by an append that lost a concurrency conflict publishes the winning
+/// event, whose own entry it shares.
+pub(crate) async fn record(
+    connection: &ScyllaDbConnection,
+    aggregate_id: &str,
+    sequence: i64,
+) -> Result<(), Error> {
+    let session = connection.session().await?;
+    let statement = connection
+        .prepare(
+            &query::insert_outbox_entry(connection),
+            connection.consistency.append_events,
+        )
+        .await?;
+    session
+        .execute(
+            &statement,
+            (shard(aggregate_id), aggregate_id, sequence, Utc::now()),
+        )
+        .await?;
+    Ok(())
+}
+
+fn shard(aggregate_id: &str) -> i32 {
+    let mut hasher = DefaultHasher::new();
+    aggregate_id.hash(&mut hasher);
+    (hasher.finish() % SHARDS as u64) as i32
+}
+
+/// What the relay does with an outbox entry.
+#[derive(Debug, PartialEq)]
+enum EntryAction<T> {
+    Publish(T),
+    Discard,
+    Wait,
+}
+
+/// What to do with an entry given the event stored at its sequence, if any, and how long ago
+/// the entry was written. Entries without an event wait for the grace period, in case their
+/// append is still in flight, and are discarded once it has passed.
+fn entry_action<T>(
+    event: Option<T>,
+    age: chrono::Duration,
+    grace_period: chrono::Duration,
+) -> EntryAction<T> {
+    match event {
+        Some(event) => EntryAction::Publish(event),
+        None if age > grace_period => EntryAction::Discard,
+        None => EntryAction::Wait,
+    }
+}
+
+/// Where the next page of a shard starts, given the last entry of the page. The remaining
+/// entries of a blocked aggregate are skipped, so that the aggregates after it aren't starved
+/// by one whose events can't be published.
+fn next_page(last: (String, i64), blocked: &HashSet<String>) -> (String, i64) {
+    match blocked.contains(&last.0) {
+        true => (last.0, i64::MAX),
+        false => last,
+    }
+}
+
+/// Drains the outbox to a publisher, for deployments that don't publish the events table
+/// through CDC. Every entry is deleted once its event is published, so events are published at
+/// least once, and in order for each aggregate: when an event fails to publish, the following
+/// events of its aggregate wait for it to be retried on the next pass.
+pub struct OutboxRelay<E: Event, P: EventPublisher<E>> {
+    connection: ScyllaDbConnection,
+    publisher: P,
+    poll_interval: Duration,
+    batch_size: i32,
+    grace_period: Duration,
+    _event: PhantomData<fn() -> E>,
+}
+
+impl<E: Event, P: EventPublisher<E>> OutboxRelay<E, P> {
+    pub fn new(connection: ScyllaDbConnection, publisher: P) -> Self {
+        Self {
+            connection,
+            publisher,
+            poll_interval: Duration::from_secs(1),
+            batch_size: 100,
+            grace_period: Duration::from_secs(60),
+            _event: PhantomData,
+        }
+    }
+
+    /// Time to wait between passes over the outbox once it's drained, or after a failure.
+    pub fn with_poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    /// Number of entries read from a shard at once. A pass reads every entry of each shard, a
+    /// page of this size at a time.
+    pub fn with_batch_size(mut self, batch_size: i32) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// How long an entry whose event can't be read is kept, in case the append is still in
+    /// flight. Entries older than that belong to appends that failed and are deleted.
+    pub fn with_grace_period(mut self, grace_period: Duration) -> Self {
+        self.grace_period = grace_period;
+        self
+    }
+
+    /// Relay events until the process stops, logging the failures of a pass and retrying it.
+    pub async fn run(&self) {
+        loop {
+            match self.relay().await {
+                Ok(0) => tokio::time::sleep(self.poll_interval).await,
+                Ok(_) => {}
+                Err(error) => {
+                    error!("Failed relaying the outbox: {:?}", error);
+                    tokio::time::sleep(self.poll_interval).await;
+                }
+            }
+        }
+    }
+
+    /// A single pass over the outbox, returning the number of events published.
+    #[tracing::instrument(name = "scylladb.relay_outbox", skip_all, err)]
+    pub async fn relay(&self) -> Result<u64, Error> {
+        let mut published = 0;
+        for shard in 0..SHARDS {
+            published += self.relay_shard(shard).await?;
+        }
+        Ok(published)
+    }
+
+    /// Publish the events of a shard, a page of entries at a time, until every entry of the
+    /// shard has been visited.
+    async fn relay_shard(&self, shard: i32) -> Result<u64, Error> {
+        let session = self.connection.session().await?;
+        let statement = self
+            .connection
+            .prepare(
+                &query::read_outbox_entries(&self.connection),
+                self.connection.consistency.read_events,
+            )
+            .await?;
+        let grace_period = chrono::Duration::from_std(self.grace_period)?;
+        let mut published = 0;
+        // Aggregates with an event that couldn't be published on this pass.
+        let mut blocked = HashSet::new();
+        let mut cursor = (String::new(), i64::MIN);
+        loop {
+            let entries = session
+                .execute(&statement, (shard, &cursor.0, cursor.1, self.batch_size))
+                .await?
+                .rows
+                .unwrap_or_default()
+                .into_typed::<(String, i64, DateTime<Utc>)>()
+                .collect::<Result<Vec<_>, _>>()?;
+            let last = match entries.last() {
+                Some((aggregate_id, sequence, _)) if entries.len() >= self.batch_size as usize => {
+                    Some((aggregate_id.clone(), *sequence))
+                }
+                _ => None,
+            };
+            for (aggregate_id, sequence, created_at) in entries {
+                if blocked.contains(&aggregate_id) {
+                    continue;
+                }
+                let event = self.read_event(&aggregate_id, sequence).await?;
+                match entry_action(event, Utc::now() - created_at, grace_period) {
+                    EntryAction::Publish(event_envelope) => {
+                        match self.publisher.publish(&event_envelope).await {
+                            Ok(()) => {
+                                self.delete(shard, &aggregate_id, sequence).await?;
+                                metrics::record_outbox_entry("published");
+                                published += 1;
+                            }
+                            Err(error) => {
+                                warn!(
+                                "Failed publishing event {}#{}, retrying on the next pass: {:?}",
+                                aggregate_id, sequence, error
+                            );
+                                metrics::record_outbox_entry("failed");
+                                blocked.insert(aggregate_id);
+                            }
+                        }
+                    }
+                    EntryAction::Discard => {
+                        info!(
+                            "Discarding outbox entry {}#{} of an event that was never appended",
+                            aggregate_id, sequence
+                        );
+                        self.delete(shard, &aggregate_id, sequence).await?;
+                        metrics::record_outbox_entry("discarded");
+                    }
+                    EntryAction::Wait => {
+                        blocked.insert(aggregate_id);
+                    }
+                }
+            }
+            match last {
+                Some(last) => cursor = next_page(last, &blocked),
+                None => return Ok(published),
+            }
+        }
+    }
+
+    async fn read_event(
+        &self,
+        aggregate_id: &str,
+        sequence: i64,
+    ) -> Result<Option<EventEnvelope<E>>, Error> {
+        let session = self.connection.session().await?;
+        let statement = self
+            .connection
+            .prepare(
+                &query::read_event(&self.connection),
+                self.connection.consistency.read_events,
+            )
+            .await?;
+        session
+            .execute(&statement, (aggregate_id, sequence))
+            .await?
+            .rows
+            .unwrap_or_default()
+            .into_iter()
+            .next()
+            .map(|row| ScyllaDbEventStore::map_event_envelope(self.connection.codecs(), row))
+            .transpose()
+    }
+
+    async fn delete(&self, shard: i32, aggregate_id: &str, sequence: i64) -> Result<(), Error> {
+        let session = self.connection.session().await?;
+        let statement = self
+            .connection
+            .prepare(
+                &query::delete_outbox_entry(&self.connection),
+                self.connection.consistency.append_events,
+            )
+            .await?;
+        session
+            .execute(&statement, (shard, aggregate_id, sequence))
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entry_of_a_stored_event_is_published() {
+        assert_eq!(
+            entry_action(
+                Some(7),
+                chrono::Duration::hours(1),
+                chrono::Duration::seconds(60)
+            ),
+            EntryAction::Publish(7)
+        );
+    }
+
+    #[test]
+    fn entry_without_an_event_waits_for_the_grace_period() {
+        assert_eq!(
+            entry_action::<i64>(
+                None,
+                chrono::Duration::seconds(10),
+                chrono::Duration::seconds(60)
+            ),
+            EntryAction::Wait
+        );
+    }
+
+    #[test]
+    fn entry_of_an_append_that_failed_is_discarded_unpublished() {
+        assert_eq!(
+            entry_action::<i64>(
+                None,
+                chrono::Duration::seconds(61),
+                chrono::Duration::seconds(60)
+            ),
+            EntryAction::Discard
+        );
+    }
+
+    #[test]
+    fn next_page_starts_after_the_last_entry() {
+        let blocked = HashSet::from([String::from("account-1")]);
+        assert_eq!(
+            next_page((String::from("account-2"), 7), &blocked),
+            (String::from("account-2"), 7)
+        );
+    }
+
+    #[test]
+    fn next_page_skips_the_rest_of_a_blocked_aggregate() {
+        let blocked = HashSet::from([String::from("account-1")]);
+        assert_eq!(
+            next_page((String::from("account-1"), 7), &blocked),
+            (String::from("account-1"), i64::MAX)
+        );
+    }
+}
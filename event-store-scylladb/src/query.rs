@@ -1,10 +1,41 @@
-pub(crate) fn create_keyspace(replication_factor: &i8) -> String {
+use crate::event::store::EVENT_COLUMNS;
+use crate::snapshot::store::SNAPSHOT_COLUMNS;
+use crate::{Replication, ScyllaDbConnection};
+use scylla::frame::response::result::{CqlValue, Row};
+use scylla::QueryResult;
+
+pub(crate) fn create_keyspace(connection: &ScyllaDbConnection) -> String {
+    let keyspace = identifier(&connection.keyspace);
+    let replication = match &connection.replication {
+        Replication::SimpleStrategy { replication_factor } => {
+            format!("{{'class' : 'SimpleStrategy', 'replication_factor' : {replication_factor}}}")
+        }
+        Replication::NetworkTopologyStrategy { datacenters } => {
+            let datacenters: Vec<String> = datacenters
+                .iter()
+                .map(|(datacenter, replication_factor)| {
+                    format!(
+                        "'{}' : {replication_factor}",
+                        datacenter.replace('\'', "''")
+                    )
+                })
+                .collect();
+            format!(
+                "{{'class' : 'NetworkTopologyStrategy', {}}}",
+                datacenters.join(", ")
+            )
+        }
+    };
     // language=cassandraql
-    format!("CREATE KEYSPACE IF NOT EXISTS event_store WITH REPLICATION = {{'class' : 'SimpleStrategy', 'replication_factor' : {replication_factor}}}")
+    format!("CREATE KEYSPACE IF NOT EXISTS {keyspace} WITH REPLICATION = {replication}")
 }
-// language=cassandraql
-pub(crate) const CREATE_EVENTS_TABLE: &str = "
-CREATE TABLE IF NOT EXISTS event_store.events
+
+pub(crate) fn create_events_table(connection: &ScyllaDbConnection) -> String {
+    let table = events_table(connection);
+    // language=cassandraql
+    format!(
+        "
+CREATE TABLE IF NOT EXISTS {table}
 (
     aggregate_id   VARCHAR,
     aggregate_type VARCHAR,
@@ -15,28 +46,52 @@ CREATE TABLE IF NOT EXISTS event_store.events
     revision       BIGINT,
     metadata       MAP<VARCHAR, TEXT>,
     primary key (aggregate_id, sequence)
-) WITH cdc = {'enabled': true}
-";
-// language=cassandraql
-pub(crate) const INSERT_EVENT: &str = "
-INSERT INTO event_store.events (aggregate_id, aggregate_type, event, event_type, event_time, sequence, revision, metadata)
-VALUES (?, ?, ?, ?, ?, ?, ?, ?) IF NOT EXISTS
-";
-// language=cassandraql
-pub(crate) const READ_EVENTS: &str = "
-SELECT aggregate_id, aggregate_type, event, event_type, event_time, sequence, revision, metadata
-FROM event_store.events
+) WITH cdc = {{'enabled': true}}
+"
+    )
+}
+
+pub(crate) fn insert_event(connection: &ScyllaDbConnection) -> String {
+    let table = events_table(connection);
+    // language=cassandraql
+    format!(
+        "
+INSERT INTO {table} ({EVENT_COLUMNS})
+VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?) IF NOT EXISTS
+"
+    )
+}
+
+pub(crate) fn read_events(connection: &ScyllaDbConnection) -> String {
+    let table = events_table(connection);
+    // language=cassandraql
+    format!(
+        "
+SELECT {EVENT_COLUMNS}
+FROM {table}
 WHERE aggregate_id = ?
-";
-// language=cassandraql
-pub(crate) const READ_EVENTS_FROM: &str = "
-SELECT aggregate_id, aggregate_type, event, event_type, event_time, sequence, revision, metadata
-FROM event_store.events
+"
+    )
+}
+
+pub(crate) fn read_events_from(connection: &ScyllaDbConnection) -> String {
+    let table = events_table(connection);
+    // language=cassandraql
+    format!(
+        "
+SELECT {EVENT_COLUMNS}
+FROM {table}
 WHERE aggregate_id = ? AND sequence >= ?
-";
-// language=cassandraql
-pub(crate) const CREATE_SNAPSHOT_TABLE: &str = "
-CREATE TABLE IF NOT EXISTS event_store.snapshots
+"
+    )
+}
+
+pub(crate) fn create_snapshot_table(connection: &ScyllaDbConnection) -> String {
+    let table = snapshots_table(connection);
+    // language=cassandraql
+    format!(
+        "
+CREATE TABLE IF NOT EXISTS {table}
 (
     aggregate_id   VARCHAR,
     aggregate_type VARCHAR,
@@ -44,18 +99,391 @@ CREATE TABLE IF NOT EXISTS event_store.snapshots
     state_time     TIMESTAMP,
     sequence       BIGINT,
     primary key (aggregate_id, sequence)
-) WITH cdc = {'enabled': true}
-";
-// language=cassandraql
-pub(crate) const INSERT_SNAPSHOT: &str = "
-INSERT INTO event_store.snapshots (aggregate_id, aggregate_type, state, state_time, sequence)
-VALUES (?, ?, ?, ?, ?) IF NOT EXISTS
-";
-// language=cassandraql
-pub(crate) const READ_SNAPSHOT: &str = "
-SELECT aggregate_id, aggregate_type, state, state_time, sequence
-FROM event_store.snapshots
+) WITH cdc = {{'enabled': true}}
+"
+    )
+}
+
+pub(crate) fn add_column(
+    connection: &ScyllaDbConnection,
+    table: &str,
+    column: &str,
+    cql_type: &str,
+) -> String {
+    let table = self::table(connection, table);
+    let column = identifier(column);
+    // language=cassandraql
+    format!("ALTER TABLE {table} ADD {column} {cql_type}")
+}
+
+pub(crate) fn read_column() -> String {
+    // language=cassandraql
+    String::from(
+        "
+SELECT column_name
+FROM system_schema.columns
+WHERE keyspace_name = ? AND table_name = ? AND column_name = ?
+",
+    )
+}
+
+pub(crate) fn insert_snapshot(connection: &ScyllaDbConnection) -> String {
+    let table = snapshots_table(connection);
+    // language=cassandraql
+    format!(
+        "
+INSERT INTO {table} ({SNAPSHOT_COLUMNS})
+VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?) IF NOT EXISTS
+"
+    )
+}
+
+/// Write a snapshot again, with every column expiring after the TTL bound last.
+pub(crate) fn expire_snapshot(connection: &ScyllaDbConnection) -> String {
+    let table = snapshots_table(connection);
+    // language=cassandraql
+    format!(
+        "
+INSERT INTO {table} ({SNAPSHOT_COLUMNS})
+VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?) USING TTL ?
+"
+    )
+}
+
+/// The snapshots of an aggregate older than a sequence, along with the TTL left on them.
+pub(crate) fn read_superseded_snapshots(connection: &ScyllaDbConnection) -> String {
+    let table = snapshots_table(connection);
+    // language=cassandraql
+    format!(
+        "
+SELECT {SNAPSHOT_COLUMNS}, TTL(state_time)
+FROM {table}
+WHERE aggregate_id = ? AND sequence < ?
+"
+    )
+}
+
+pub(crate) fn read_snapshot(connection: &ScyllaDbConnection) -> String {
+    let table = snapshots_table(connection);
+    // language=cassandraql
+    format!(
+        "
+SELECT {SNAPSHOT_COLUMNS}
+FROM {table}
 WHERE aggregate_id = ?
 ORDER BY sequence DESC
 LIMIT 1
-";
+"
+    )
+}
+
+pub(crate) fn read_snapshot_at(connection: &ScyllaDbConnection) -> String {
+    let table = snapshots_table(connection);
+    // language=cassandraql
+    format!(
+        "
+SELECT {SNAPSHOT_COLUMNS}
+FROM {table}
+WHERE aggregate_id = ? AND sequence <= ?
+ORDER BY sequence DESC
+LIMIT 1
+"
+    )
+}
+
+pub(crate) fn list_snapshots(connection: &ScyllaDbConnection) -> String {
+    let table = snapshots_table(connection);
+    // language=cassandraql
+    format!(
+        "
+SELECT {SNAPSHOT_COLUMNS}
+FROM {table}
+WHERE aggregate_id = ?
+ORDER BY sequence ASC
+"
+    )
+}
+
+pub(crate) fn read_snapshot_sequences(connection: &ScyllaDbConnection) -> String {
+    let table = snapshots_table(connection);
+    // language=cassandraql
+    format!(
+        "
+SELECT sequence
+FROM {table}
+WHERE aggregate_id = ?
+ORDER BY sequence DESC
+LIMIT ?
+"
+    )
+}
+
+pub(crate) fn delete_snapshots_before(connection: &ScyllaDbConnection) -> String {
+    let table = snapshots_table(connection);
+    // language=cassandraql
+    format!(
+        "
+DELETE FROM {table}
+WHERE aggregate_id = ? AND sequence < ?
+"
+    )
+}
+
+pub(crate) fn read_snapshot_aggregate_ids(connection: &ScyllaDbConnection) -> String {
+    let table = snapshots_table(connection);
+    // language=cassandraql
+    format!(
+        "
+SELECT DISTINCT aggregate_id
+FROM {table}
+"
+    )
+}
+
+pub(crate) fn read_event(connection: &ScyllaDbConnection) -> String {
+    let table = events_table(connection);
+    // language=cassandraql
+    format!(
+        "
+SELECT {EVENT_COLUMNS}
+FROM {table}
+WHERE aggregate_id = ? AND sequence = ?
+"
+    )
+}
+
+pub(crate) fn create_outbox_table(connection: &ScyllaDbConnection) -> String {
+    let table = outbox_table(connection);
+    // language=cassandraql
+    format!(
+        "
+CREATE TABLE IF NOT EXISTS {table}
+(
+    shard          INT,
+    aggregate_id   VARCHAR,
+    sequence       BIGINT,
+    created_at     TIMESTAMP,
+    primary key (shard, aggregate_id, sequence)
+)
+"
+    )
+}
+
+pub(crate) fn insert_outbox_entry(connection: &ScyllaDbConnection) -> String {
+    let table = outbox_table(connection);
+    // language=cassandraql
+    format!(
+        "
+INSERT INTO {table} (shard, aggregate_id, sequence, created_at)
+VALUES (?, ?, ?, ?)
+"
+    )
+}
+
+/// A page of the entries of an outbox shard, after the given aggregate and sequence.
+pub(crate) fn read_outbox_entries(connection: &ScyllaDbConnection) -> String {
+    let table = outbox_table(connection);
+    // language=cassandraql
+    format!(
+        "
+SELECT aggregate_id, sequence, created_at
+FROM {table}
+WHERE shard = ? AND (aggregate_id, sequence) > (?, ?)
+LIMIT ?
+"
+    )
+}
+
+pub(crate) fn delete_outbox_entry(connection: &ScyllaDbConnection) -> String {
+    let table = outbox_table(connection);
+    // language=cassandraql
+    format!(
+        "
+DELETE FROM {table}
+WHERE shard = ? AND aggregate_id = ? AND sequence = ?
+"
+    )
+}
+
+pub(crate) fn create_inbox_table(connection: &ScyllaDbConnection) -> String {
+    let table = inbox_table(connection);
+    // language=cassandraql
+    format!(
+        "
+CREATE TABLE IF NOT EXISTS {table}
+(
+    consumer       VARCHAR,
+    aggregate_id   VARCHAR,
+    sequence       BIGINT,
+    processed_at   TIMESTAMP,
+    primary key ((consumer, aggregate_id), sequence)
+)
+"
+    )
+}
+
+pub(crate) fn insert_inbox_entry(connection: &ScyllaDbConnection) -> String {
+    let table = inbox_table(connection);
+    // language=cassandraql
+    format!(
+        "
+INSERT INTO {table} (consumer, aggregate_id, sequence, processed_at)
+VALUES (?, ?, ?, ?) USING TTL ?
+"
+    )
+}
+
+pub(crate) fn read_inbox_entry(connection: &ScyllaDbConnection) -> String {
+    let table = inbox_table(connection);
+    // language=cassandraql
+    format!(
+        "
+SELECT sequence
+FROM {table}
+WHERE consumer = ? AND aggregate_id = ? AND sequence = ?
+"
+    )
+}
+
+pub(crate) fn create_schema_version_table(
+    connection: &ScyllaDbConnection,
+    version_table: &str,
+) -> String {
+    let table = table(connection, version_table);
+    // language=cassandraql
+    format!(
+        "
+CREATE TABLE IF NOT EXISTS {table}
+(
+    version     BIGINT,
+    description TEXT,
+    applied_at  TIMESTAMP,
+    primary key (version)
+)
+"
+    )
+}
+
+pub(crate) fn read_schema_versions(connection: &ScyllaDbConnection, version_table: &str) -> String {
+    let table = table(connection, version_table);
+    // language=cassandraql
+    format!(
+        "
+SELECT version, description, applied_at
+FROM {table}
+"
+    )
+}
+
+pub(crate) fn insert_schema_version(
+    connection: &ScyllaDbConnection,
+    version_table: &str,
+) -> String {
+    let table = table(connection, version_table);
+    // language=cassandraql
+    format!(
+        "
+INSERT INTO {table} (version, description, applied_at)
+VALUES (?, ?, ?) IF NOT EXISTS
+"
+    )
+}
+
+pub(crate) fn create_schema_lock_table(connection: &ScyllaDbConnection) -> String {
+    let table = table(connection, "schema_lock");
+    // language=cassandraql
+    format!(
+        "
+CREATE TABLE IF NOT EXISTS {table}
+(
+    name  TEXT,
+    owner UUID,
+    primary key (name)
+)
+"
+    )
+}
+
+pub(crate) fn acquire_schema_lock(connection: &ScyllaDbConnection) -> String {
+    let table = table(connection, "schema_lock");
+    // language=cassandraql
+    format!(
+        "
+INSERT INTO {table} (name, owner)
+VALUES ('migration', ?) IF NOT EXISTS USING TTL ?
+"
+    )
+}
+
+pub(crate) fn release_schema_lock(connection: &ScyllaDbConnection) -> String {
+    let table = table(connection, "schema_lock");
+    // language=cassandraql
+    format!(
+        "
+DELETE FROM {table}
+WHERE name = 'migration'
+IF owner = ?
+"
+    )
+}
+
+fn events_table(connection: &ScyllaDbConnection) -> String {
+    table(connection, &connection.events_table)
+}
+
+fn snapshots_table(connection: &ScyllaDbConnection) -> String {
+    table(connection, &connection.snapshots_table)
+}
+
+fn outbox_table(connection: &ScyllaDbConnection) -> String {
+    table(connection, &connection.outbox_table)
+}
+
+fn inbox_table(connection: &ScyllaDbConnection) -> String {
+    table(connection, &connection.inbox_table)
+}
+
+/// A table of the keyspace, quoted so it can be embedded in a statement as-is.
+pub fn table(connection: &ScyllaDbConnection, table: &str) -> String {
+    format!("{}.{}", identifier(&connection.keyspace), identifier(table))
+}
+
+/// Whether a conditional statement was applied, as reported in its `[applied]` column.
+pub(crate) fn applied(result: QueryResult) -> bool {
+    matches!(
+        result
+            .rows
+            .and_then(|rows| rows.into_iter().next())
+            .and_then(|row| row.columns.into_iter().next()),
+        Some(Some(CqlValue::Boolean(true)))
+    )
+}
+
+/// Aggregate type of a row holding the [`EVENT_COLUMNS`] or the [`SNAPSHOT_COLUMNS`], which both
+/// select it second.
+pub(crate) fn aggregate_type(row: &Row) -> Option<&str> {
+    row.columns.get(1)?.as_ref()?.as_text().map(String::as_str)
+}
+
+/// Quote a keyspace or table name so it can be embedded in a statement as-is.
+pub fn identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// The row Scylla returns for the given values once bound to a statement, decoding each value
+/// from its wire format as a column of the given type.
+#[cfg(test)]
+pub(crate) fn bind_and_read(
+    values: impl scylla::frame::value::ValueList,
+    types: &[scylla::frame::response::result::ColumnType],
+) -> scylla::frame::response::result::Row {
+    use scylla::frame::response::result::deser_cql_value;
+
+    let values = values.serialized().unwrap();
+    let columns = values
+        .iter()
+        .zip(types)
+        .map(|(value, typ)| value.map(|mut value| deser_cql_value(typ, &mut value).unwrap()))
+        .collect();
+    scylla::frame::response::result::Row { columns }
+}
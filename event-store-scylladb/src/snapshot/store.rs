@@ -1,104 +1,711 @@
-use chrono::{DateTime, NaiveDateTime, Utc};
+use chrono::{DateTime, Utc};
 
-use scylla::frame::response::result::Row;
-use scylla::{IntoTypedRows, Session, SessionBuilder};
+use scylla::frame::response::result::{CqlValue, Row};
+use scylla::frame::value::{MaybeUnset, ValueList};
+use scylla::IntoTypedRows;
 
 use event_sourcing::aggregate::Aggregate;
+use event_sourcing::codec::Codecs;
 use event_sourcing::event::store::EventStoreError::Concurrency;
 use event_sourcing::snapshot::envelope::SnapshotEnvelope;
 use event_sourcing::snapshot::store::SnapshotStore;
 use event_sourcing::Error;
 
-use crate::{query, ScyllaDbConnection};
+use crate::{metrics, query, time, ScyllaDbConnection};
+use futures::StreamExt;
+use log::warn;
+use std::time::Instant;
+
+/// Columns selected from and inserted into the snapshots table. Snapshots taken before payloads
+/// were stored as blobs have their JSON in the `state` column and no `payload`.
+pub const SNAPSHOT_COLUMNS: &str =
+    "aggregate_id, aggregate_type, state, state_time, sequence, state_time_nanos, payload, content_type, revision";
+
+/// Row of the snapshots table, as bound by [`ScyllaDbSnapshotStore`] when persisting a snapshot.
+type SnapshotValues = (
+    String,
+    String,
+    MaybeUnset<String>,
+    DateTime<Utc>,
+    i64,
+    i32,
+    Vec<u8>,
+    String,
+    i64,
+);
+
+/// Row of the snapshots table, as read by [`ScyllaDbSnapshotStore`].
+type SnapshotRow = (
+    String,
+    String,
+    Option<String>,
+    DateTime<Utc>,
+    i64,
+    Option<i32>,
+    Option<Vec<u8>>,
+    Option<String>,
+    Option<i64>,
+);
 
 #[derive(Debug, Clone)]
 pub struct ScyllaDbSnapshotStore {
     pub connection: ScyllaDbConnection,
+    aggregate_type: Option<String>,
 }
 
 #[async_trait::async_trait]
 impl<A: Aggregate> SnapshotStore<A> for ScyllaDbSnapshotStore {
+    #[tracing::instrument(
+        name = "scylladb.read_snapshot",
+        skip_all,
+        fields(aggregate_id = %aggregate_id),
+        err
+    )]
     async fn read(&self, aggregate_id: &String) -> Result<Option<SnapshotEnvelope<A>>, Error> {
-        let session: Session = SessionBuilder::new()
-            .known_node(&self.connection.host)
-            .build()
-            .await?;
-        match session
-            .query(query::READ_SNAPSHOT, [aggregate_id].as_ref())
-            .await?
-            .rows
-        {
-            Some(rows) => Self::map_snapshot_envelope(rows),
-            None => Ok(None),
+        let (aggregate_type, result) = self
+            .select_snapshots(
+                "read_snapshot",
+                query::read_snapshot(&self.connection),
+                (aggregate_id,),
+            )
+            .await;
+        let result = result.map(|snapshot_envelopes| snapshot_envelopes.into_iter().next());
+        if let Ok(optional_snapshot_envelope) = &result {
+            metrics::record_snapshot_read(&aggregate_type, optional_snapshot_envelope.is_some());
         }
+        result
     }
 
+    #[tracing::instrument(
+        name = "scylladb.read_snapshot_at",
+        skip_all,
+        fields(aggregate_id = %aggregate_id, max_sequence = max_sequence),
+        err
+    )]
+    async fn read_at(
+        &self,
+        aggregate_id: &String,
+        max_sequence: i64,
+    ) -> Result<Option<SnapshotEnvelope<A>>, Error> {
+        let (_, result) = self
+            .select_snapshots(
+                "read_snapshot_at",
+                query::read_snapshot_at(&self.connection),
+                (aggregate_id, max_sequence),
+            )
+            .await;
+        result.map(|snapshot_envelopes| snapshot_envelopes.into_iter().next())
+    }
+
+    #[tracing::instrument(
+        name = "scylladb.list_snapshots",
+        skip_all,
+        fields(aggregate_id = %aggregate_id),
+        err
+    )]
+    async fn list(&self, aggregate_id: &String) -> Result<Vec<SnapshotEnvelope<A>>, Error> {
+        let (_, result) = self
+            .select_snapshots(
+                "list_snapshots",
+                query::list_snapshots(&self.connection),
+                (aggregate_id,),
+            )
+            .await;
+        result
+    }
+
+    #[tracing::instrument(
+        name = "scylladb.insert_snapshot",
+        skip_all,
+        fields(
+            aggregate_id = %snapshot_envelope.aggregate_id,
+            aggregate_type = %snapshot_envelope.aggregate_type,
+            sequence = snapshot_envelope.sequence,
+        ),
+        err
+    )]
     async fn persist(&self, snapshot_envelope: &SnapshotEnvelope<A>) -> Result<(), Error> {
-        let session: Session = SessionBuilder::new()
-            .known_node(&self.connection.host)
-            .build()
+        let started = Instant::now();
+        let result = self.insert_snapshot(snapshot_envelope).await;
+        metrics::record_query(
+            "insert_snapshot",
+            &snapshot_envelope.aggregate_type,
+            started,
+            &result,
+        );
+        let retention = &self.connection.snapshot_retention;
+        if result.is_ok() && (retention.keep_last.is_some() || retention.ttl.is_some()) {
+            // The snapshot is stored, so failing to prune only delays the removal until the next one.
+            if let Err(error) = self.prune(&snapshot_envelope.aggregate_id).await {
+                warn!(
+                    "Failed to prune snapshots of {}: {:?}",
+                    snapshot_envelope.aggregate_id, error
+                );
+            }
+        }
+        result
+    }
+}
+
+impl ScyllaDbSnapshotStore {
+    pub fn new(connection: ScyllaDbConnection) -> Self {
+        Self {
+            connection,
+            aggregate_type: None,
+        }
+    }
+
+    /// Aggregate type the store is dedicated to, labelling the metrics of reads that fail before
+    /// any snapshot tells which aggregate type was read.
+    pub fn with_aggregate_type(mut self, aggregate_type: impl Into<String>) -> Self {
+        self.aggregate_type = Some(aggregate_type.into());
+        self
+    }
+
+    /// Apply the [`SnapshotRetention`](crate::SnapshotRetention) to the snapshots of the
+    /// aggregate: delete the ones older than the snapshots kept, and set the ones superseded by
+    /// a newer snapshot to expire after the TTL. Returns whether any snapshot was deleted or set
+    /// to expire.
+    #[tracing::instrument(
+        name = "scylladb.prune_snapshots",
+        skip_all,
+        fields(aggregate_id = %aggregate_id),
+        err
+    )]
+    pub async fn prune(&self, aggregate_id: &str) -> Result<bool, Error> {
+        let retention = &self.connection.snapshot_retention;
+        let keep_last = retention.keep_last.map(|keep_last| keep_last.max(1));
+        if keep_last.is_none() && retention.ttl.is_none() {
+            return Ok(false);
+        }
+        let session = self.connection.session().await?;
+        let statement = self
+            .connection
+            .prepare(
+                &query::read_snapshot_sequences(&self.connection),
+                self.connection.consistency.read_snapshot,
+            )
             .await?;
-        match session
-            .query(
-                query::INSERT_SNAPSHOT,
+        let sequences = session
+            .execute(
+                &statement,
                 (
-                    &snapshot_envelope.aggregate_id,
-                    &snapshot_envelope.aggregate_type,
-                    &serde_json::to_string(&snapshot_envelope.state)?,
-                    &snapshot_envelope.state_time.timestamp_millis(),
-                    &snapshot_envelope.sequence,
+                    aggregate_id,
+                    keep_last.map_or(1, |keep_last| keep_last + 1) as i32,
                 ),
             )
             .await?
             .rows
+            .unwrap_or_default()
+            .into_typed::<(i64,)>()
+            .map(|row| Ok(row?.0))
+            .collect::<Result<Vec<i64>, Error>>()?;
+        let mut pruned = false;
+        if let Some(oldest_kept) =
+            keep_last.and_then(|keep_last| oldest_kept(&sequences, keep_last))
         {
-            Some(rows) => rows
-                .into_typed::<(
-                    bool,
-                    Option<String>,
-                    Option<i64>,
-                    Option<String>,
-                    Option<String>,
-                    Option<i64>,
-                )>()
-                .try_for_each(|row| {
-                    let applied: bool = row?.0;
-                    match applied {
-                        true => Ok(()),
-                        false => Err(Concurrency.into()),
-                    }
-                }),
-            None => Ok(()),
+            let statement = self
+                .connection
+                .prepare(
+                    &query::delete_snapshots_before(&self.connection),
+                    self.connection.consistency.write_snapshot,
+                )
+                .await?;
+            session
+                .execute(&statement, (aggregate_id, oldest_kept))
+                .await?;
+            pruned = true;
         }
+        if let (Some(ttl), Some(latest)) = (retention.ttl, sequences.first()) {
+            pruned |= self.expire_superseded(aggregate_id, *latest, ttl).await? > 0;
+        }
+        Ok(pruned)
     }
-}
 
-impl ScyllaDbSnapshotStore {
+    /// Write the snapshots of the aggregate older than the latest one again, expiring after the
+    /// TTL, unless they are set to expire already. Returns the number of snapshots set to expire.
+    async fn expire_superseded(
+        &self,
+        aggregate_id: &str,
+        latest: i64,
+        ttl: std::time::Duration,
+    ) -> Result<usize, Error> {
+        let session = self.connection.session().await?;
+        let statement = self
+            .connection
+            .prepare(
+                &query::read_superseded_snapshots(&self.connection),
+                self.connection.consistency.read_snapshot,
+            )
+            .await?;
+        let rows = session
+            .execute(&statement, (aggregate_id, latest))
+            .await?
+            .rows
+            .unwrap_or_default();
+        let statement = self
+            .connection
+            .prepare(
+                &query::expire_snapshot(&self.connection),
+                self.connection.consistency.write_snapshot,
+            )
+            .await?;
+        let ttl = time::ttl_seconds(ttl)?;
+        let mut expired = 0;
+        for values in rows.into_iter().filter_map(|row| expiring_values(row, ttl)) {
+            session.execute(&statement, values).await?;
+            expired += 1;
+        }
+        Ok(expired)
+    }
+
+    /// Prune the snapshots of every aggregate, for a maintenance task enforcing a retention
+    /// that was introduced or tightened after the snapshots were taken. Returns the number of
+    /// aggregates whose snapshots were pruned.
+    #[tracing::instrument(name = "scylladb.prune_all_snapshots", skip_all, err)]
+    pub async fn prune_all(&self) -> Result<u64, Error> {
+        let session = self.connection.session().await?;
+        let statement = self
+            .connection
+            .prepare(
+                &query::read_snapshot_aggregate_ids(&self.connection),
+                self.connection.consistency.read_snapshot,
+            )
+            .await?;
+        let mut aggregate_ids = session
+            .execute_iter(statement, &[])
+            .await?
+            .into_typed::<(String,)>();
+        let mut pruned = 0;
+        while let Some(row) = aggregate_ids.next().await {
+            let (aggregate_id,) = row?;
+            if self.prune(&aggregate_id).await? {
+                pruned += 1;
+            }
+        }
+        Ok(pruned)
+    }
+
+    /// Read snapshots, recording the read. Returns the aggregate type the read was recorded under
+    /// along with the snapshots.
+    async fn select_snapshots<A: Aggregate>(
+        &self,
+        operation: &'static str,
+        query: String,
+        values: impl ValueList,
+    ) -> (String, Result<Vec<SnapshotEnvelope<A>>, Error>) {
+        let started = Instant::now();
+        let rows = self.select_rows(query, values).await;
+        let aggregate_type = metrics::read_aggregate_type(&rows, self.aggregate_type.as_deref());
+        let result = rows.and_then(|rows| {
+            let mut snapshot_envelopes = vec![];
+            for row in rows {
+                snapshot_envelopes
+                    .extend(Self::map_snapshot_envelope(self.connection.codecs(), row)?);
+            }
+            Ok(snapshot_envelopes)
+        });
+        metrics::record_query(operation, &aggregate_type, started, &result);
+        (aggregate_type, result)
+    }
+
+    async fn select_rows(&self, query: String, values: impl ValueList) -> Result<Vec<Row>, Error> {
+        let session = self.connection.session().await?;
+        let statement = self
+            .connection
+            .prepare(&query, self.connection.consistency.read_snapshot)
+            .await?;
+        Ok(session
+            .execute(&statement, values)
+            .await?
+            .rows
+            .unwrap_or_default())
+    }
+
+    async fn insert_snapshot<A: Aggregate>(
+        &self,
+        snapshot_envelope: &SnapshotEnvelope<A>,
+    ) -> Result<(), Error> {
+        let session = self.connection.session().await?;
+        let statement = self
+            .connection
+            .prepare(
+                &query::insert_snapshot(&self.connection),
+                self.connection.consistency.write_snapshot,
+            )
+            .await?;
+        let result = session
+            .execute(
+                &statement,
+                Self::snapshot_values(self.connection.codecs(), snapshot_envelope)?,
+            )
+            .await?;
+        match query::applied(result) {
+            true => Ok(()),
+            false => Err(Concurrency.into()),
+        }
+    }
+
+    /// Values bound to the insert statement, in the order of [`SNAPSHOT_COLUMNS`].
+    fn snapshot_values<A: Aggregate>(
+        codecs: &Codecs,
+        snapshot_envelope: &SnapshotEnvelope<A>,
+    ) -> Result<SnapshotValues, Error> {
+        let (state_time, state_time_nanos) = time::split(snapshot_envelope.state_time);
+        Ok((
+            snapshot_envelope.aggregate_id.clone(),
+            snapshot_envelope.aggregate_type.clone(),
+            MaybeUnset::Unset,
+            state_time,
+            snapshot_envelope.sequence,
+            state_time_nanos,
+            codecs.encode(&snapshot_envelope.state)?,
+            codecs.content_type().to_string(),
+            snapshot_envelope.revision,
+        ))
+    }
+
+    /// Build an envelope from a row holding the [`SNAPSHOT_COLUMNS`]. A snapshot whose state is
+    /// of another revision and can't be upcast, or doesn't deserialize, is discarded so the
+    /// aggregate is rebuilt from its events instead.
     fn map_snapshot_envelope<A: Aggregate>(
-        rows: Vec<Row>,
+        codecs: &Codecs,
+        row: Row,
     ) -> Result<Option<SnapshotEnvelope<A>>, Error> {
-        let result: Result<Vec<SnapshotEnvelope<A>>, Error> = rows
-            .into_iter()
-            .map(|row| {
-                let (agg_id, agg_type, state, state_time, sequence) =
-                    row.into_typed::<(String, String, String, i64, i64)>()?;
-
-                let ts_secs = state_time / 1000;
-                let ts_ns = (state_time % 1000) * 1_000_000;
-                let date_time = DateTime::<Utc>::from_utc(
-                    NaiveDateTime::from_timestamp_opt(ts_secs, ts_ns as u32)
-                        .ok_or(Error::from("Failed to get timestamp"))?,
-                    Utc,
+        let (
+            agg_id,
+            agg_type,
+            state,
+            state_time,
+            sequence,
+            state_time_nanos,
+            payload,
+            content_type,
+            revision,
+        ) = row.into_typed::<SnapshotRow>()?;
+
+        let state: Result<Vec<u8>, Error> = match (payload, content_type, state) {
+            (Some(payload), Some(content_type), _) => codecs.decode_json(&content_type, &payload),
+            (_, _, Some(state)) => Ok(state.into_bytes()),
+            _ => return Err(Error::from("Snapshot has no payload")),
+        };
+        let state = match state {
+            Ok(state) => state,
+            Err(error) => {
+                warn!(
+                    "Discarding snapshot {}#{} that failed to decode: {}",
+                    agg_id, sequence, error
+                );
+                metrics::record_snapshot_discarded(&agg_type, "decoding");
+                return Ok(None);
+            }
+        };
+        // Snapshots taken before revisions were stored are of the first revision.
+        let revision = revision.unwrap_or(1);
+        let state = match revision == A::revision() {
+            true => serde_json::from_slice(&state),
+            false => match serde_json::from_slice(&state).map(|state| A::upcast(revision, state)) {
+                Ok(Some(state)) => serde_json::from_value(state),
+                Err(error) => Err(error),
+                Ok(None) => {
+                    warn!(
+                        "Discarding snapshot {}#{} of revision {}, the current revision is {}",
+                        agg_id,
+                        sequence,
+                        revision,
+                        A::revision()
+                    );
+                    metrics::record_snapshot_discarded(&agg_type, "revision");
+                    return Ok(None);
+                }
+            },
+        };
+        let state = match state {
+            Ok(state) => state,
+            Err(error) => {
+                // The JSON of a payload is only parsed here, so malformed JSON is a decoding error.
+                let reason = match error.is_data() {
+                    true => "deserialization",
+                    false => "decoding",
+                };
+                warn!(
+                    "Discarding snapshot {}#{} that failed {}: {}",
+                    agg_id, sequence, reason, error
                 );
-                Ok(SnapshotEnvelope::new(
-                    agg_id,
-                    agg_type,
-                    serde_json::from_str(&state)?,
-                    date_time,
-                    sequence,
-                ))
-            })
-            .collect();
-        result.map(|r| Some(r.first()?.clone()))
+                metrics::record_snapshot_discarded(&agg_type, reason);
+                return Ok(None);
+            }
+        };
+        Ok(Some(SnapshotEnvelope::new(
+            agg_id,
+            agg_type,
+            state,
+            time::join(state_time, state_time_nanos),
+            sequence,
+            A::revision(),
+        )))
+    }
+}
+
+/// Sequence of the oldest snapshot kept, given the sequences of the newest snapshots in
+/// descending order, when there are more snapshots than the ones kept.
+fn oldest_kept(sequences: &[i64], keep_last: u32) -> Option<i64> {
+    match sequences.len() > keep_last as usize {
+        true => Some(sequences[keep_last as usize - 1]),
+        false => None,
+    }
+}
+
+/// Values writing a superseded snapshot again so it expires after the TTL, from its row of
+/// [`SNAPSHOT_COLUMNS`] followed by the TTL left on it, given the TTL in seconds. Snapshots already set to expire are left
+/// as they are. Columns the row doesn't have are left unset rather than written as null.
+fn expiring_values(mut row: Row, ttl: i32) -> Option<Vec<MaybeUnset<CqlValue>>> {
+    if let Some(Some(_)) = row.columns.pop() {
+        return None;
+    }
+    let mut values: Vec<MaybeUnset<CqlValue>> = row
+        .columns
+        .into_iter()
+        .map(|column| column.map_or(MaybeUnset::Unset, MaybeUnset::Set))
+        .collect();
+    values.push(MaybeUnset::Set(CqlValue::Int(ttl)));
+    Some(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use scylla::frame::response::result::ColumnType;
+    use serde::{Deserialize, Serialize};
+    use serde_json::Value;
+
+    /// Implements the parts of [`Aggregate`] not exercised by the snapshot store.
+    macro_rules! aggregate {
+        () => {
+            type AggregateID = String;
+            type Event = i64;
+            type Error = Error;
+
+            fn aggregate_id(&self) -> &Self::AggregateID {
+                &self.id
+            }
+
+            fn aggregate_type(&self) -> String {
+                String::from("account")
+            }
+
+            fn apply(_state: Option<Self>, _event: Self::Event) -> Result<Self, Self::Error> {
+                Err(Error::from("not used by snapshot tests"))
+            }
+
+            fn apply_all(
+                _state: Option<Self>,
+                _events: Vec<Self::Event>,
+            ) -> Result<Self, Self::Error> {
+                Err(Error::from("not used by snapshot tests"))
+            }
+        };
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Account {
+        id: String,
+        balance: i64,
+    }
+
+    impl Aggregate for Account {
+        aggregate!();
+    }
+
+    /// [`Account`] with a field added in its second revision.
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct AccountWithCurrency {
+        id: String,
+        balance: i64,
+        currency: String,
+    }
+
+    impl Aggregate for AccountWithCurrency {
+        aggregate!();
+
+        fn revision() -> i64 {
+            2
+        }
+
+        fn upcast(revision: i64, mut state: Value) -> Option<Value> {
+            match revision {
+                1 => {
+                    state["currency"] = Value::from("EUR");
+                    Some(state)
+                }
+                _ => None,
+            }
+        }
+    }
+
+    /// [`AccountWithCurrency`] at a revision without an upcaster.
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct AccountWithOverdraft {
+        id: String,
+        balance: i64,
+        overdraft: i64,
+    }
+
+    impl Aggregate for AccountWithOverdraft {
+        aggregate!();
+
+        fn revision() -> i64 {
+            2
+        }
+    }
+
+    /// Column types of the [`SNAPSHOT_COLUMNS`].
+    fn snapshot_column_types() -> Vec<ColumnType> {
+        vec![
+            ColumnType::Text,
+            ColumnType::Text,
+            ColumnType::Text,
+            ColumnType::Timestamp,
+            ColumnType::BigInt,
+            ColumnType::Int,
+            ColumnType::Blob,
+            ColumnType::Text,
+            ColumnType::BigInt,
+        ]
+    }
+
+    fn snapshot_envelope(state_time: DateTime<Utc>) -> SnapshotEnvelope<Account> {
+        SnapshotEnvelope::new(
+            String::from("account-1"),
+            String::from("account"),
+            Account {
+                id: String::from("account-1"),
+                balance: 100,
+            },
+            state_time,
+            3,
+            Account::revision(),
+        )
+    }
+
+    fn persisted_row(snapshot_envelope: &SnapshotEnvelope<Account>) -> Row {
+        query::bind_and_read(
+            ScyllaDbSnapshotStore::snapshot_values(&Codecs::default(), snapshot_envelope).unwrap(),
+            &snapshot_column_types(),
+        )
+    }
+
+    #[test]
+    fn persisted_snapshot_reads_back_equal() {
+        for state_time in [
+            Utc.timestamp_opt(1_700_000_000, 123_456_789).unwrap(),
+            Utc::now(),
+        ] {
+            let snapshot_envelope = snapshot_envelope(state_time);
+            let row = persisted_row(&snapshot_envelope);
+            let read =
+                ScyllaDbSnapshotStore::map_snapshot_envelope(&Codecs::default(), row).unwrap();
+            assert_eq!(read, Some(snapshot_envelope));
+        }
+    }
+
+    #[test]
+    fn snapshot_of_older_revision_is_upcast() {
+        let snapshot_envelope = snapshot_envelope(Utc::now());
+        let row = persisted_row(&snapshot_envelope);
+        let read = ScyllaDbSnapshotStore::map_snapshot_envelope::<AccountWithCurrency>(
+            &Codecs::default(),
+            row,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(read.revision, 2);
+        assert_eq!(
+            read.state,
+            AccountWithCurrency {
+                id: String::from("account-1"),
+                balance: 100,
+                currency: String::from("EUR"),
+            }
+        );
+    }
+
+    #[test]
+    fn snapshot_of_older_revision_without_upcaster_is_discarded() {
+        let row = persisted_row(&snapshot_envelope(Utc::now()));
+        let read = ScyllaDbSnapshotStore::map_snapshot_envelope::<AccountWithOverdraft>(
+            &Codecs::default(),
+            row,
+        )
+        .unwrap();
+        assert_eq!(read, None);
+    }
+
+    #[test]
+    fn snapshot_failing_to_deserialize_is_discarded() {
+        let mut row = persisted_row(&snapshot_envelope(Utc::now()));
+        row.columns[6] = Some(CqlValue::Blob(b"{\"id\": \"account-1\"}".to_vec()));
+        let read = ScyllaDbSnapshotStore::map_snapshot_envelope::<Account>(&Codecs::default(), row)
+            .unwrap();
+        assert_eq!(read, None);
+    }
+
+    #[test]
+    fn snapshot_failing_to_decode_is_discarded() {
+        let mut row = persisted_row(&snapshot_envelope(Utc::now()));
+        row.columns[6] = Some(CqlValue::Blob(b"{\"id\": ".to_vec()));
+        let read = ScyllaDbSnapshotStore::map_snapshot_envelope::<Account>(&Codecs::default(), row)
+            .unwrap();
+        assert_eq!(read, None);
+    }
+
+    #[test]
+    fn snapshot_taken_before_revisions_were_stored_is_of_the_first_revision() {
+        let snapshot_envelope = snapshot_envelope(Utc::now());
+        let mut row = persisted_row(&snapshot_envelope);
+        row.columns[8] = None;
+        let read = ScyllaDbSnapshotStore::map_snapshot_envelope(&Codecs::default(), row).unwrap();
+        assert_eq!(read, Some(snapshot_envelope));
+    }
+
+    #[test]
+    fn oldest_kept_is_the_last_of_the_kept_snapshots() {
+        assert_eq!(oldest_kept(&[9, 7, 5, 3], 3), Some(5));
+        assert_eq!(oldest_kept(&[9, 7], 1), Some(9));
+    }
+
+    #[test]
+    fn nothing_is_deleted_when_no_more_snapshots_than_kept() {
+        assert_eq!(oldest_kept(&[9, 7, 5], 3), None);
+        assert_eq!(oldest_kept(&[9], 3), None);
+        assert_eq!(oldest_kept(&[], 1), None);
+    }
+
+    #[test]
+    fn superseded_snapshot_is_written_again_with_the_ttl() {
+        let mut row = persisted_row(&snapshot_envelope(Utc::now()));
+        let columns = row.columns.clone();
+        row.columns[8] = None;
+        row.columns.push(None);
+        let values = expiring_values(row, 60).unwrap();
+        assert_eq!(values.len(), columns.len() + 1);
+        for (index, column) in columns.into_iter().enumerate().take(8) {
+            match column {
+                Some(column) => {
+                    assert!(matches!(&values[index], MaybeUnset::Set(value) if *value == column))
+                }
+                None => assert!(matches!(values[index], MaybeUnset::Unset)),
+            }
+        }
+        assert!(matches!(values[8], MaybeUnset::Unset));
+        assert!(matches!(values[9], MaybeUnset::Set(CqlValue::Int(60))));
+    }
+
+    #[test]
+    fn snapshot_already_expiring_is_left_as_is() {
+        let mut row = persisted_row(&snapshot_envelope(Utc::now()));
+        row.columns.push(Some(CqlValue::Int(42)));
+        assert!(expiring_values(row, 60).is_none());
     }
 }
@@ -0,0 +1,90 @@
+use chrono::{DateTime, Duration, Utc};
+use event_sourcing::Error;
+
+/// Nanoseconds in a millisecond, the precision of a CQL `TIMESTAMP`.
+const NANOS_PER_MILLI: u32 = 1_000_000;
+
+/// Longest TTL Scylla accepts, 20 years.
+const MAX_TTL_SECONDS: u64 = 630_720_000;
+
+/// Split a time into the millisecond precision `TIMESTAMP` stored by Scylla and the nanoseconds
+/// within that millisecond, which are stored alongside so the time can be restored exactly.
+pub(crate) fn split(time: DateTime<Utc>) -> (DateTime<Utc>, i32) {
+    let nanos = time.timestamp_subsec_nanos() % NANOS_PER_MILLI;
+    (time - Duration::nanoseconds(nanos.into()), nanos as i32)
+}
+
+/// Restore a time split by [`split`]. Rows written before the nanoseconds were stored have none.
+pub(crate) fn join(timestamp: DateTime<Utc>, nanos: Option<i32>) -> DateTime<Utc> {
+    timestamp + Duration::nanoseconds(nanos.unwrap_or_default().into())
+}
+
+/// A TTL in the whole seconds written by Scylla. TTLs under a second would be written as 0,
+/// which Scylla takes as no TTL at all, and it rejects those over 20 years.
+pub(crate) fn ttl_seconds(ttl: std::time::Duration) -> Result<i32, Error> {
+    if ttl < std::time::Duration::from_secs(1) || ttl.as_secs() > MAX_TTL_SECONDS {
+        return Err(Error::from(format!(
+            "TTL of {ttl:?} is not between 1 second and {MAX_TTL_SECONDS} seconds"
+        )));
+    }
+    Ok(i32::try_from(ttl.as_secs())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn split_keeps_whole_milliseconds_in_the_timestamp() {
+        let time = Utc.timestamp_opt(1_700_000_000, 123_456_789).unwrap();
+        let (timestamp, nanos) = split(time);
+        assert_eq!(timestamp.timestamp_millis(), time.timestamp_millis());
+        assert_eq!(timestamp.timestamp_subsec_nanos(), 123_000_000);
+        assert_eq!(nanos, 456_789);
+    }
+
+    #[test]
+    fn join_restores_the_split_time() {
+        for time in [
+            Utc.timestamp_opt(1_700_000_000, 123_456_789).unwrap(),
+            Utc.timestamp_opt(1_700_000_000, 999_999_999).unwrap(),
+            Utc.timestamp_opt(-1_700_000_000, 1).unwrap(),
+            Utc::now(),
+        ] {
+            let (timestamp, nanos) = split(time);
+            assert_eq!(join(timestamp, Some(nanos)), time);
+        }
+    }
+
+    #[test]
+    fn join_without_nanos_keeps_the_timestamp() {
+        let timestamp = Utc.timestamp_millis_opt(1_700_000_000_123).unwrap();
+        assert_eq!(join(timestamp, None), timestamp);
+    }
+
+    #[test]
+    fn ttl_is_written_in_whole_seconds() {
+        assert_eq!(ttl_seconds(std::time::Duration::from_secs(60)).unwrap(), 60);
+        assert_eq!(
+            ttl_seconds(std::time::Duration::from_millis(1_500)).unwrap(),
+            1
+        );
+        assert_eq!(
+            ttl_seconds(std::time::Duration::from_secs(MAX_TTL_SECONDS)).unwrap(),
+            630_720_000
+        );
+    }
+
+    #[test]
+    fn ttl_under_a_second_or_over_20_years_is_rejected() {
+        for ttl in [
+            std::time::Duration::ZERO,
+            std::time::Duration::from_millis(999),
+            std::time::Duration::from_secs(MAX_TTL_SECONDS + 1),
+            std::time::Duration::from_secs(u64::MAX),
+        ] {
+            assert!(ttl_seconds(ttl).is_err());
+        }
+    }
+}
@@ -0,0 +1,473 @@
+//! Round trips through a running ScyllaDB node. Run with `cargo test -- --ignored`, pointing
+//! `SCYLLA_HOST` at the node when it isn't `localhost:9042`.
+
+use chrono::{TimeZone, Utc};
+use event_sourcing::aggregate::Aggregate;
+use event_sourcing::event::envelope::EventEnvelope;
+use event_sourcing::event::inbox::{Inbox, InboxListener};
+use event_sourcing::event::listener::AsyncEventListener;
+use event_sourcing::event::store::EventStore;
+use event_sourcing::event::Event;
+use event_sourcing::snapshot::envelope::SnapshotEnvelope;
+use event_sourcing::snapshot::store::SnapshotStore;
+use event_sourcing::test_support::{event_envelope, RecordingListener, RecordingPublisher};
+use event_sourcing::Error;
+use event_store_scylladb::event::store::ScyllaDbEventStore;
+use event_store_scylladb::inbox::ScyllaDbInbox;
+use event_store_scylladb::outbox::OutboxRelay;
+use event_store_scylladb::snapshot::store::ScyllaDbSnapshotStore;
+use event_store_scylladb::{
+    migration, run_migration, table, ScyllaDbConnection, SnapshotRetention,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+enum AccountEvent {
+    Deposited { amount: i64 },
+}
+
+impl Event for AccountEvent {
+    fn event_type(&self) -> String {
+        String::from("Deposited")
+    }
+
+    fn revision(&self) -> i64 {
+        1
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Account {
+    id: String,
+    balance: i64,
+}
+
+impl Aggregate for Account {
+    type AggregateID = String;
+    type Event = AccountEvent;
+    type Error = Error;
+
+    fn aggregate_id(&self) -> &Self::AggregateID {
+        &self.id
+    }
+
+    fn aggregate_type(&self) -> String {
+        String::from("account")
+    }
+
+    fn apply(state: Option<Self>, event: Self::Event) -> Result<Self, Self::Error> {
+        let state = state.ok_or(Error::from("Account not opened"))?;
+        match event {
+            AccountEvent::Deposited { amount } => Ok(Account {
+                balance: state.balance + amount,
+                ..state
+            }),
+        }
+    }
+
+    fn apply_all(state: Option<Self>, events: Vec<Self::Event>) -> Result<Self, Self::Error> {
+        events
+            .into_iter()
+            .try_fold(state, |state, event| Self::apply(state, event).map(Some))?
+            .ok_or(Error::from("Account not opened"))
+    }
+}
+
+async fn connection() -> ScyllaDbConnection {
+    let host = std::env::var("SCYLLA_HOST").unwrap_or(String::from("localhost:9042"));
+    let connection = ScyllaDbConnection::new(host, 1).with_keyspace("event_store_round_trip");
+    run_migration(&connection).await.unwrap();
+    connection
+}
+
+#[tokio::test]
+#[ignore = "requires a running ScyllaDB node"]
+async fn persisted_events_read_back_equal() {
+    let event_store = ScyllaDbEventStore::new(connection().await);
+    let aggregate_id = uuid::Uuid::new_v4().to_string();
+    let event_envelopes: Vec<EventEnvelope<AccountEvent>> = (1..=3)
+        .map(|sequence| {
+            EventEnvelope::new(
+                aggregate_id.clone(),
+                String::from("account"),
+                AccountEvent::Deposited { amount: sequence },
+                String::from("Deposited"),
+                Utc.timestamp_opt(1_700_000_000, 123_456_789 + sequence as u32)
+                    .unwrap(),
+                sequence,
+                1,
+                HashMap::from([(String::from("correlation-id"), sequence.to_string())]),
+            )
+        })
+        .collect();
+    for event_envelope in &event_envelopes {
+        event_store.persist(event_envelope).await.unwrap();
+    }
+
+    let read: Vec<EventEnvelope<AccountEvent>> = event_store.read(&aggregate_id).await.unwrap();
+    assert_eq!(read, event_envelopes);
+    let read: Vec<EventEnvelope<AccountEvent>> =
+        event_store.read_from(&aggregate_id, 2).await.unwrap();
+    assert_eq!(read, event_envelopes[1..]);
+}
+
+#[tokio::test]
+#[ignore = "requires a running ScyllaDB node"]
+async fn outbox_relays_appended_events_once_in_order() {
+    let connection = connection().await.with_outbox(true);
+    let event_store = ScyllaDbEventStore::new(connection.clone());
+    let aggregate_id = uuid::Uuid::new_v4().to_string();
+    let event_envelopes: Vec<EventEnvelope<AccountEvent>> = (1..=3)
+        .map(|sequence| {
+            EventEnvelope::new(
+                aggregate_id.clone(),
+                String::from("account"),
+                AccountEvent::Deposited { amount: sequence },
+                String::from("Deposited"),
+                Utc::now(),
+                sequence,
+                1,
+                HashMap::new(),
+            )
+        })
+        .collect();
+    for event_envelope in &event_envelopes {
+        event_store.persist(event_envelope).await.unwrap();
+    }
+    // Loses the concurrency conflict, so its entry must not publish anything.
+    assert!(event_store.persist(&event_envelopes[2]).await.is_err());
+
+    let publisher = RecordingPublisher::default();
+    let relay = OutboxRelay::new(connection, publisher.clone());
+    relay.relay().await.unwrap();
+    relay.relay().await.unwrap();
+    let published: Vec<EventEnvelope<AccountEvent>> = publisher
+        .published()
+        .into_iter()
+        .filter(|event_envelope| event_envelope.aggregate_id == aggregate_id)
+        .collect();
+    assert_eq!(published, event_envelopes);
+}
+
+#[tokio::test]
+#[ignore = "requires a running ScyllaDB node"]
+async fn outbox_entry_of_a_lost_append_publishes_only_the_winner() {
+    let connection = connection().await.with_outbox(true);
+    let event_store = ScyllaDbEventStore::new(connection.clone());
+    let aggregate_id = uuid::Uuid::new_v4().to_string();
+    let deposit = |amount| {
+        EventEnvelope::new(
+            aggregate_id.clone(),
+            String::from("account"),
+            AccountEvent::Deposited { amount },
+            String::from("Deposited"),
+            Utc::now(),
+            1,
+            1,
+            HashMap::new(),
+        )
+    };
+    let winner = deposit(10);
+    event_store.persist(&winner).await.unwrap();
+    assert!(event_store.persist(&deposit(99)).await.is_err());
+
+    let publisher = RecordingPublisher::default();
+    let relay = OutboxRelay::new(connection, publisher.clone());
+    relay.relay().await.unwrap();
+    relay.relay().await.unwrap();
+    let published: Vec<EventEnvelope<AccountEvent>> = publisher
+        .published()
+        .into_iter()
+        .filter(|event_envelope| event_envelope.aggregate_id == aggregate_id)
+        .collect();
+    assert_eq!(published, vec![winner]);
+}
+
+#[tokio::test]
+#[ignore = "requires a running ScyllaDB node"]
+async fn outbox_entry_of_a_failed_append_is_discarded_unpublished() {
+    let connection = connection().await.with_outbox(true);
+    let aggregate_id = uuid::Uuid::new_v4().to_string();
+    // The entry an append writes before its conditional insert, left behind as the insert
+    // never stored the event.
+    let session = connection.session().await.unwrap();
+    session
+        .query(
+            format!(
+                "INSERT INTO {} (shard, aggregate_id, sequence, created_at) VALUES (?, ?, ?, ?)",
+                table(&connection, connection.outbox_table())
+            ),
+            (0, aggregate_id.as_str(), 1_i64, Utc::now()),
+        )
+        .await
+        .unwrap();
+
+    let publisher = RecordingPublisher::<AccountEvent>::default();
+    let relay =
+        OutboxRelay::new(connection.clone(), publisher.clone()).with_grace_period(Duration::ZERO);
+    relay.relay().await.unwrap();
+    assert!(!publisher
+        .published()
+        .iter()
+        .any(|event_envelope| event_envelope.aggregate_id == aggregate_id));
+    let remaining = session
+        .query(
+            format!(
+                "SELECT sequence FROM {} WHERE shard = 0 AND aggregate_id = ?",
+                table(&connection, connection.outbox_table())
+            ),
+            (aggregate_id.as_str(),),
+        )
+        .await
+        .unwrap()
+        .rows
+        .unwrap_or_default();
+    assert!(remaining.is_empty());
+}
+
+#[tokio::test]
+#[ignore = "requires a running ScyllaDB node"]
+async fn inbox_skips_redelivered_events() {
+    let inbox = ScyllaDbInbox {
+        connection: connection().await,
+    };
+    let consumer = String::from("round-trip");
+    let aggregate_id = uuid::Uuid::new_v4().to_string();
+    assert!(!inbox.contains(&consumer, &aggregate_id, 1).await.unwrap());
+    inbox.record(&consumer, &aggregate_id, 1).await.unwrap();
+    assert!(inbox.contains(&consumer, &aggregate_id, 1).await.unwrap());
+    assert!(!inbox.contains(&consumer, &aggregate_id, 2).await.unwrap());
+    assert!(!inbox
+        .contains(&String::from("other"), &aggregate_id, 1)
+        .await
+        .unwrap());
+
+    let listener = RecordingListener::default();
+    let inbox_listener = InboxListener::new(listener.clone(), inbox, consumer);
+    let event_envelope = event_envelope(&aggregate_id, 2);
+    for _ in 0..2 {
+        inbox_listener.on(event_envelope.clone()).await.unwrap();
+    }
+    assert_eq!(listener.received(), vec![event_envelope]);
+}
+
+#[tokio::test]
+#[ignore = "requires a running ScyllaDB node"]
+async fn persisted_snapshot_reads_back_equal() {
+    let snapshot_store = ScyllaDbSnapshotStore::new(connection().await);
+    let aggregate_id = uuid::Uuid::new_v4().to_string();
+    let snapshot_envelope = SnapshotEnvelope::new(
+        aggregate_id.clone(),
+        String::from("account"),
+        Account {
+            id: aggregate_id.clone(),
+            balance: 100,
+        },
+        Utc::now(),
+        3,
+        Account::revision(),
+    );
+    snapshot_store.persist(&snapshot_envelope).await.unwrap();
+
+    let read: Option<SnapshotEnvelope<Account>> = snapshot_store.read(&aggregate_id).await.unwrap();
+    assert_eq!(read, Some(snapshot_envelope));
+}
+
+#[tokio::test]
+#[ignore = "requires a running ScyllaDB node"]
+async fn snapshots_read_at_sequence_and_list_in_order() {
+    let snapshot_store = ScyllaDbSnapshotStore::new(connection().await);
+    let aggregate_id = uuid::Uuid::new_v4().to_string();
+    let snapshot_envelopes: Vec<SnapshotEnvelope<Account>> = [3, 6, 9]
+        .into_iter()
+        .map(|sequence| {
+            SnapshotEnvelope::new(
+                aggregate_id.clone(),
+                String::from("account"),
+                Account {
+                    id: aggregate_id.clone(),
+                    balance: sequence * 100,
+                },
+                Utc::now(),
+                sequence,
+                Account::revision(),
+            )
+        })
+        .collect();
+    for snapshot_envelope in &snapshot_envelopes {
+        snapshot_store.persist(snapshot_envelope).await.unwrap();
+    }
+
+    let read: Option<SnapshotEnvelope<Account>> =
+        snapshot_store.read_at(&aggregate_id, 8).await.unwrap();
+    assert_eq!(read.as_ref(), snapshot_envelopes.get(1));
+    let read: Option<SnapshotEnvelope<Account>> =
+        snapshot_store.read_at(&aggregate_id, 2).await.unwrap();
+    assert_eq!(read, None);
+    let listed: Vec<SnapshotEnvelope<Account>> = snapshot_store.list(&aggregate_id).await.unwrap();
+    assert_eq!(listed, snapshot_envelopes);
+}
+
+fn account_snapshot(aggregate_id: &str, sequence: i64) -> SnapshotEnvelope<Account> {
+    SnapshotEnvelope::new(
+        String::from(aggregate_id),
+        String::from("account"),
+        Account {
+            id: String::from(aggregate_id),
+            balance: sequence * 100,
+        },
+        Utc::now(),
+        sequence,
+        Account::revision(),
+    )
+}
+
+/// Sequence of each stored snapshot of the aggregate along with the TTL left on it.
+async fn snapshot_ttls(
+    connection: &ScyllaDbConnection,
+    aggregate_id: &str,
+) -> Vec<(i64, Option<i32>)> {
+    let query = format!(
+        "SELECT sequence, TTL(state_time) FROM {}.{} WHERE aggregate_id = ?",
+        connection.keyspace(),
+        connection.snapshots_table()
+    );
+    let mut ttls: Vec<(i64, Option<i32>)> = connection
+        .session()
+        .await
+        .unwrap()
+        .query(query, (aggregate_id,))
+        .await
+        .unwrap()
+        .rows_typed::<(i64, Option<i32>)>()
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    ttls.sort();
+    ttls
+}
+
+#[tokio::test]
+#[ignore = "requires a running ScyllaDB node"]
+async fn persisting_a_snapshot_deletes_the_ones_not_kept() {
+    let connection = connection()
+        .await
+        .with_snapshot_retention(SnapshotRetention {
+            keep_last: Some(2),
+            ttl: None,
+        })
+        .unwrap();
+    let snapshot_store = ScyllaDbSnapshotStore::new(connection.clone());
+    let aggregate_id = uuid::Uuid::new_v4().to_string();
+    for sequence in [3, 6, 9] {
+        snapshot_store
+            .persist(&account_snapshot(&aggregate_id, sequence))
+            .await
+            .unwrap();
+    }
+
+    assert_eq!(
+        snapshot_ttls(&connection, &aggregate_id).await,
+        vec![(6, None), (9, None)]
+    );
+    assert!(!snapshot_store.prune(&aggregate_id).await.unwrap());
+}
+
+#[tokio::test]
+#[ignore = "requires a running ScyllaDB node"]
+async fn only_superseded_snapshots_expire() {
+    let connection = connection()
+        .await
+        .with_snapshot_retention(SnapshotRetention {
+            keep_last: None,
+            ttl: Some(Duration::from_secs(3600)),
+        })
+        .unwrap();
+    let snapshot_store = ScyllaDbSnapshotStore::new(connection.clone());
+    let aggregate_id = uuid::Uuid::new_v4().to_string();
+    for sequence in [3, 6, 9] {
+        snapshot_store
+            .persist(&account_snapshot(&aggregate_id, sequence))
+            .await
+            .unwrap();
+    }
+
+    let ttls = snapshot_ttls(&connection, &aggregate_id).await;
+    assert_eq!(ttls.len(), 3);
+    for (_, ttl) in &ttls[..2] {
+        assert!(matches!(ttl, Some(ttl) if *ttl > 0 && *ttl <= 3600));
+    }
+    assert_eq!(ttls[2], (9, None));
+    let read: Option<SnapshotEnvelope<Account>> = snapshot_store.read(&aggregate_id).await.unwrap();
+    assert_eq!(read.map(|snapshot| snapshot.sequence), Some(9));
+}
+
+#[tokio::test]
+#[ignore = "requires a running ScyllaDB node"]
+async fn prune_all_applies_a_retention_introduced_later() {
+    let connection = connection().await;
+    let aggregate_id = uuid::Uuid::new_v4().to_string();
+    let snapshot_store = ScyllaDbSnapshotStore::new(connection.clone());
+    for sequence in [3, 6, 9] {
+        snapshot_store
+            .persist(&account_snapshot(&aggregate_id, sequence))
+            .await
+            .unwrap();
+    }
+
+    let snapshot_store = ScyllaDbSnapshotStore::new(
+        connection
+            .clone()
+            .with_snapshot_retention(SnapshotRetention {
+                keep_last: Some(1),
+                ttl: None,
+            })
+            .unwrap(),
+    );
+    assert!(snapshot_store.prune_all().await.unwrap() >= 1);
+    assert_eq!(
+        snapshot_ttls(&connection, &aggregate_id).await,
+        vec![(9, None)]
+    );
+}
+
+#[tokio::test]
+#[ignore = "requires a running ScyllaDB node"]
+async fn migration_failed_partway_is_applied_again() {
+    let host = std::env::var("SCYLLA_HOST").unwrap_or(String::from("localhost:9042"));
+    let connection = ScyllaDbConnection::new(host, 1).with_keyspace("event_store_migration");
+    run_migration(&connection).await.unwrap();
+
+    // Leave migration 3 as if it failed after adding the columns of the events table only.
+    let session = connection.session().await.unwrap();
+    session
+        .query(
+            format!(
+                "ALTER TABLE {}.{} DROP (payload, content_type)",
+                connection.keyspace(),
+                connection.snapshots_table()
+            ),
+            &[],
+        )
+        .await
+        .unwrap();
+    session
+        .query(
+            format!(
+                "DELETE FROM {}.schema_version WHERE version IN (3, 4, 5, 6)",
+                connection.keyspace()
+            ),
+            &[],
+        )
+        .await
+        .unwrap();
+
+    run_migration(&connection).await.unwrap();
+    assert!(migration::status(&connection)
+        .await
+        .unwrap()
+        .iter()
+        .all(|status| status.applied_at.is_some()));
+}
@@ -1 +1,2 @@
+pub(crate) mod trace;
 pub(crate) mod v1;
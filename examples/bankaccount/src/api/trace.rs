@@ -0,0 +1,34 @@
+use event_sourcing::trace::{self, TRACEPARENT, TRACESTATE};
+use rocket::request::{FromRequest, Outcome};
+use rocket::Request;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use tracing::Span;
+
+/// W3C trace context sent by the caller in the `traceparent` and `tracestate` headers.
+pub(crate) struct TraceContext(HashMap<String, String>);
+
+impl TraceContext {
+    /// Continue the caller's trace on the given span.
+    pub(crate) fn set_parent(&self, span: &Span) {
+        trace::set_parent(span, &self.0);
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for TraceContext {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let headers = [TRACEPARENT, TRACESTATE]
+            .into_iter()
+            .filter_map(|key| {
+                request
+                    .headers()
+                    .get_one(key)
+                    .map(|value| (key.to_string(), value.to_string()))
+            })
+            .collect();
+        Outcome::Success(TraceContext(headers))
+    }
+}
@@ -1,5 +1,7 @@
 use rocket::{post, State};
+use tracing::Span;
 
+use crate::api::trace::TraceContext;
 use crate::application::account::deposit_command_handler::{DepositCommand, DepositCommandHandler};
 use crate::application::account::open_account_command_handler::{
     OpenAccountCommand, OpenAccountCommandHandler,
@@ -29,11 +31,14 @@ pub(crate) struct Withdraw {
 }
 
 #[post("/open", data = "<request>")]
+#[tracing::instrument(name = "POST /api/v1/open", skip_all, err)]
 pub(crate) async fn open(
+    trace_context: TraceContext,
     request: Json<OpenAccount>,
     event_store: &State<ScyllaDbEventStore>,
     _snapshot_store: &State<ScyllaDbSnapshotStore>,
 ) -> Result<Json<CommandResponse>, String> {
+    trace_context.set_parent(&Span::current());
     let command = OpenAccountCommand {
         name: request.into_inner().name,
     };
@@ -48,12 +53,15 @@ pub(crate) async fn open(
 }
 
 #[post("/deposit/<account_id>", data = "<request>")]
+#[tracing::instrument(name = "POST /api/v1/deposit", skip_all, err)]
 pub(crate) async fn deposit(
+    trace_context: TraceContext,
     request: Json<Deposit>,
     account_id: String,
     event_store: &State<ScyllaDbEventStore>,
     snapshot_store: &State<ScyllaDbSnapshotStore>,
 ) -> Result<Json<CommandResponse>, String> {
+    trace_context.set_parent(&Span::current());
     let command = DepositCommand {
         account_id,
         amount: request.into_inner().amount,
@@ -70,12 +78,15 @@ pub(crate) async fn deposit(
 }
 
 #[post("/withdraw/<account_id>", data = "<request>")]
+#[tracing::instrument(name = "POST /api/v1/withdraw", skip_all, err)]
 pub(crate) async fn withdraw(
+    trace_context: TraceContext,
     request: Json<Withdraw>,
     account_id: String,
     event_store: &State<ScyllaDbEventStore>,
     snapshot_store: &State<ScyllaDbSnapshotStore>,
 ) -> Result<Json<CommandResponse>, String> {
+    trace_context.set_parent(&Span::current());
     let command = WithdrawCommand {
         account_id,
         amount: request.into_inner().amount,
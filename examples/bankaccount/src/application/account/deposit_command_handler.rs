@@ -11,9 +11,10 @@ use event_sourcing::event::store::EventStore;
 use event_sourcing::event::Event;
 use event_sourcing::snapshot::envelope::SnapshotEnvelope;
 use event_sourcing::snapshot::store::SnapshotStore;
+use event_sourcing::trace;
 use event_sourcing::Error;
 use std::collections::HashMap;
-use uuid::Uuid;
+use tracing::Instrument;
 
 pub(crate) struct DepositCommand {
     pub account_id: String,
@@ -41,6 +42,12 @@ impl<E: EventStore<AccountEvents>, S: SnapshotStore<Account>> CommandHandler<Dep
 {
     type Error = Error;
 
+    #[tracing::instrument(
+        name = "DepositCommandHandler::handle",
+        skip_all,
+        fields(aggregate_id = %command.account_id),
+        err
+    )]
     async fn handle(&self, command: DepositCommand) -> Result<CommandResponse, Self::Error> {
         let deposited_event = Deposited {
             account_id: command.account_id.clone(),
@@ -49,16 +56,23 @@ impl<E: EventStore<AccountEvents>, S: SnapshotStore<Account>> CommandHandler<Dep
         let aggregate_id: &String = command
             .target_aggregate_identifier()
             .ok_or(MissingTargetAggregateIdentifier)?;
-        let optional_snapshot_envelope: Option<SnapshotEnvelope<Account>> =
-            self.snapshot_store.read(aggregate_id).await?;
-        let snapshot_sequence: i64 = optional_snapshot_envelope
-            .clone()
-            .map(|envelope| envelope.sequence)
-            .unwrap_or(0);
-        let event_envelopes: Vec<EventEnvelope<AccountEvents>> = self
-            .event_store
-            .read_from(aggregate_id, snapshot_sequence + 1)
-            .await?;
+        let (optional_snapshot_envelope, event_envelopes): (
+            Option<SnapshotEnvelope<Account>>,
+            Vec<EventEnvelope<AccountEvents>>,
+        ) = async {
+            let optional_snapshot_envelope = self.snapshot_store.read(aggregate_id).await?;
+            let snapshot_sequence: i64 = optional_snapshot_envelope
+                .clone()
+                .map(|envelope: SnapshotEnvelope<Account>| envelope.sequence)
+                .unwrap_or(0);
+            let event_envelopes = self
+                .event_store
+                .read_from(aggregate_id, snapshot_sequence + 1)
+                .await?;
+            Ok::<_, Error>((optional_snapshot_envelope, event_envelopes))
+        }
+        .instrument(tracing::info_span!("aggregate.load"))
+        .await?;
         let events: Vec<AccountEvents> = event_envelopes
             .clone()
             .into_iter()
@@ -71,6 +85,8 @@ impl<E: EventStore<AccountEvents>, S: SnapshotStore<Account>> CommandHandler<Dep
             .unwrap_or(Account::apply_all(None, events.clone()))?;
         let sequence: i64 = next_sequence(event_envelopes, optional_snapshot_envelope);
         let time: DateTime<Utc> = Utc::now();
+        let mut metadata = HashMap::from([(String::from("correlation-id"), String::from(""))]);
+        trace::inject_context(&mut metadata);
         let event_envelope = &EventEnvelope::new(
             bank_account.aggregate_id().clone(), // ID of the aggregate that the envelope belongs to.
             bank_account.aggregate_type(), // Type of the aggregate that the envelope can be applied to.
@@ -79,10 +95,7 @@ impl<E: EventStore<AccountEvents>, S: SnapshotStore<Account>> CommandHandler<Dep
             time,                          // Timestamp of when the event was created.
             sequence,                      // Location in a sequence of events.
             deposited_event.revision(),    // Revision of the event.
-            HashMap::from([
-                (String::from("trace-id"), Uuid::new_v4().to_string()),
-                (String::from("correlation-id"), String::from("")),
-            ]),
+            metadata,                      // Metadata for the event.
         );
         self.event_store.persist(event_envelope).await?;
         // Create a snapshot every three events
@@ -93,6 +106,7 @@ impl<E: EventStore<AccountEvents>, S: SnapshotStore<Account>> CommandHandler<Dep
                 bank_account.clone(),
                 time,
                 sequence,
+                Account::revision(),
             );
             self.snapshot_store.persist(snapshot_envelope).await?;
         }
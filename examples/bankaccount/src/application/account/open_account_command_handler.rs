@@ -8,6 +8,7 @@ use event_sourcing::command_handler::{CommandHandler, CommandResponse};
 use event_sourcing::event::envelope::EventEnvelope;
 use event_sourcing::event::store::EventStore;
 use event_sourcing::event::Event;
+use event_sourcing::trace;
 use event_sourcing::Error;
 use std::collections::HashMap;
 use uuid::Uuid;
@@ -35,6 +36,7 @@ impl<E: EventStore<AccountEvents>> CommandHandler<OpenAccountCommand>
 {
     type Error = Error;
 
+    #[tracing::instrument(name = "OpenAccountCommandHandler::handle", skip_all, err)]
     async fn handle(&self, command: OpenAccountCommand) -> Result<CommandResponse, Self::Error> {
         let open_account_event = Opened {
             account_id: Uuid::new_v4().to_string(),
@@ -42,6 +44,8 @@ impl<E: EventStore<AccountEvents>> CommandHandler<OpenAccountCommand>
         };
         let bank_account: Account = Account::apply(None, open_account_event.clone())?;
         let sequence = next_sequence::<AccountEvents, Account>(vec![], None);
+        let mut metadata = HashMap::from([(String::from("correlation-id"), String::from(""))]);
+        trace::inject_context(&mut metadata);
         let event_envelope: &EventEnvelope<AccountEvents> = &EventEnvelope::new(
             bank_account.aggregate_id().to_string(), // ID of the aggregate that the envelope belongs to.
             bank_account.aggregate_type(), // Type of the aggregate that the envelope can be applied to.
@@ -50,10 +54,7 @@ impl<E: EventStore<AccountEvents>> CommandHandler<OpenAccountCommand>
             Utc::now(),                    // Timestamp of when the event was created.
             sequence,                      // Location in a sequence of events.
             open_account_event.revision(), // Revision of the event.
-            HashMap::from([
-                (String::from("trace-id"), Uuid::new_v4().to_string()),
-                (String::from("correlation-id"), String::from("")),
-            ]),
+            metadata,                      // Metadata for the event.
         );
         self.event_store.persist(event_envelope).await?;
 
@@ -58,9 +58,10 @@ impl Aggregate for Account {
     }
 
     fn apply_all(state: Option<Self>, events: Vec<Self::Event>) -> Result<Self, Self::Error> {
-        match events.into_iter().fold(Ok(state), |state, event| {
-            Self::apply(state?, event).map(Some)
-        }) {
+        match events
+            .into_iter()
+            .try_fold(state, |state, event| Self::apply(state, event).map(Some))
+        {
             Ok(Some(state)) => Ok(state),
             Ok(None) => Err(Error::from("Aggregate must not be None")),
             Err(error) => Err(error),
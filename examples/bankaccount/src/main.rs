@@ -6,8 +6,13 @@ mod infrastructure;
 use event_bus_kafka::event::listener::{
     KafkaConnection, KafkaEventListener, KafkaEventListenerContainer,
 };
+use metrics_exporter_prometheus::PrometheusBuilder;
+use opentelemetry::KeyValue;
+use opentelemetry_sdk::trace::config;
+use opentelemetry_sdk::{runtime, Resource};
 use rocket::routes;
-use std::thread;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
 
 use event_sourcing::event::listener::EventListenerContainer;
 use event_sourcing::Error;
@@ -35,19 +40,38 @@ fn setup_logger() -> Result<(), fern::InitError> {
     Ok(())
 }
 
+fn setup_tracing() -> Result<(), Error> {
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(opentelemetry_otlp::new_exporter().tonic())
+        .with_trace_config(config().with_resource(Resource::new(vec![KeyValue::new(
+            "service.name",
+            "bankaccount",
+        )])))
+        .install_batch(runtime::Tokio)?;
+    tracing_subscriber::registry()
+        .with(tracing_opentelemetry::layer().with_tracer(tracer))
+        .try_init()?;
+    Ok(())
+}
+
+fn setup_metrics() -> Result<(), Error> {
+    PrometheusBuilder::new()
+        .with_http_listener(([0, 0, 0, 0], 9000))
+        .install()?;
+    Ok(())
+}
+
 #[rocket::main]
 async fn main() -> Result<(), Error> {
     setup_logger()?;
-    let scylla_db_connection = ScyllaDbConnection {
-        host: String::from("localhost:9042"),
-        replication_factor: 1,
-    };
-    let event_store = ScyllaDbEventStore {
-        connection: scylla_db_connection.clone(),
-    };
-    let snapshot_store = ScyllaDbSnapshotStore {
-        connection: scylla_db_connection.clone(),
-    };
+    setup_tracing()?;
+    setup_metrics()?;
+    let scylla_db_connection = ScyllaDbConnection::new("localhost:9042", 1);
+    let event_store =
+        ScyllaDbEventStore::new(scylla_db_connection.clone()).with_aggregate_type("BankAccount");
+    let snapshot_store =
+        ScyllaDbSnapshotStore::new(scylla_db_connection.clone()).with_aggregate_type("BankAccount");
 
     let kafka_connection = KafkaConnection {
         brokers: vec!["localhost:9092".to_owned()],
@@ -55,14 +79,16 @@ async fn main() -> Result<(), Error> {
         group: "bankaccount.event_store.events".to_string(),
     };
 
-    thread::spawn(|| {
-        let event_listener = KafkaEventListener;
-        let event_listener_container = KafkaEventListenerContainer {
-            connection: kafka_connection,
-        };
-        if let Err(e) = event_listener_container.start(event_listener) {
-            println!("Failed consuming messages: {e}");
+    let event_listener_container =
+        KafkaEventListenerContainer::new(kafka_connection).start(KafkaEventListener)?;
+    let event_listener_shutdown = event_listener_container.shutdown();
+    // Report a failing container as soon as it terminates rather than once the server stops.
+    let event_listener_terminated = rocket::tokio::spawn(async move {
+        let result = event_listener_container.terminated().await;
+        if let Err(error) = &result {
+            log::error!("Event listener container failed: {}", error);
         }
+        result
     });
 
     run_migration(&scylla_db_connection).await?;
@@ -74,5 +100,11 @@ async fn main() -> Result<(), Error> {
         .launch()
         .await?;
 
+    // Rocket returns once it has shut down on Ctrl-C or SIGTERM; let the listener commit what
+    // it has consumed before exiting.
+    event_listener_shutdown.stop();
+    event_listener_terminated.await??;
+
+    opentelemetry::global::shutdown_tracer_provider();
     Ok(())
 }